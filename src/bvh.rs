@@ -0,0 +1,368 @@
+// Bounding Volume Hierarchy
+use super::vectors::*;
+use super::rays::*;
+use super::primitives::*;
+use super::lights::*;
+use Vector3 as Point3;
+
+const SAH_BUCKETS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+
+/// Axis-aligned bounding box
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub minimum: Point3,
+    pub maximum: Point3,
+}
+
+impl Aabb {
+    pub fn new(a: Point3, b: Point3) -> Self {
+        Self {
+            minimum: a.min(b),
+            maximum: a.max(b),
+        }
+    }
+
+    /// Slab test, checking one axis at a time so we can bail out early
+    pub fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for axis in 0..3 {
+            let inv_d = 1.0 / r.direction[axis];
+            let mut t0 = (self.minimum[axis] - r.origin[axis]) * inv_d;
+            let mut t1 = (self.maximum[axis] - r.origin[axis]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = if t0 > t_min {t0} else {t_min};
+            t_max = if t1 < t_max {t1} else {t_max};
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn surrounding_box(self, other: Aabb) -> Aabb {
+        Aabb {
+            minimum: self.minimum.min(other.minimum),
+            maximum: self.maximum.max(other.maximum),
+        }
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        0.5 * (self.minimum + self.maximum)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.maximum - self.minimum;
+        2.0 * (d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+
+    pub fn longest_axis(&self) -> usize {
+        let d = self.maximum - self.minimum;
+        if d.x > d.y && d.x > d.z {
+            0
+        }
+        else if d.y > d.z {
+            1
+        }
+        else {
+            2
+        }
+    }
+}
+
+/// How a node's objects are divided between its two children
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum SplitMethod {
+    /// Split at the middle of the centroid bounds along the longest axis. Fast to build.
+    Midpoint,
+    /// Pick the split with the lowest surface area heuristic cost. Slower to build, faster to trace.
+    #[default]
+    Sah,
+}
+
+enum NodeKind {
+    Leaf { start: usize, count: usize },
+    // The first child always directly follows its parent in the node list
+    Interior { second_child: usize, axis: usize },
+}
+
+struct FlatNode {
+    bbox: Aabb,
+    kind: NodeKind,
+}
+
+/// Hierarchy over indices of bounded items, shared by `BvhNode` and triangle meshes.
+/// The tree only stores indices, so the items themselves can live anywhere.
+pub(crate) struct BvhTree {
+    nodes: Vec<FlatNode>,
+    indices: Vec<usize>,
+}
+
+impl BvhTree {
+    pub(crate) fn build(boxes: &[Aabb], split: SplitMethod) -> Self {
+        let mut tree = BvhTree {
+            nodes: Vec::with_capacity(2 * boxes.len()),
+            indices: (0..boxes.len()).collect(),
+        };
+        if !boxes.is_empty() {
+            let centroids: Vec<Point3> = boxes.iter().map(|b| b.centroid()).collect();
+            let mut indices = std::mem::take(&mut tree.indices);
+            tree.build_node(boxes, &centroids, &mut indices, 0, split);
+            tree.indices = indices;
+        }
+        tree
+    }
+
+    pub(crate) fn bounding_box(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bbox)
+    }
+
+    fn build_node(&mut self, boxes: &[Aabb], centroids: &[Point3], indices: &mut [usize], start: usize, split: SplitMethod) {
+        let bbox = indices[1..].iter().fold(boxes[indices[0]], |acc, &i| acc.surrounding_box(boxes[i]));
+        let node_index = self.nodes.len();
+        self.nodes.push(FlatNode { bbox, kind: NodeKind::Leaf { start, count: indices.len() } });
+
+        if indices.len() <= 1 {
+            return;
+        }
+
+        let centroid_bounds = indices[1..].iter().fold(Aabb::new(centroids[indices[0]], centroids[indices[0]]), |acc, &i| {
+            acc.surrounding_box(Aabb::new(centroids[i], centroids[i]))
+        });
+        let axis = centroid_bounds.longest_axis();
+        let extent = centroid_bounds.maximum[axis] - centroid_bounds.minimum[axis];
+
+        let mid = if extent <= 0.0 {
+            // Every centroid in the same spot, no axis will separate them
+            if indices.len() <= MAX_LEAF_SIZE {
+                return;
+            }
+            indices.len() / 2
+        }
+        else {
+            match split {
+                SplitMethod::Midpoint => {
+                    let pivot = centroid_bounds.centroid()[axis];
+                    partition(indices, |i| centroids[i][axis] < pivot)
+                }
+                SplitMethod::Sah => {
+                    match sah_split(boxes, centroids, indices, &bbox, &centroid_bounds, axis) {
+                        None => return,
+                        Some(bucket) => {
+                            let lo = centroid_bounds.minimum[axis];
+                            partition(indices, |i| bucket_index(centroids[i][axis], lo, extent) <= bucket)
+                        }
+                    }
+                }
+            }
+        };
+        // Fall back to an even split if the chosen plane left one side empty
+        let mid = if mid == 0 || mid == indices.len() {
+            indices.sort_by(|&a, &b| centroids[a][axis].total_cmp(&centroids[b][axis]));
+            indices.len() / 2
+        }
+        else {
+            mid
+        };
+
+        let (left, right) = indices.split_at_mut(mid);
+        self.build_node(boxes, centroids, left, start, split);
+        let second_child = self.nodes.len();
+        self.build_node(boxes, centroids, right, start + mid, split);
+        self.nodes[node_index].kind = NodeKind::Interior { second_child, axis };
+    }
+
+    /// Find the closest hit, calling `hit_item` for each item whose leaf the ray reaches
+    pub(crate) fn hit<'s, F>(&self, r: &Ray, t_min: f32, t_max: f32, mut hit_item: F) -> Option<HitRecord<'s>>
+    where F: FnMut(usize, &Ray, f32, f32) -> Option<HitRecord<'s>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut closest_so_far = t_max;
+        let mut temp_rec = None;
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.bbox.hit(r, t_min, closest_so_far) {
+                continue;
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    for &item in &self.indices[start..start + count] {
+                        if let Some(hit_record) = hit_item(item, r, t_min, closest_so_far) {
+                            closest_so_far = hit_record.t;
+                            temp_rec = Some(hit_record);
+                        }
+                    }
+                }
+                NodeKind::Interior { second_child, axis } => {
+                    // Visit the nearer child first so the farther one is more likely to be culled
+                    if r.direction[axis] < 0.0 {
+                        stack.push(node_index + 1);
+                        stack.push(second_child);
+                    }
+                    else {
+                        stack.push(second_child);
+                        stack.push(node_index + 1);
+                    }
+                }
+            }
+        }
+        temp_rec
+    }
+}
+
+fn bucket_index(value: f32, lo: f32, extent: f32) -> usize {
+    (((value - lo) / extent * SAH_BUCKETS as f32) as usize).min(SAH_BUCKETS - 1)
+}
+
+// Returns the last bucket of the cheapest left side, or None if a leaf is cheaper than any split
+fn sah_split(boxes: &[Aabb], centroids: &[Point3], indices: &[usize], bbox: &Aabb, centroid_bounds: &Aabb, axis: usize) -> Option<usize> {
+    let lo = centroid_bounds.minimum[axis];
+    let extent = centroid_bounds.maximum[axis] - lo;
+    let mut counts = [0usize; SAH_BUCKETS];
+    let mut bounds: [Option<Aabb>; SAH_BUCKETS] = [None; SAH_BUCKETS];
+    for &i in indices {
+        let b = bucket_index(centroids[i][axis], lo, extent);
+        counts[b] += 1;
+        bounds[b] = Some(match bounds[b] {
+            None => boxes[i],
+            Some(acc) => acc.surrounding_box(boxes[i]),
+        });
+    }
+
+    let area = |range: &[Option<Aabb>]| {
+        range.iter().flatten().fold(None, |acc: Option<Aabb>, b| Some(match acc {
+            None => *b,
+            Some(acc) => acc.surrounding_box(*b),
+        })).map_or(0.0, |b| b.surface_area())
+    };
+
+    let mut best = None;
+    let mut best_cost = f32::INFINITY;
+    for split in 0..SAH_BUCKETS - 1 {
+        let left_count: usize = counts[..=split].iter().sum();
+        let right_count = indices.len() - left_count;
+        if left_count == 0 || right_count == 0 {
+            continue;
+        }
+        // Traversal cost of 1/8 relative to an intersection test
+        let cost = 0.125 + (left_count as f32 * area(&bounds[..=split]) + right_count as f32 * area(&bounds[split + 1..])) / bbox.surface_area().max(f32::EPSILON);
+        if cost < best_cost {
+            best_cost = cost;
+            best = Some(split);
+        }
+    }
+
+    if indices.len() <= MAX_LEAF_SIZE && best_cost >= indices.len() as f32 {
+        None
+    }
+    else {
+        best
+    }
+}
+
+// Move the items matching `pred` to the front, returning how many there are
+fn partition<P: Fn(usize) -> bool>(indices: &mut [usize], pred: P) -> usize {
+    let mut mid = 0;
+    for k in 0..indices.len() {
+        if pred(indices[k]) {
+            indices.swap(mid, k);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Hierarchy built from a `HittableList`, usable anywhere the list is
+pub struct BvhNode<'a> {
+    objects: Vec<Box<dyn Hittable + 'a>>,
+    unbounded: Vec<Box<dyn Hittable + 'a>>, // Objects without a box are tested on every ray
+    tree: BvhTree,
+}
+
+impl <'a> BvhNode<'a> {
+    pub fn new(list: HittableList<'a>, split: SplitMethod) -> Self {
+        let (objects, unbounded): (Vec<_>, Vec<_>) = list.hittables.into_iter().partition(|h| h.bounding_box().is_some());
+        let boxes: Vec<Aabb> = objects.iter().map(|h| h.bounding_box().unwrap()).collect();
+        Self {
+            tree: BvhTree::build(&boxes, split),
+            objects,
+            unbounded,
+        }
+    }
+}
+
+impl Hittable for BvhNode<'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let mut temp_rec = None;
+        let mut closest_so_far = t_max;
+        for hittable in &self.unbounded {
+            if let Some(hit_record) = hittable.hit(r, t_min, closest_so_far) {
+                closest_so_far = hit_record.t;
+                temp_rec = Some(hit_record);
+            }
+        }
+        self.tree.hit(r, t_min, closest_so_far, |i, r, t_min, t_max| self.objects[i].hit(r, t_min, t_max)).or(temp_rec)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.tree.bounding_box()
+        }
+        else {
+            None
+        }
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        self.objects.iter().chain(&self.unbounded).flat_map(|h| h.lights()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials;
+
+    #[test]
+    fn bvh_hits_match_hittable_list() {
+        let material = materials::Material::default();
+        let build = || {
+            let mut world = HittableList::default();
+            for a in -6..6 {
+                for b in -6..6 {
+                    let center = Vector3::new(a as f32, 0.3 * (b as f32).sin(), b as f32);
+                    world.add(Box::new(Sphere{center, material: &material, radius: 0.2 + 0.05 * ((a + b) as f32).cos()}));
+                }
+            }
+            world
+        };
+        let list = build();
+        let bvhs = [BvhNode::new(build(), SplitMethod::Midpoint), BvhNode::new(build(), SplitMethod::Sah)];
+
+        for k in 0..500 {
+            let f = k as f32;
+            let r = Ray {
+                origin: Vector3::new(8.0 * (f * 0.37).sin(), 3.0, 8.0 * (f * 0.53).cos()),
+                direction: Vector3::new((f * 0.71).sin(), -1.0, (f * 0.13).cos()),
+            };
+            let expected = list.hit(&r, 0.001, f32::INFINITY).map(|h| h.t);
+            for bvh in &bvhs {
+                assert_eq!(bvh.hit(&r, 0.001, f32::INFINITY).map(|h| h.t), expected);
+            }
+        }
+
+        // A NaN centroid sorts somewhere instead of stopping the build
+        for split in [SplitMethod::Midpoint, SplitMethod::Sah] {
+            let mut world = HittableList::default();
+            for _ in 0..3 {
+                world.add(Box::new(Sphere{center: Vector3::new(f32::NAN, f32::NAN, f32::NAN), material: &material, radius: 0.5}));
+            }
+            BvhNode::new(world, split);
+        }
+    }
+}
@@ -2,6 +2,7 @@
 use super::vectors::*;
 use super::rays::*;
 use super::util::*;
+use super::samplers::*;
 use Vector3 as Point3;
 
 pub struct Camera {
@@ -11,7 +12,6 @@ pub struct Camera {
     vertical: Vector3,
     u: Vector3,
     v: Vector3,
-    w: Vector3,
     lens_radius: f32,
 }
 
@@ -40,13 +40,20 @@ impl Camera {
             lower_left_corner,
             u,
             v,
-            w,
             lens_radius,
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
+    /// Stretch the viewport horizontally to match an image, keeping the vertical field of view
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        let center = self.lower_left_corner + self.horizontal/2.0 + self.vertical/2.0;
+        self.horizontal = aspect_ratio * self.vertical.length() * self.u;
+        self.lower_left_corner = center - self.horizontal/2.0 - self.vertical/2.0;
+    }
+
+    /// The ray through viewport point (s, t), from a point on the lens drawn from `sampler`
+    pub fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens_radius * random_in_unit_disk(sampler);
         let offset = (self.u * rd.x) + (self.v * rd.y);
 
         Ray{
@@ -62,10 +69,14 @@ impl Default for Camera {
     }
 }
 
-fn random_in_unit_disk() -> Point3 {
-    let mut p = Vector3::new(rand_range(-1.0, 1.0), rand_range(-1.0, 1.0), 0.0);
-    while p.length_squared() >= 1.0 {
-        p = Vector3::new(rand_range(-1.0, 1.0), rand_range(-1.0, 1.0), 0.0);
-    };
-    p
-}
\ No newline at end of file
+// Shirley and Chiu's concentric mapping, which keeps neighbouring samples neighbours on the disk
+fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Point3 {
+    let (u1, u2) = sampler.get_2d();
+    let (a, b) = (2.0*u1 - 1.0, 2.0*u2 - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    let quarter_pi = std::f32::consts::FRAC_PI_4;
+    let (r, theta) = if a.abs() > b.abs() { (a, quarter_pi * (b / a)) } else { (b, 2.0*quarter_pi - quarter_pi * (a / b)) };
+    Vector3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
@@ -0,0 +1,202 @@
+// Saving and resuming progressive renders
+use super::colors::*;
+use super::render::*;
+use super::environment::*;
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"RRCHECK1";
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Why a checkpoint could not be resumed
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io { path: PathBuf, error: std::io::Error },
+    /// Not a checkpoint file, or cut short
+    Format { path: PathBuf, message: String },
+    /// The checkpoint was saved from another scene or with other render settings
+    SceneMismatch { path: PathBuf },
+}
+
+impl std::fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CheckpointError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            CheckpointError::Format { path, message } => write!(f, "{}: {}", path.display(), message),
+            CheckpointError::SceneMismatch { path } =>
+                write!(f, "{}: checkpoint was saved from a different scene or image settings, refusing to resume", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+/// FNV-1a hash of a scene's source (the scene file, or a built-in scene's name) and the
+/// settings that change what each sample sees: image size, depth, integrator, sampler,
+/// environment and alpha. Files the scene refers to, like textures, OBJs and
+/// environment maps, aren't included.
+pub fn scene_hash(source: &[u8], config: &SceneConfig) -> u64 {
+    let mut hash = Fnv1a::default();
+    hash.write(source);
+    hash.write(&config.image_width().to_le_bytes());
+    hash.write(&config.image_height().to_le_bytes());
+    hash.write(&config.max_depth().to_le_bytes());
+    hash.write(format!("{:?} {:?}", config.integrator(), config.sampler()).as_bytes());
+    match config.environment() {
+        Environment::Gradient => hash.write(b"sky"),
+        Environment::Constant(color) => {
+            for channel in [color.r, color.g, color.b] {
+                hash.write(&channel.to_le_bytes());
+            }
+        }
+        Environment::Map(map) => {
+            hash.write(b"map");
+            for value in [map.width() as f32, map.height() as f32, map.rotation(), map.intensity()] {
+                hash.write(&value.to_le_bytes());
+            }
+        }
+        Environment::Sky(sky) => {
+            hash.write(b"daylight");
+            for value in [sky.sun_elevation(), sky.sun_azimuth(), sky.turbidity(), sky.intensity()] {
+                hash.write(&value.to_le_bytes());
+            }
+        }
+    }
+    hash.write(&[config.alpha() as u8]);
+    hash.0
+}
+
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+/// Save the accumulated sums, coverage and counts with the scene hash and seed. The file is written next
+/// to `path` and renamed over it, so an interrupted save keeps the previous checkpoint.
+pub fn save<P: AsRef<Path>>(path: P, accumulator: &Accumulator, scene_hash: u64) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut w = BufWriter::new(File::create(&partial)?);
+    w.write_all(MAGIC)?;
+    w.write_all(&scene_hash.to_le_bytes())?;
+    w.write_all(&accumulator.seed().to_le_bytes())?;
+    w.write_all(&(accumulator.sums().len() as u64).to_le_bytes())?;
+    for (k, (sum, count)) in accumulator.sums().iter().zip(accumulator.counts()).enumerate() {
+        for channel in [sum.r, sum.g, sum.b] {
+            w.write_all(&channel.to_le_bytes())?;
+        }
+        if let Some(coverage) = accumulator.coverage() {
+            w.write_all(&coverage[k].to_le_bytes())?;
+        }
+        w.write_all(&count.to_le_bytes())?;
+    }
+    w.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(&partial, path)
+}
+
+/// Load a checkpoint to add more samples to, refusing one saved with a different scene hash
+pub fn load<P: AsRef<Path>>(path: P, scene: &SceneConfig, scene_hash: u64) -> Result<Accumulator, CheckpointError> {
+    let path = path.as_ref();
+    let format_error = |message: &str| CheckpointError::Format { path: path.to_path_buf(), message: message.to_string() };
+    let file = File::open(path).map_err(|error| CheckpointError::Io { path: path.to_path_buf(), error })?;
+    let mut r = BufReader::new(file);
+
+    if &read_bytes::<8>(&mut r, path)? != MAGIC {
+        return Err(format_error("not a rustrays checkpoint"));
+    }
+    if u64::from_le_bytes(read_bytes(&mut r, path)?) != scene_hash {
+        return Err(CheckpointError::SceneMismatch { path: path.to_path_buf() });
+    }
+    let seed = u64::from_le_bytes(read_bytes(&mut r, path)?);
+    let pixels = u64::from_le_bytes(read_bytes(&mut r, path)?);
+    if pixels != (scene.image_width() * scene.image_height()) as u64 {
+        return Err(format_error("checkpoint pixel count doesn't match the image size"));
+    }
+
+    let mut sums = Vec::with_capacity(pixels as usize);
+    let mut coverage = scene.alpha().then(|| Vec::with_capacity(pixels as usize));
+    let mut counts = Vec::with_capacity(pixels as usize);
+    for _ in 0..pixels {
+        let buf = read_bytes::<12>(&mut r, path)?;
+        let float = |k: usize| f32::from_le_bytes(buf[k..k + 4].try_into().unwrap());
+        sums.push(Color::new(float(0), float(4), float(8)));
+        if let Some(coverage) = &mut coverage {
+            coverage.push(f32::from_le_bytes(read_bytes(&mut r, path)?));
+        }
+        counts.push(u32::from_le_bytes(read_bytes(&mut r, path)?));
+    }
+    Ok(Accumulator::from_parts(scene.image_width(), scene.image_height(), seed, sums, coverage, counts).unwrap())
+}
+
+// The next N bytes, where running out means the file was cut short
+fn read_bytes<const N: usize>(r: &mut impl Read, path: &Path) -> Result<[u8; N], CheckpointError> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf).map_err(|error| match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => CheckpointError::Format { path: path.to_path_buf(), message: "checkpoint file is truncated".to_string() },
+        _ => CheckpointError::Io { path: path.to_path_buf(), error },
+    })?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vectors, primitives, cameras, materials, samplers, test_util};
+
+    #[test]
+    fn checkpoints_resume_the_same_render() {
+        use std::sync::atomic::AtomicBool;
+        let material = materials::Material::default();
+        let mut world = primitives::HittableList::default();
+        world.add(Box::new(primitives::Sphere{center: vectors::Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.5}));
+        let cam = cameras::Camera::default();
+        let render = |config: &SceneConfig, samples, accumulator| {
+            let progressive = Progressive { max_samples: Some(samples), ..Progressive::default() };
+            render_progressive(config, &world, &cam, &progressive, accumulator, &AtomicBool::new(false), |_| Ok(())).unwrap()
+        };
+
+        // Raising the sample count on resume gives what rendering straight to it would, even
+        // for samplers that stratify
+        let file = test_util::TempFile::new("bin");
+        let path = file.path();
+        for sampler in [samplers::SamplerKind::Independent, samplers::SamplerKind::Stratified].iter() {
+            let config = SceneConfig::builder().width(16).height(9).seed(5).sampler(*sampler).build().unwrap();
+            let hash = scene_hash(b"sphere", &config);
+            save(path, &render(&config, 2, Accumulator::new(&config)), hash).unwrap();
+            let resumed = render(&config, 4, load(path, &config, hash).unwrap());
+            let straight = render(&config, 4, Accumulator::new(&config));
+            assert_eq!(resumed.counts(), straight.counts());
+            for (a, b) in resumed.sums().iter().zip(straight.sums()) {
+                assert_eq!((a.r.to_bits(), a.g.to_bits(), a.b.to_bits()), (b.r.to_bits(), b.g.to_bits(), b.b.to_bits()), "{:?}", sampler);
+            }
+        }
+        let config = SceneConfig::builder().width(16).height(9).seed(5).build().unwrap();
+        let hash = scene_hash(b"sphere", &config);
+        save(path, &render(&config, 2, Accumulator::new(&config)), hash).unwrap();
+
+        // Another scene, or the same one at another size, hashes differently
+        assert_ne!(scene_hash(b"spheres", &config), hash);
+        let wider = config.clone().into_builder().width(32).build().unwrap();
+        assert!(matches!(load(path, &wider, scene_hash(b"sphere", &wider)), Err(CheckpointError::SceneMismatch { .. })));
+
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::write(path, &bytes[..bytes.len() - 3]).unwrap();
+        assert!(matches!(load(path, &config, hash), Err(CheckpointError::Format { .. })));
+    }
+}
@@ -13,6 +13,99 @@ pub struct Color {
     pub b: f32
 }
 
+/// Convert an sRGB encoded component, 0 to 1, to linear light
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    }
+    else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encode a linear component, 0 to 1, with the sRGB curve
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    }
+    else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// A transfer function, between linear light and the values stored in a file
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Transfer {
+    /// The piecewise sRGB curve, what PNGs and color pickers assume
+    #[default]
+    Srgb,
+    /// No curve at all
+    Linear,
+    /// A pure 2.2 power
+    Gamma22,
+    /// The Rec. 709 (and Rec. 2020) camera curve, for video
+    Rec709,
+}
+
+impl Transfer {
+    pub const NAMES: [&'static str; 4] = ["srgb", "linear", "gamma2.2", "rec709"];
+
+    /// Linear light to an encoded value. Negative light encodes as 0.
+    pub fn encode(&self, value: f32) -> f32 {
+        let value = value.max(0.0);
+        match self {
+            Transfer::Srgb => linear_to_srgb(value),
+            Transfer::Linear => value,
+            Transfer::Gamma22 => value.powf(1.0 / 2.2),
+            Transfer::Rec709 if value < 0.018 => 4.5 * value,
+            Transfer::Rec709 => 1.099 * value.powf(0.45) - 0.099,
+        }
+    }
+
+    /// An encoded value back to linear light
+    pub fn decode(&self, value: f32) -> f32 {
+        let value = value.max(0.0);
+        match self {
+            Transfer::Srgb => srgb_to_linear(value),
+            Transfer::Linear => value,
+            Transfer::Gamma22 => value.powf(2.2),
+            Transfer::Rec709 if value < 0.081 => value / 4.5,
+            Transfer::Rec709 => ((value + 0.099) / 1.099).powf(1.0 / 0.45),
+        }
+    }
+
+    pub fn encode_color(&self, c: Color) -> Color {
+        Color::new(self.encode(c.r), self.encode(c.g), self.encode(c.b))
+    }
+
+    pub fn decode_color(&self, c: Color) -> Color {
+        Color::new(self.decode(c.r), self.decode(c.g), self.decode(c.b))
+    }
+
+    /// The exponent for a PNG gAMA chunk, times 100000, that best describes the curve
+    pub fn png_gamma(&self) -> u32 {
+        match self {
+            Transfer::Srgb | Transfer::Gamma22 => 45455,
+            Transfer::Linear => 100000,
+            Transfer::Rec709 => 45000,
+        }
+    }
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "srgb" => Ok(Transfer::Srgb),
+            "linear" => Ok(Transfer::Linear),
+            "gamma2.2" => Ok(Transfer::Gamma22),
+            "rec709" => Ok(Transfer::Rec709),
+            _ => Err(format!("unknown transfer function `{}`, expected one of: {}", s, Transfer::NAMES.join(", "))),
+        }
+    }
+}
+
 impl std::fmt::Display for Color {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}, {}, {}", (self.r.clamp(0.0, 0.999)*MAX_VAL) as u32, 
@@ -38,10 +131,9 @@ impl Color {
         }
     }
 
+    /// Encode with the sRGB curve, for display
     pub fn gamma_correct(&mut self) {
-        self.r = self.r.sqrt();
-        self.g = self.g.sqrt();
-        self.b = self.b.sqrt();
+        *self = Transfer::Srgb.encode_color(*self);
     }
 
     pub fn clamp(self, min:f32, max:f32) -> Self {
@@ -52,6 +144,11 @@ impl Color {
         }
     }
 
+    /// Perceived brightness, with Rec. 709 weights
+    pub fn luminance(&self) -> f32 {
+        0.2126*self.r + 0.7152*self.g + 0.0722*self.b
+    }
+
     pub fn get_png_color(&self) -> [u8; 3] {
         let png_color = self.clamp(0.0, 0.999)*255.0;
         [png_color.r as u8, png_color.g as u8, png_color.b as u8]
@@ -188,4 +285,43 @@ impl MulAssign for Color {
             b: self.b * other.b,
         };
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{materials, scene, framebuffer};
+
+    #[test]
+    fn transfers_encode_and_scene_colors_decode() {
+        for transfer in [Transfer::Srgb, Transfer::Linear, Transfer::Gamma22, Transfer::Rec709] {
+            for k in 0..=20 {
+                let x = k as f32 / 20.0;
+                assert!((transfer.decode(transfer.encode(x)) - x).abs() < 1e-5, "{:?} should round trip {}", transfer, x);
+            }
+        }
+        assert!((Transfer::Srgb.encode(0.5) - 0.735357).abs() < 1e-5);
+        assert!((Transfer::Srgb.encode(0.002) - 0.02584).abs() < 1e-5);
+        assert_eq!("gamma2.2".parse(), Ok(Transfer::Gamma22));
+
+        let image = framebuffer::Framebuffer::new(2, 2);
+        let chunks = |transfer| {
+            let mut bytes = Vec::new();
+            let settings = framebuffer::OutputSettings { transfer, ..Default::default() };
+            framebuffer::ImageFormat::Png.write(&image, &settings, &mut bytes).unwrap();
+            let has = |name: &[u8]| bytes.windows(4).any(|w| w == name);
+            (has(b"sRGB"), has(b"gAMA"))
+        };
+        assert_eq!(chunks(Transfer::Srgb), (true, true));
+        assert_eq!(chunks(Transfer::Linear), (false, true));
+
+        let camera = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 90.0\n";
+        let grey = "[materials.grey]\ntype = \"diffuse\"\nalbedo = [0.5, 0.5, 0.5]\n";
+        let albedo = |text: &str| match scene::Scene::parse(text).unwrap().material("grey") {
+            Some(materials::Material::Diffuse { albedo }) => albedo.value(0.0, 0.0, Vector3::new(0.0, 0.0, 0.0)).g,
+            _ => panic!("expected a diffuse material"),
+        };
+        assert!((albedo(&format!("{}{}", camera, grey)) - 0.214041).abs() < 1e-5);
+        assert_eq!(albedo(&format!("color_space = \"linear\"\n{}{}", camera, grey)), 0.5);
+    }
+}
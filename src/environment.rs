@@ -0,0 +1,341 @@
+// Environments, the light arriving from infinitely far away in every direction
+use super::colors::*;
+use super::vectors::*;
+use super::rays::*;
+use super::framebuffer::*;
+use super::lights::*;
+use super::util::*;
+use super::sky::*;
+use Vector3 as Point3;
+
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What rays that miss every object see, and the light it sheds on the scene
+#[derive(Clone, Default)]
+pub enum Environment {
+    /// The white to blue gradient from `ray_color_bg`
+    #[default]
+    Gradient,
+    /// A single color, black for scenes lit only by their own lights
+    Constant(Color),
+    /// An equirectangular image, sampled directly by the nee integrator
+    Map(Arc<EnvironmentMap>),
+    /// A daylight sky and sun, sampled directly by the nee integrator
+    Sky(Arc<Sky>),
+}
+
+impl Environment {
+    /// Radiance arriving along the reverse of `r`
+    pub fn color(&self, r: &Ray) -> Color {
+        match self {
+            Environment::Gradient => ray_color_bg(r),
+            Environment::Constant(color) => *color,
+            Environment::Map(map) => map.radiance(r.direction),
+            Environment::Sky(sky) => sky.radiance(r.direction),
+        }
+    }
+
+    /// The environment as a light to sample directly, for those worth sampling
+    pub fn light(&self) -> Option<&dyn Light> {
+        match self {
+            Environment::Map(map) => Some(map.as_ref()),
+            Environment::Sky(sky) => Some(sky.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A latitude-longitude image wrapped around the scene: the top row straight up, the bottom
+/// straight down, and the middle column toward -z, with +x a quarter turn to its right.
+/// Directions are picked in proportion to the brightness they see, so small bright features
+/// like the sun are found by light sampling instead of by chance.
+pub struct EnvironmentMap {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+    rotation: f32, // Radians about the y axis
+    intensity: f32,
+    distribution: Distribution2D, // Over u and v, top row first
+}
+
+impl EnvironmentMap {
+    /// A map from linear radiance, turned `rotation` degrees anticlockwise about the y axis
+    /// seen from above, so 90 brings what was toward -z round to -x, and scaled by `intensity`
+    pub fn new(image: &Framebuffer, rotation: f32, intensity: f32) -> Self {
+        assert!(image.width() > 0 && image.height() > 0, "an environment map needs pixels");
+        let mut map = Self {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().to_vec(),
+            rotation: rotation.to_radians(),
+            intensity,
+            distribution: Distribution2D::new(&[1.0], 1, 1),
+        };
+        // Each texel is picked by the average brightness the bilinear lookup gives over it,
+        // which blends in an eighth of each neighbour, so light blurred into a texel is still
+        // found. Rows near the poles cover less of the sphere, so they're picked less often.
+        const BLUR: [(i64, f32); 3] = [(-1, 0.125), (0, 0.75), (1, 0.125)];
+        let (width, height) = (map.width as usize, map.height as usize);
+        let weights: Vec<f32> = (0..width * height).map(|k| {
+            let (i, j) = ((k % width) as i64, (k / width) as i64);
+            let mut average = 0.0;
+            for &(di, wi) in BLUR.iter() {
+                for &(dj, wj) in BLUR.iter() {
+                    average += wi * wj * map.texel(i + di, j + dj).luminance().max(0.0);
+                }
+            }
+            average * (PI * (j as f32 + 0.5) / height as f32).sin()
+        }).collect();
+        map.distribution = Distribution2D::new(&weights, width, height);
+        map
+    }
+
+    /// Load an HDR, PFM or EXR image as a map
+    pub fn load<P: AsRef<Path>>(path: P, rotation: f32, intensity: f32) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let format = ImageFormat::from_path(path).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "environment maps must be .hdr, .pfm or .exr images",
+        ))?;
+        Ok(Self::new(&format.load(path)?, rotation, intensity))
+    }
+
+    /// A `width` by `height` map of the radiance `f` gives toward each texel's center, for
+    /// baking analytic skies into something that can be sampled
+    pub fn from_fn<F: Fn(Vector3) -> Color>(width: u32, height: u32, f: F) -> Self {
+        let pixels = (0..width * height)
+            .map(|k| f(direction((((k % width) as f32 + 0.5) / width as f32, ((k / width) as f32 + 0.5) / height as f32), 0.0)))
+            .collect();
+        Self::new(&Framebuffer::from_pixels(width, height, pixels), 0.0, 1.0)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// In degrees
+    pub fn rotation(&self) -> f32 {
+        self.rotation.to_degrees()
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// Radiance arriving from `direction`
+    pub fn radiance(&self, direction: Vector3) -> Color {
+        let (u, v) = self.uv(direction.unit_vector());
+        // Bilinear between texel centers, wrapping around the sides
+        let (x, y) = (u * self.width as f32 - 0.5, v * self.height as f32 - 0.5);
+        let (i, j) = (x.floor() as i64, y.floor() as i64);
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+        let top = (1.0 - fx) * self.texel(i, j) + fx * self.texel(i + 1, j);
+        let bottom = (1.0 - fx) * self.texel(i, j + 1) + fx * self.texel(i + 1, j + 1);
+        self.intensity * ((1.0 - fy) * top + fy * bottom)
+    }
+
+    // Columns wrap around, rows stop at the poles
+    fn texel(&self, i: i64, j: i64) -> Color {
+        let i = i.rem_euclid(self.width as i64);
+        let j = j.clamp(0, self.height as i64 - 1);
+        self.pixels[(j * self.width as i64 + i) as usize]
+    }
+
+    // Image coordinates of a unit direction, with v from the top
+    fn uv(&self, direction: Vector3) -> (f32, f32) {
+        let phi = direction.x.atan2(-direction.z) + self.rotation;
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+}
+
+// The unit direction toward image coordinates (u, v) of a map turned `rotation` radians,
+// the reverse of `EnvironmentMap::uv`
+fn direction((u, v): (f32, f32), rotation: f32) -> Vector3 {
+    let (sin_theta, cos_theta) = (PI * v).sin_cos();
+    let (sin_phi, cos_phi) = ((u - 0.5) * 2.0 * PI - rotation).sin_cos();
+    Vector3::new(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi)
+}
+
+// An image's density over u and v becomes one over solid angle by dividing out how much of
+// the sphere each bit of it covers, 2 pi^2 sin(theta)
+impl Light for EnvironmentMap {
+    fn sample(&self, _p: Point3, u: (f32, f32)) -> Option<LightSample> {
+        let (uv, uv_pdf) = self.distribution.sample(u);
+        let sin_theta = (PI * uv.1).sin();
+        if uv_pdf <= 0.0 || sin_theta <= 0.0 {
+            return None;
+        }
+        let direction = direction(uv, self.rotation);
+        Some(LightSample {
+            direction,
+            distance: f32::INFINITY,
+            radiance: self.radiance(direction),
+            pdf: uv_pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf(&self, _p: Point3, direction: Vector3) -> f32 {
+        let (u, v) = self.uv(direction.unit_vector());
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf((u, v)) / (2.0 * PI * PI * sin_theta)
+    }
+
+    fn power(&self, world_radius: f32) -> f32 {
+        // The distribution's integral is the luminance over the sphere in uv terms
+        let radiant_intensity = 2.0 * PI * PI * self.intensity * self.distribution.integral();
+        PI * world_radius * world_radius * radiant_intensity
+    }
+}
+
+/// A piecewise constant density over 0 to 1, proportional to some non-negative weights
+pub struct Distribution1D {
+    weights: Vec<f32>,
+    cdf: Vec<f32>, // One longer than the weights, from 0 to 1
+    integral: f32, // The weights' average, or 0 if they're all 0
+}
+
+impl Distribution1D {
+    pub fn new(weights: &[f32]) -> Self {
+        let n = weights.len().max(1) as f32;
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        cdf.push(0.0);
+        let mut running = 0.0;
+        for &w in weights {
+            running += w / n;
+            cdf.push(running);
+        }
+        let integral = running;
+        // Nothing to go by, so pick evenly
+        for (k, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 { *c / integral } else { k as f32 / n };
+        }
+        Self { weights: weights.to_vec(), cdf, integral }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// A point from 0 to 1 with a uniform number, its density, and which piece it's in
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let n = self.weights.len();
+        let index = self.cdf.partition_point(|&c| c <= u).clamp(1, n.max(1)) - 1;
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = if width > 0.0 { (u - self.cdf[index]) / width } else { 0.0 };
+        let x = ((index as f32 + offset.clamp(0.0, 1.0)) / n as f32).min(1.0 - f32::EPSILON);
+        (x, self.density(index), index)
+    }
+
+    /// The density `sample` picks a point in piece `index` with
+    pub fn density(&self, index: usize) -> f32 {
+        if self.integral > 0.0 { self.weights[index] / self.integral } else { 1.0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+}
+
+/// A piecewise constant density over the unit square, from a grid of weights in rows. A row
+/// is picked first, then a column within it.
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(weights: &[f32], width: usize, height: usize) -> Self {
+        assert_eq!(weights.len(), width * height, "a {}x{} distribution needs {} weights", width, height, width * height);
+        let rows: Vec<Distribution1D> = weights.chunks(width.max(1)).map(Distribution1D::new).collect();
+        let marginal = Distribution1D::new(&rows.iter().map(|row| row.integral()).collect::<Vec<_>>());
+        Self { rows, marginal }
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.marginal.integral()
+    }
+
+    /// A point in the unit square, x along the rows and y down them, and its density
+    pub fn sample(&self, (u1, u2): (f32, f32)) -> ((f32, f32), f32) {
+        let (y, y_pdf, row) = self.marginal.sample(u2);
+        let (x, x_pdf, _) = self.rows[row].sample(u1);
+        ((x, y), x_pdf * y_pdf)
+    }
+
+    /// The density `sample` picks a point with
+    pub fn pdf(&self, (x, y): (f32, f32)) -> f32 {
+        let row = ((y * self.marginal.len() as f32) as usize).min(self.marginal.len() - 1);
+        let columns = &self.rows[row];
+        let column = ((x * columns.len() as f32) as usize).min(columns.len() - 1);
+        self.marginal.density(row) * columns.density(column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Integrator;
+    use crate::scene::Scene;
+    use crate::test_util::*;
+
+    #[test]
+    fn environment_maps_light_the_scene_and_show_through() {
+        // A dim sky with a small, very bright sun part way up it
+        let mut image = Framebuffer::from_pixels(32, 16, vec![Color::new(0.2, 0.2, 0.2); 32 * 16]);
+        image.set(20, 3, Color::new(5000.0, 5000.0, 5000.0));
+        let map = EnvironmentMap::new(&image, 0.0, 1.0);
+        let rotated = EnvironmentMap::new(&image, 90.0, 1.0);
+        // The middle of the sun's texel, then turned a quarter right-handed turn about y
+        let (theta, phi) = (3.5 / 16.0 * PI, (20.5 / 32.0 - 0.5) * 2.0 * PI);
+        let sun = Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos());
+        assert!((map.radiance(sun).g - 5000.0).abs() < 1.0);
+        assert!((rotated.radiance(Vector3::new(sun.z, sun.y, -sun.x)).g - 5000.0).abs() < 1.0);
+        let environment = Environment::Map(Arc::new(map));
+
+        let floor_material = floor_material();
+        let world = floor_world(&floor_material, Vec::new());
+        let lights = LightList::new(&world, &environment, LightSelection::Power);
+        assert_eq!(lights.len(), 1);
+
+        // Rays that miss see the map as it is
+        let up = Ray { origin: Vector3::new(0.0, 1.0, 0.0), direction: Vector3::new(0.3, 1.0, 0.2) };
+        let mut sampler = crate::samplers::SamplerKind::Independent.sampler(3, 1);
+        let (seen, coverage) = Integrator::Nee.ray_color(&up, &world, &lights, &environment, 4, sampler.as_mut());
+        assert_eq!((seen.r, seen.g, seen.b, coverage), (environment.color(&up).r, environment.color(&up).g, environment.color(&up).b, 0.0));
+
+        // The floor shows albedo / pi times the irradiance, summed here over a fine grid of the sky
+        let irradiance = hemisphere_irradiance(512, |direction| environment.color(&Ray { origin: Vector3::new(0.0, 0.0, 0.0), direction }).g);
+        let expected = FLOOR_ALBEDO * irradiance / PI;
+        let estimate = |integrator| estimates(integrator, &down_from(1.0), &world, &lights, &environment, 2, 4000);
+        let (nee, path) = (estimate(Integrator::Nee), estimate(Integrator::Path));
+        assert_near("light sampling", mean(&nee), expected, 0.05);
+        assert!(spread(&nee) * 5.0 < spread(&path));
+
+        // Scene files load maps by file, and won't take a background as well
+        let file = TempFile::new("pfm");
+        ImageFormat::Pfm.save(&image, &OutputSettings::default(), file.path()).unwrap();
+        let base = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 40.0\n";
+        let map_table = format!("[environment]\ntype = \"map\"\nfile = {:?}\nrotation = 45.0\n", file.path().to_str().unwrap());
+        match Scene::parse(&format!("{}{}", base, map_table)).unwrap().config().environment() {
+            Environment::Map(map) => assert_eq!((map.width(), map.rotation()), (32, 45.0)),
+            _ => panic!("expected an environment map"),
+        }
+        assert_eq!(invalid_field(&format!("{}[render]\nbackground = [0.0, 0.0, 0.0]\n{}", base, map_table)), "type");
+        assert_eq!(invalid_field(&format!("{}{}intensity = nan\n", base, map_table)), "intensity");
+    }
+}
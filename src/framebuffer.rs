@@ -0,0 +1,330 @@
+// Rendered images and the writers that save them
+use super::colors::*;
+use super::hdr::*;
+use super::tonemap::*;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// A rendered image in linear RGB, top row first, not yet gamma corrected or clamped.
+/// It may carry an alpha channel of coverage, in which case the colors are premultiplied by it.
+#[derive(Clone)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>,
+    alpha: Option<Vec<f32>>,
+}
+
+impl Framebuffer {
+    /// A black image
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, pixels: vec![Color::new(0.0,0.0,0.0); (width * height) as usize], alpha: None }
+    }
+
+    /// An image from its pixels, top row first. Panics unless there are width * height of them.
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize, "a {}x{} image needs {} pixels", width, height, width * height);
+        Self { width, height, pixels, alpha: None }
+    }
+
+    /// The same image with an alpha channel, top row first. Panics unless there is one value a pixel.
+    pub fn with_alpha(self, alpha: Vec<f32>) -> Self {
+        assert_eq!(alpha.len(), self.pixels.len(), "a {}x{} image needs {} alpha values", self.width, self.height, self.pixels.len());
+        Self { alpha: Some(alpha), ..self }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        &mut self.pixels
+    }
+
+    /// Coverage of each pixel from 0 to 1, if the image has an alpha channel
+    pub fn alpha(&self) -> Option<&[f32]> {
+        self.alpha.as_deref()
+    }
+
+    /// The pixel in column x of row y, counting rows from the top
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Color) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    /// Root mean square difference from another image the same size, over all channels
+    pub fn rms_difference(&self, other: &Framebuffer) -> f32 {
+        assert_eq!((self.width, self.height), (other.width, other.height), "images differ in size");
+        let squared: f32 = self.pixels.iter().zip(&other.pixels)
+            .map(|(&a, &b)| { let d = a - b; d.r*d.r + d.g*d.g + d.b*d.b })
+            .sum();
+        (squared / (3 * self.pixels.len()).max(1) as f32).sqrt()
+    }
+
+    // Display encoded colors, not premultiplied, and alpha if there is any, all clamped to 0 to 1
+    fn display_pixels<'a>(&'a self, settings: &'a OutputSettings) -> impl Iterator<Item = (Color, Option<f32>)> + 'a {
+        self.pixels.iter().enumerate().map(move |(k, &pixel)| match &self.alpha {
+            None => (settings.display_color(pixel), None),
+            Some(alpha) => {
+                let a = alpha[k].clamp(0.0, 1.0);
+                let straight = if a > 0.0 { pixel / a } else { Color::new(0.0,0.0,0.0) };
+                (settings.display_color(straight), Some(a))
+            }
+        })
+    }
+}
+
+/// How linear radiance is turned into the display values stored in PNG and PPM files.
+/// HDR formats store the radiance as it is.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct OutputSettings {
+    /// Brightness adjustment in stops, each one doubling the light
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+    /// The curve display values are encoded with, declared in PNG files
+    pub transfer: Transfer,
+    /// Bits per channel in a PNG
+    pub bit_depth: BitDepth,
+    /// Levels per channel in a PPM
+    pub dyn_range: u32,
+}
+
+impl OutputSettings {
+    /// Exposed, tone mapped and encoded with the transfer function, but not yet clamped
+    pub fn display_color(&self, color: Color) -> Color {
+        self.transfer.encode_color(self.tone_map.apply(color * 2f32.powf(self.exposure)))
+    }
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self { exposure: 0.0, tone_map: ToneMap::default(), transfer: Transfer::default(), bit_depth: BitDepth::default(), dyn_range: DYN_RANGE as u32 }
+    }
+}
+
+/// Bits per channel in PNG files
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    /// No banding in smooth gradients, at twice the size
+    Sixteen,
+}
+
+impl BitDepth {
+    pub const NAMES: [&'static str; 2] = ["8", "16"];
+}
+
+impl std::str::FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(BitDepth::Eight),
+            "16" => Ok(BitDepth::Sixteen),
+            _ => Err(format!("unknown bit depth `{}`, expected one of: {}", s, BitDepth::NAMES.join(", "))),
+        }
+    }
+}
+
+/// File formats a `Framebuffer` can be saved as
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum ImageFormat {
+    /// 8 or 16-bit gamma corrected PNG, with alpha if the image has it
+    #[default]
+    Png,
+    /// Plain text PPM, gamma corrected
+    Ppm,
+    /// Radiance RGBE, linear and unclamped
+    Hdr,
+    /// Portable float map, linear 32-bit floats
+    Pfm,
+    /// OpenEXR, linear
+    Exr { pixel_type: ExrPixelType, compression: ExrCompression },
+}
+
+impl ImageFormat {
+    pub const NAMES: [&'static str; 5] = ["png", "ppm", "hdr", "pfm", "exr"];
+
+    /// The format a file extension asks for, if it's one we write
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        extension.parse().ok()
+    }
+
+    /// Write `image` in this format. `settings` only matter to PNG and PPM, and only PNG keeps
+    /// alpha: the others store the image over black.
+    pub fn write<W: Write>(&self, image: &Framebuffer, settings: &OutputSettings, w: W) -> std::io::Result<()> {
+        match self {
+            ImageFormat::Png => write_png(image, settings, w),
+            ImageFormat::Ppm => write_ppm(image, settings, w),
+            ImageFormat::Hdr => write_hdr(image, w),
+            ImageFormat::Pfm => write_pfm(image, w),
+            ImageFormat::Exr { pixel_type, compression } => write_exr(image, *pixel_type, *compression, w),
+        }
+    }
+
+    /// Read an image in this format. Only the HDR formats can be read.
+    pub fn read<R: BufRead>(&self, r: R) -> std::io::Result<Framebuffer> {
+        match self {
+            ImageFormat::Hdr => read_hdr(r),
+            ImageFormat::Pfm => read_pfm(r),
+            ImageFormat::Exr { .. } => read_exr(r),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{:?} images can't be read back", self))),
+        }
+    }
+
+    /// Read an image file in this format
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> std::io::Result<Framebuffer> {
+        self.read(BufReader::new(File::open(path)?))
+    }
+
+    /// Write `image` to a file in this format
+    pub fn save<P: AsRef<Path>>(&self, image: &Framebuffer, settings: &OutputSettings, path: P) -> std::io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write(image, settings, &mut w)?;
+        w.flush()
+    }
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(ImageFormat::Png),
+            "ppm" => Ok(ImageFormat::Ppm),
+            "hdr" => Ok(ImageFormat::Hdr),
+            "pfm" => Ok(ImageFormat::Pfm),
+            "exr" => Ok(ImageFormat::Exr { pixel_type: ExrPixelType::default(), compression: ExrCompression::default() }),
+            _ => Err(format!("unknown image format `{}`, expected one of: {}", s, ImageFormat::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Write an image as a PNG of display values, tagged with their transfer function: an sRGB
+/// chunk for sRGB, otherwise the nearest gAMA. Images with alpha are written as RGBA, with
+/// the colors divided back out of the coverage as PNG expects.
+pub fn write_png<W: Write>(image: &Framebuffer, settings: &OutputSettings, w: W) -> std::io::Result<()> {
+    let mut encoder = png::Encoder::new(w, image.width, image.height);
+    encoder.set_color(if image.alpha.is_some() { png::ColorType::RGBA } else { png::ColorType::RGB });
+    encoder.set_depth(match settings.bit_depth {
+        BitDepth::Eight => png::BitDepth::Eight,
+        BitDepth::Sixteen => png::BitDepth::Sixteen,
+    });
+    let mut writer = encoder.write_header()?;
+    if settings.transfer == Transfer::Srgb {
+        // Perceptual rendering intent
+        writer.write_chunk(*b"sRGB", &[0])?;
+    }
+    // The PNG spec asks for gAMA alongside sRGB, for decoders that don't know sRGB
+    writer.write_chunk(*b"gAMA", &settings.transfer.png_gamma().to_be_bytes())?;
+    let mut data = Vec::new();
+    for (color, alpha) in image.display_pixels(settings) {
+        match settings.bit_depth {
+            BitDepth::Eight => {
+                data.extend_from_slice(&color.get_png_color());
+                data.extend(alpha.map(|a| (a * 255.0).round() as u8));
+            }
+            BitDepth::Sixteen => {
+                // Samples are stored big-endian
+                for value in [color.r, color.g, color.b].iter().copied().chain(alpha) {
+                    data.extend_from_slice(&((value.clamp(0.0, 1.0) * 65535.0).round() as u16).to_be_bytes());
+                }
+            }
+        }
+    }
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+/// Write an image as a plain text PPM of display values, with `settings.dyn_range` levels per
+/// channel. Alpha is dropped, leaving the image over black.
+pub fn write_ppm<W: Write>(image: &Framebuffer, settings: &OutputSettings, mut w: W) -> std::io::Result<()> {
+    let max_value = settings.dyn_range.clamp(2, 65536) - 1;
+    writeln!(w, "P3")?;
+    writeln!(w, "{} {}", image.width, image.height)?;
+    writeln!(w, "{}", max_value)?;
+    for &pixel in &image.pixels {
+        let pixel = settings.display_color(pixel);
+        let level = |value: f32| (value.clamp(0.0, 0.999) * (max_value + 1) as f32) as u32;
+        writeln!(w, "{} {} {}", level(pixel.r), level(pixel.g), level(pixel.b))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{vectors, primitives, cameras, materials, render};
+
+    #[test]
+    fn framebuffers_save_in_each_format() {
+        let mut image = Framebuffer::new(3, 2);
+        image.set(0, 0, Color::new(0.25, 1.0, 4.0));
+        image.set(2, 1, Color::new(0.01, 0.0, 0.5));
+        assert_eq!(image.get(2, 1).b, 0.5);
+
+        let mut ppm = Vec::new();
+        ImageFormat::Ppm.write(&image, &OutputSettings::default(), &mut ppm).unwrap();
+        let ppm = String::from_utf8(ppm).unwrap();
+        let lines: Vec<&str> = ppm.lines().collect();
+        assert_eq!(&lines[..4], &["P3", "3 2", "255", "137 255 255"]);
+        assert_eq!(lines.len(), 3 + 6);
+
+        let mut png_bytes = Vec::new();
+        ImageFormat::Png.write(&image, &OutputSettings::default(), &mut png_bytes).unwrap();
+        let (info, mut reader) = png::Decoder::new(&png_bytes[..]).read_info().unwrap();
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(&data[..3], &[136, 254, 254]);
+        assert_eq!(&data[15..], &[25, 0, 187]);
+
+        assert_eq!(ImageFormat::from_path("out/render.PPM"), Some(ImageFormat::Ppm));
+        assert_eq!(ImageFormat::from_path("render"), None);
+        assert_eq!(image.rms_difference(&image.clone()), 0.0);
+        assert!(image.rms_difference(&Framebuffer::new(3, 2)) > 0.0);
+    }
+
+    #[test]
+    fn pngs_hold_16_bits_and_coverage() {
+        let image = Framebuffer::from_pixels(2, 1, vec![Color::new(0.5, 0.5, 0.25), Color::new(0.0, 0.0, 0.0)]).with_alpha(vec![0.5, 0.0]);
+        let settings = OutputSettings { bit_depth: BitDepth::Sixteen, transfer: Transfer::Linear, ..Default::default() };
+        let mut bytes = Vec::new();
+        ImageFormat::Png.write(&image, &settings, &mut bytes).unwrap();
+        let mut decoder = png::Decoder::new(&bytes[..]);
+        decoder.set_transformations(png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::RGBA, png::BitDepth::Sixteen));
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        let samples: Vec<u16> = data.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+        // Colors come out of premultiplication, so half covered at 0.5 is full brightness
+        assert_eq!(samples, [65535, 65535, 32768, 32768, 0, 0, 0, 0]);
+
+        let material = materials::Material::default();
+        let mut world = primitives::HittableList::default();
+        world.add(Box::new(primitives::Sphere{center: vectors::Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.5}));
+        let cam = cameras::Camera::default();
+        let config = render::SceneConfig::builder().width(16).height(9).samples_per_pixel(4).seed(3).alpha(true).build().unwrap();
+        let rendered = render::render_pixels(&config, &world, &cam);
+        let alpha = rendered.alpha().unwrap();
+        assert_eq!((alpha[0], alpha[4 * 16 + 8]), (0.0, 1.0));
+        assert_eq!(rendered.get(0, 0).g, 0.0);
+        assert!(render::render_pixels(&config.into_builder().alpha(false).build().unwrap(), &world, &cam).alpha().is_none());
+    }
+}
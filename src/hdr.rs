@@ -0,0 +1,688 @@
+// High dynamic range image files: Radiance RGBE, PFM and OpenEXR, written and read back as
+// linear radiance with nothing clamped
+use super::colors::*;
+use super::framebuffer::*;
+
+use std::convert::TryInto;
+use std::io::{self, BufRead, Read, Write};
+
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const EXR_TILED: u32 = 0x200;
+const EXR_DEEP: u32 = 0x800;
+const EXR_MULTIPART: u32 = 0x1000;
+const EXR_UINT: i32 = 0;
+const EXR_HALF: i32 = 1;
+const EXR_FLOAT: i32 = 2;
+const EXR_NO_COMPRESSION: u8 = 0;
+const EXR_ZIPS_COMPRESSION: u8 = 2;
+const EXR_ZIP_COMPRESSION: u8 = 3;
+const ZIP_LEVEL: u8 = 6;
+// Larger images are refused, so a bad header can't ask for more memory than any real image needs.
+// 16384 by 8192 fits, the biggest environment maps in common use.
+const MAX_PIXELS: u32 = 1 << 27;
+
+/// How many bits each channel of an OpenEXR file is stored with
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum ExrPixelType {
+    /// 16-bit floats, about three significant digits and plenty for display
+    #[default]
+    Half,
+    /// 32-bit floats, exactly what the renderer computed
+    Float,
+}
+
+impl ExrPixelType {
+    pub const NAMES: [&'static str; 2] = ["half", "float"];
+
+    fn code(&self) -> i32 {
+        match self {
+            ExrPixelType::Half => EXR_HALF,
+            ExrPixelType::Float => EXR_FLOAT,
+        }
+    }
+}
+
+impl std::str::FromStr for ExrPixelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half" => Ok(ExrPixelType::Half),
+            "float" => Ok(ExrPixelType::Float),
+            _ => Err(format!("unknown EXR pixel type `{}`, expected one of: {}", s, ExrPixelType::NAMES.join(", "))),
+        }
+    }
+}
+
+/// How the scanlines of an OpenEXR file are compressed
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum ExrCompression {
+    None,
+    /// Deflate over blocks of 16 scanlines, lossless
+    #[default]
+    Zip,
+}
+
+impl ExrCompression {
+    pub const NAMES: [&'static str; 2] = ["none", "zip"];
+
+    fn code(&self) -> u8 {
+        match self {
+            ExrCompression::None => EXR_NO_COMPRESSION,
+            ExrCompression::Zip => EXR_ZIP_COMPRESSION,
+        }
+    }
+}
+
+impl std::str::FromStr for ExrCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ExrCompression::None),
+            "zip" => Ok(ExrCompression::Zip),
+            _ => Err(format!("unknown EXR compression `{}`, expected one of: {}", s, ExrCompression::NAMES.join(", "))),
+        }
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// The pixel count of an image from a file header, if it isn't empty or implausibly large
+fn pixel_count(width: u32, height: u32) -> io::Result<usize> {
+    match width.checked_mul(height) {
+        Some(pixels) if pixels > 0 && pixels <= MAX_PIXELS => Ok(pixels as usize),
+        Some(0) => Err(invalid_data("image is empty")),
+        _ => Err(invalid_data(format!("{}x{} image is too large", width, height))),
+    }
+}
+
+/// Write an image as a Radiance HDR file: 8-bit RGB mantissas sharing an exponent
+pub fn write_hdr<W: Write>(image: &Framebuffer, mut w: W) -> io::Result<()> {
+    write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", image.height(), image.width())?;
+    let data: Vec<u8> = image.pixels().iter().flat_map(|&c| to_rgbe(c)).collect();
+    w.write_all(&data)
+}
+
+/// Read a Radiance HDR file, flat or run length encoded, stored top row first
+pub fn read_hdr<R: BufRead>(mut r: R) -> io::Result<Framebuffer> {
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid_data("not a Radiance HDR file"));
+    }
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err(invalid_data("HDR header has no end"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid_data(format!("unsupported HDR pixel format `{}`", format)));
+            }
+        }
+    }
+
+    line.clear();
+    r.read_line(&mut line)?;
+    let (width, height) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (width.parse::<u32>(), height.parse::<u32>()),
+        _ => return Err(invalid_data(format!("unsupported HDR image orientation `{}`, expected -Y height +X width", line.trim_end()))),
+    };
+    let (width, height) = match (width, height) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => (width, height),
+        _ => return Err(invalid_data(format!("bad HDR image size `{}`", line.trim_end()))),
+    };
+
+    let mut pixels = Vec::with_capacity(pixel_count(width, height)?);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_hdr_scanline(&mut r, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| from_rgbe(rgbe)));
+    }
+    Ok(Framebuffer::from_pixels(width, height, pixels))
+}
+
+// One scanline, either flat RGBE pixels or the newer format with each channel run length encoded
+fn read_hdr_scanline<R: Read>(r: &mut R, scanline: &mut [[u8; 4]]) -> io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    r.read_exact(&mut first)?;
+    let encoded = (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+    if !encoded {
+        if first[..3] == [1, 1, 1] {
+            return Err(invalid_data("old style HDR run length encoding isn't supported"));
+        }
+        scanline[0] = first;
+        for pixel in &mut scanline[1..] {
+            r.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+    if ((first[2] as usize) << 8 | first[3] as usize) != width {
+        return Err(invalid_data("HDR scanline length doesn't match the image width"));
+    }
+
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 2];
+            r.read_exact(&mut count[..1])?;
+            let (run, length) = match count[0] {
+                n if n > 128 => (true, (n - 128) as usize),
+                n => (false, n as usize),
+            };
+            if length == 0 || x + length > width {
+                return Err(invalid_data("bad HDR run length"));
+            }
+            if run {
+                r.read_exact(&mut count[1..])?;
+                for pixel in &mut scanline[x..x + length] {
+                    pixel[channel] = count[1];
+                }
+            }
+            else {
+                for pixel in &mut scanline[x..x + length] {
+                    r.read_exact(&mut count[1..])?;
+                    pixel[channel] = count[1];
+                }
+            }
+            x += length;
+        }
+    }
+    Ok(())
+}
+
+// Shared exponent encoding, black for anything too small, negative or not a number
+fn to_rgbe(color: Color) -> [u8; 4] {
+    let (r, g, b) = (color.r.max(0.0) as f64, color.g.max(0.0) as f64, color.b.max(0.0) as f64);
+    let brightest = r.max(g).max(b).min(f32::MAX as f64);
+    if brightest.is_nan() || brightest < 1e-32 {
+        return [0; 4];
+    }
+    // brightest = mantissa * 2^exponent, with the mantissa in [0.5, 1)
+    let mut exponent = brightest.log2().floor() as i32 + 1;
+    if brightest / 2f64.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let scale = 256.0 / 2f64.powi(exponent);
+    let byte = |value: f64| (value * scale).min(255.0) as u8;
+    [byte(r), byte(g), byte(b), (exponent + 128) as u8]
+}
+
+fn from_rgbe(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    let scale = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    let channel = |byte: u8| ((byte as f64 + 0.5) * scale) as f32;
+    Color::new(channel(rgbe[0]), channel(rgbe[1]), channel(rgbe[2]))
+}
+
+/// Write an image as a little endian color PFM, which stores rows bottom first
+pub fn write_pfm<W: Write>(image: &Framebuffer, mut w: W) -> io::Result<()> {
+    write!(w, "PF\n{} {}\n-1.0\n", image.width(), image.height())?;
+    let mut data = Vec::with_capacity(image.pixels().len() * 12);
+    for row in image.pixels().chunks(image.width() as usize).rev() {
+        for pixel in row {
+            for channel in [pixel.r, pixel.g, pixel.b] {
+                data.extend_from_slice(&channel.to_le_bytes());
+            }
+        }
+    }
+    w.write_all(&data)
+}
+
+/// Read a color (PF) or greyscale (Pf) PFM file of either byte order
+pub fn read_pfm<R: BufRead>(mut r: R) -> io::Result<Framebuffer> {
+    let channels = match pfm_token(&mut r)?.as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("not a PFM file")),
+    };
+    let width: u32 = pfm_token(&mut r)?.parse().map_err(|_| invalid_data("bad PFM width"))?;
+    let height: u32 = pfm_token(&mut r)?.parse().map_err(|_| invalid_data("bad PFM height"))?;
+    let scale: f32 = pfm_token(&mut r)?.parse().map_err(|_| invalid_data("bad PFM scale"))?;
+    if width == 0 || height == 0 || scale == 0.0 || !scale.is_finite() {
+        return Err(invalid_data("bad PFM header"));
+    }
+
+    let mut data = vec![0u8; pixel_count(width, height)? * channels * 4];
+    r.read_exact(&mut data)?;
+    // A negative scale means little endian
+    let values: Vec<f32> = data.chunks_exact(4).map(|bytes| {
+        let bytes = bytes.try_into().unwrap();
+        if scale < 0.0 { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }
+    }).collect();
+    let mut image = Framebuffer::new(width, height);
+    for (k, pixel) in values.chunks_exact(channels).enumerate() {
+        let (x, y) = (k as u32 % width, height - 1 - k as u32 / width);
+        let color = match *pixel {
+            [grey] => Color::new(grey, grey, grey),
+            [r, g, b] => Color::new(r, g, b),
+            _ => unreachable!(),
+        };
+        image.set(x, y, color);
+    }
+    Ok(image)
+}
+
+// A whitespace separated header field, and the single whitespace byte after it
+fn pfm_token<R: BufRead>(r: &mut R) -> io::Result<String> {
+    let mut token = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        r.read_exact(&mut byte)?;
+        match (byte[0].is_ascii_whitespace(), token.is_empty()) {
+            (true, true) => continue,
+            (true, false) => break,
+            (false, _) => token.push(byte[0]),
+        }
+        if token.len() > 64 {
+            return Err(invalid_data("bad PFM header"));
+        }
+    }
+    String::from_utf8(token).map_err(|_| invalid_data("bad PFM header"))
+}
+
+/// Write an image as a single part, scanline OpenEXR file with R, G and B channels
+pub fn write_exr<W: Write>(image: &Framebuffer, pixel_type: ExrPixelType, compression: ExrCompression, mut w: W) -> io::Result<()> {
+    let (width, height) = (image.width(), image.height());
+    let mut header = Vec::new();
+    header.extend_from_slice(&EXR_MAGIC);
+    header.extend_from_slice(&2u32.to_le_bytes());
+
+    let mut channels = Vec::new();
+    // Channels are listed, and stored, in alphabetical order
+    for name in ["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&pixel_type.code().to_le_bytes());
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    let attributes: [(&str, &str, Vec<u8>); 8] = [
+        ("channels", "chlist", channels),
+        ("compression", "compression", vec![compression.code()]),
+        ("dataWindow", "box2i", window.clone()),
+        ("displayWindow", "box2i", window),
+        ("lineOrder", "lineOrder", vec![0]),
+        ("pixelAspectRatio", "float", 1.0f32.to_le_bytes().to_vec()),
+        ("screenWindowCenter", "v2f", [0.0f32, 0.0].iter().flat_map(|v| v.to_le_bytes()).collect()),
+        ("screenWindowWidth", "float", 1.0f32.to_le_bytes().to_vec()),
+    ];
+    for (name, kind, value) in attributes {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(&value);
+    }
+    header.push(0);
+
+    let lines_per_block = exr_lines_per_block(compression.code()).unwrap();
+    let mut chunks = Vec::new();
+    for first_line in (0..height).step_by(lines_per_block as usize) {
+        let mut raw = Vec::new();
+        for y in first_line..(first_line + lines_per_block).min(height) {
+            let row = &image.pixels()[(y * width) as usize..((y + 1) * width) as usize];
+            for channel in [|c: &Color| c.b, |c: &Color| c.g, |c: &Color| c.r] {
+                for pixel in row {
+                    match pixel_type {
+                        ExrPixelType::Half => raw.extend_from_slice(&f32_to_half(channel(pixel)).to_le_bytes()),
+                        ExrPixelType::Float => raw.extend_from_slice(&channel(pixel).to_le_bytes()),
+                    }
+                }
+            }
+        }
+        // Blocks that don't shrink are stored as they are, which readers tell apart by size
+        let data = match compression {
+            ExrCompression::None => raw,
+            ExrCompression::Zip => Some(zip_compress(&raw)).filter(|zipped| zipped.len() < raw.len()).unwrap_or(raw),
+        };
+        chunks.push((first_line, data));
+    }
+
+    let mut offset = (header.len() + 8 * chunks.len()) as u64;
+    w.write_all(&header)?;
+    for (_, data) in &chunks {
+        w.write_all(&offset.to_le_bytes())?;
+        offset += 8 + data.len() as u64;
+    }
+    for (first_line, data) in &chunks {
+        w.write_all(&(*first_line as i32).to_le_bytes())?;
+        w.write_all(&(data.len() as i32).to_le_bytes())?;
+        w.write_all(data)?;
+    }
+    Ok(())
+}
+
+/// Read a single part, scanline OpenEXR file, uncompressed or ZIP compressed. R, G and B
+/// channels (or Y alone, as grey) are read and any others are ignored.
+pub fn read_exr<R: Read>(mut r: R) -> io::Result<Framebuffer> {
+    let mut file = Vec::new();
+    r.read_to_end(&mut file)?;
+    let mut bytes = ExrBytes { data: &file, pos: 0 };
+    if bytes.take(4)? != EXR_MAGIC {
+        return Err(invalid_data("not an OpenEXR file"));
+    }
+    let version = bytes.u32()?;
+    if version & 0xff != 2 || version & (EXR_TILED | EXR_DEEP | EXR_MULTIPART) != 0 {
+        return Err(invalid_data("only single part scanline OpenEXR files are supported"));
+    }
+
+    let mut channels = Vec::new();
+    let mut compression = None;
+    let mut window = None;
+    loop {
+        let name = bytes.name()?;
+        if name.is_empty() {
+            break;
+        }
+        let _kind = bytes.name()?;
+        let size = bytes.i32()?;
+        let mut value = ExrBytes { data: bytes.take(size.max(0) as usize)?, pos: 0 };
+        match name.as_str() {
+            "channels" => {
+                while value.data.get(value.pos).is_some_and(|&b| b != 0) {
+                    let channel = value.name()?;
+                    let pixel_type = value.i32()?;
+                    value.take(4)?;
+                    if (value.i32()?, value.i32()?) != (1, 1) {
+                        return Err(invalid_data(format!("EXR channel {} is subsampled, which isn't supported", channel)));
+                    }
+                    if !(EXR_UINT..=EXR_FLOAT).contains(&pixel_type) {
+                        return Err(invalid_data(format!("EXR channel {} has unknown pixel type {}", channel, pixel_type)));
+                    }
+                    channels.push((channel, pixel_type));
+                }
+            }
+            "compression" => compression = Some(value.u8()?),
+            "dataWindow" => window = Some([value.i32()?, value.i32()?, value.i32()?, value.i32()?]),
+            _ => (),
+        }
+    }
+
+    let compression = compression.ok_or_else(|| invalid_data("EXR header has no compression"))?;
+    let [x_min, y_min, x_max, y_max] = window.ok_or_else(|| invalid_data("EXR header has no data window"))?;
+    let lines_per_block = exr_lines_per_block(compression)
+        .ok_or_else(|| invalid_data(format!("unsupported EXR compression {}", compression)))?;
+    if x_max < x_min || y_max < y_min {
+        return Err(invalid_data("EXR data window is empty"));
+    }
+    // Corners far enough apart can overflow i32 as well as u32
+    let side = |min: i32, max: i32| max.checked_sub(min).and_then(|d| d.checked_add(1)).ok_or_else(|| invalid_data("EXR data window is too large"));
+    let (width, height) = (side(x_min, x_max)? as u32, side(y_min, y_max)? as u32);
+    pixel_count(width, height)?;
+    let bytes_per_line: usize = channels.iter().map(|&(_, pixel_type)| exr_type_size(pixel_type) * width as usize).sum();
+
+    let blocks = height.div_ceil(lines_per_block);
+    let offsets = (0..blocks).map(|_| bytes.u64()).collect::<io::Result<Vec<u64>>>()?;
+    let mut image = Framebuffer::new(width, height);
+    for offset in offsets {
+        let mut chunk = ExrBytes { data: &file, pos: offset as usize };
+        let first_line = chunk.i32()?.checked_sub(y_min).ok_or_else(|| invalid_data("EXR block is outside the data window"))?;
+        let size = chunk.i32()?.max(0) as usize;
+        let data = chunk.take(size)?;
+        if first_line < 0 || first_line as u32 >= height {
+            return Err(invalid_data("EXR block is outside the data window"));
+        }
+        let lines = lines_per_block.min(height - first_line as u32);
+        let expected = lines as usize * bytes_per_line;
+        let raw = match size {
+            _ if size == expected => data.to_vec(),
+            _ if compression != EXR_NO_COMPRESSION => zip_decompress(data)?,
+            _ => return Err(invalid_data("EXR block is the wrong size")),
+        };
+        if raw.len() != expected {
+            return Err(invalid_data("EXR block is the wrong size"));
+        }
+
+        let mut raw = ExrBytes { data: &raw, pos: 0 };
+        for y in first_line as u32..first_line as u32 + lines {
+            for (name, pixel_type) in &channels {
+                for x in 0..width {
+                    let value = match *pixel_type {
+                        EXR_UINT => raw.u32()? as f32,
+                        EXR_HALF => half_to_f32(u16::from_le_bytes(raw.take(2)?.try_into().unwrap())),
+                        _ => f32::from_le_bytes(raw.take(4)?.try_into().unwrap()),
+                    };
+                    let mut color = image.get(x, y);
+                    match name.as_str() {
+                        "R" => color.r = value,
+                        "G" => color.g = value,
+                        "B" => color.b = value,
+                        "Y" => color = Color::new(value, value, value),
+                        _ => continue,
+                    }
+                    image.set(x, y, color);
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+fn exr_lines_per_block(compression: u8) -> Option<u32> {
+    match compression {
+        EXR_NO_COMPRESSION | EXR_ZIPS_COMPRESSION => Some(1),
+        EXR_ZIP_COMPRESSION => Some(16),
+        _ => None,
+    }
+}
+
+fn exr_type_size(pixel_type: i32) -> usize {
+    if pixel_type == EXR_HALF { 2 } else { 4 }
+}
+
+// Little endian fields from an EXR file, erroring at the end of the data
+struct ExrBytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ExrBytes<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.saturating_add(count)).ok_or_else(|| invalid_data("OpenEXR file is truncated"))?;
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    // A nul terminated attribute or channel name
+    fn name(&mut self) -> io::Result<String> {
+        let length = self.data[self.pos.min(self.data.len())..].iter().position(|&b| b == 0)
+            .ok_or_else(|| invalid_data("OpenEXR file is truncated"))?;
+        let name = String::from_utf8_lossy(self.take(length)?).into_owned();
+        self.pos += 1;
+        Ok(name)
+    }
+}
+
+// OpenEXR's ZIP: split the bytes into even and odd halves, store the differences between
+// neighbours, then deflate
+fn zip_compress(raw: &[u8]) -> Vec<u8> {
+    let half = raw.len().div_ceil(2);
+    let mut bytes = vec![0u8; raw.len()];
+    for (i, &byte) in raw.iter().enumerate() {
+        bytes[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = byte;
+    }
+    for i in (1..bytes.len()).rev() {
+        bytes[i] = bytes[i].wrapping_sub(bytes[i - 1]).wrapping_add(128);
+    }
+    miniz_oxide::deflate::compress_to_vec_zlib(&bytes, ZIP_LEVEL)
+}
+
+fn zip_decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut bytes = miniz_oxide::inflate::decompress_to_vec_zlib(data).map_err(|_| invalid_data("corrupt OpenEXR ZIP block"))?;
+    for i in 1..bytes.len() {
+        bytes[i] = bytes[i - 1].wrapping_add(bytes[i]).wrapping_sub(128);
+    }
+    let half = bytes.len().div_ceil(2);
+    Ok((0..bytes.len()).map(|i| if i % 2 == 0 { bytes[i / 2] } else { bytes[half + i / 2] }).collect())
+}
+
+/// The nearest 16-bit float, rounding ties to even. Too large becomes infinity.
+pub fn f32_to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let (half, shift, mantissa) = if exponent <= 0 {
+        // Subnormal, with the implicit leading one shifted down into the mantissa
+        if exponent < -10 {
+            return sign;
+        }
+        let shift = (14 - exponent) as u32;
+        let mantissa = mantissa | 0x80_0000;
+        (mantissa >> shift, shift, mantissa)
+    }
+    else {
+        (((exponent as u32) << 10) | (mantissa >> 13), 13, mantissa)
+    };
+    let remainder = mantissa & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    let round_up = remainder > halfway || (remainder == halfway && half & 1 == 1);
+    // Rounding up can carry into the exponent, which is still the right answer
+    sign | (half + round_up as u32) as u16
+}
+
+/// The exact value of a 16-bit float
+pub fn half_to_f32(bits: u16) -> f32 {
+    let sign = ((bits & 0x8000) as u32) << 16;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+    match exponent {
+        0 => {
+            let value = mantissa as f32 * 2f32.powi(-24);
+            if sign != 0 { -value } else { value }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exponent + 112) << 23) | (mantissa << 13)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An EXR header with just the attributes the reader needs, and no pixels
+    fn exr_header(window: [i32; 4]) -> Vec<u8> {
+        let mut exr = EXR_MAGIC.to_vec();
+        exr.extend_from_slice(&2u32.to_le_bytes());
+        exr.extend_from_slice(b"compression\0compression\0");
+        exr.extend_from_slice(&1i32.to_le_bytes());
+        exr.push(EXR_NO_COMPRESSION);
+        exr.extend_from_slice(b"dataWindow\0box2i\0");
+        exr.extend_from_slice(&16i32.to_le_bytes());
+        exr.extend(window.iter().flat_map(|v| v.to_le_bytes()));
+        exr.push(0);
+        exr
+    }
+
+    #[test]
+    fn huge_or_garbage_headers_are_invalid_data() {
+        let invalid = |result: io::Result<Framebuffer>| matches!(result, Err(err) if err.kind() == io::ErrorKind::InvalidData);
+        assert!(invalid(read_hdr(&b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 70000 +X 70000\n"[..])));
+        assert!(invalid(read_hdr(&b"#?RADIANCE\n\n-Y 4294967295 +X 4294967295\n"[..])));
+        assert!(invalid(read_hdr(&b"#?RADIANCE\n\n-Y lots +X 2\n"[..])));
+        assert!(invalid(read_hdr(&b"GIF89a"[..])));
+        assert!(invalid(read_pfm(&b"PF\n70000 70000\n-1.0\n"[..])));
+        assert!(invalid(read_pfm(&b"PF\n65536 65536\n-1.0\n"[..])));
+        assert!(invalid(read_pfm(&b"PF\n-3 two\n-1.0\n"[..])));
+        assert!(invalid(read_exr(&exr_header([0, 0, 69999, 69999])[..])));
+        assert!(invalid(read_exr(&exr_header([i32::MIN, 0, i32::MAX, 0])[..])));
+        assert!(invalid(read_exr(&b"\x76\x2f\x31\x01garbage"[..])));
+    }
+
+    #[test]
+    fn hdr_images_round_trip() {
+        let mut image = Framebuffer::new(20, 18);
+        for y in 0..18 {
+            for x in 0..20 {
+                image.set(x, y, Color::new(x as f32 * 0.1, 50.0 / (y + 1) as f32, 0.001 * (x + y) as f32));
+            }
+        }
+        image.set(3, 4, Color::new(0.0, 0.0, 0.0));
+        let round_trip = |format: ImageFormat| {
+            let mut bytes = Vec::new();
+            format.write(&image, &OutputSettings::default(), &mut bytes).unwrap();
+            (format.read(&bytes[..]).unwrap(), bytes.len())
+        };
+        let max_error = |other: &Framebuffer, relative: bool| image.pixels().iter().zip(other.pixels()).map(|(a, b)| {
+            let scale = if relative { a.r.max(a.g).max(a.b).max(1e-6) } else { 1.0 };
+            [a.r - b.r, a.g - b.g, a.b - b.b].iter().fold(0.0f32, |m, d| m.max(d.abs())) / scale
+        }).fold(0.0, f32::max);
+
+        let (pfm, _) = round_trip(ImageFormat::Pfm);
+        assert_eq!((pfm.width(), pfm.height()), (20, 18));
+        assert_eq!(max_error(&pfm, false), 0.0);
+        // Shared exponents keep about 1% of the brightest channel
+        let (rgbe, _) = round_trip(ImageFormat::Hdr);
+        assert!(max_error(&rgbe, true) < 0.01);
+        assert_eq!(rgbe.get(3, 4).g, 0.0);
+
+        let exr = |pixel_type, compression| round_trip(ImageFormat::Exr { pixel_type, compression });
+        let (float, raw_size) = exr(ExrPixelType::Float, ExrCompression::None);
+        assert_eq!(max_error(&float, false), 0.0);
+        let (zipped, zipped_size) = exr(ExrPixelType::Float, ExrCompression::Zip);
+        assert_eq!(max_error(&zipped, false), 0.0);
+        assert!(zipped_size < raw_size);
+        for compression in [ExrCompression::None, ExrCompression::Zip] {
+            assert!(max_error(&exr(ExrPixelType::Half, compression).0, true) < 0.001);
+        }
+
+        assert_eq!(f32_to_half(1.0), 0x3c00);
+        assert_eq!(f32_to_half(65504.0), 0x7bff);
+        assert_eq!(f32_to_half(1e6), 0x7c00);
+        assert_eq!(f32_to_half(2f32.powi(-24)), 1);
+        assert!((0..0x7c00u16).all(|bits| f32_to_half(half_to_f32(bits)) == bits));
+
+        // Run length encoded scanlines, as other programs write them: one run per channel
+        let mut rle = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=1.0\n\n-Y 1 +X 8\n".to_vec();
+        rle.extend_from_slice(&[2, 2, 0, 8, 136, 128, 136, 64, 136, 0, 136, 129]);
+        let decoded = ImageFormat::Hdr.read(&rle[..]).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (8, 1));
+        let c = decoded.get(7, 0);
+        assert!((c.r - 1.0).abs() < 0.01 && (c.g - 0.5).abs() < 0.01 && c.b < 0.01);
+
+        let mut truncated = Vec::new();
+        ImageFormat::Exr { pixel_type: ExrPixelType::Half, compression: ExrCompression::Zip }.write(&image, &OutputSettings::default(), &mut truncated).unwrap();
+        truncated.truncate(truncated.len() - 10);
+        let error = ImageFormat::Exr { pixel_type: ExrPixelType::Half, compression: ExrCompression::Zip }.read(&truncated[..]).err();
+        assert_eq!(error.map(|err| err.kind()), Some(std::io::ErrorKind::InvalidData));
+        assert!(ImageFormat::Png.read(&b""[..]).is_err());
+    }
+}
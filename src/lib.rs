@@ -7,6 +7,20 @@ pub mod primitives;
 pub mod cameras;
 pub mod materials;
 pub mod render;
+pub mod bvh;
+pub mod scene;
+pub mod obj;
+pub mod textures;
+pub mod samplers;
+pub mod checkpoint;
+pub mod framebuffer;
+pub mod hdr;
+pub mod tonemap;
+pub mod lights;
+pub mod environment;
+pub mod sky;
+#[cfg(test)]
+mod test_util;
 
 #[cfg(test)]
 mod tests {
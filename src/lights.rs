@@ -0,0 +1,723 @@
+// Lights, sampled directly so small emitters are found without waiting for a path to hit them
+use super::colors::*;
+use super::vectors::*;
+use super::rays::*;
+use super::primitives::*;
+use super::materials::*;
+use super::environment::*;
+use Vector3 as Point3;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+// Spherical triangles smaller than this, in steradians, are sampled by area instead, as the
+// spherical construction loses too much precision. Larger ones are close to a hemisphere.
+const MIN_SPHERICAL_AREA: f32 = 3e-4;
+const MAX_SPHERICAL_AREA: f32 = 6.22;
+
+/// A light as seen from a point: the unit direction toward it, how far away it is, the
+/// radiance arriving from it, and the solid angle density the direction was picked with
+pub struct LightSample {
+    pub direction: Vector3,
+    pub distance: f32,
+    pub radiance: Color,
+    pub pdf: f32,
+}
+
+/// Something that gives off light and can pick directions toward itself
+pub trait Light: Send + Sync {
+    /// A direction toward the light from `p`, picked with two uniform numbers, or None if
+    /// the light can't be seen from there
+    fn sample(&self, p: Point3, u: (f32, f32)) -> Option<LightSample>;
+
+    /// The solid angle density `sample` picks `direction` from `p` with
+    fn pdf(&self, p: Point3, direction: Vector3) -> f32;
+
+    /// Roughly how much light this gives off, as luminance in watts, for picking brighter
+    /// lights more often. Lights at infinity count what falls on a disk of `world_radius`.
+    fn power(&self, world_radius: f32) -> f32;
+
+    /// Whether rays can't hit this light, so it's only ever found by sampling it and its
+    /// samples count in full. True for point, spot and directional lights.
+    fn is_punctual(&self) -> bool {
+        false
+    }
+}
+
+/// How an area light picks points on its surface
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum LightSampling {
+    /// Evenly over the solid angle the shape covers, so every sample lands on the visible side
+    /// and nearby lights aren't dominated by the part closest to the point
+    #[default]
+    SolidAngle,
+    /// Evenly over the surface area, simpler but noisier close up
+    Area,
+}
+
+impl LightSampling {
+    pub const NAMES: [&'static str; 2] = ["solid_angle", "area"];
+}
+
+impl std::str::FromStr for LightSampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "solid_angle" => Ok(LightSampling::SolidAngle),
+            "area" => Ok(LightSampling::Area),
+            _ => Err(format!("unknown light sampling `{}`, expected one of: {}", s, LightSampling::NAMES.join(", "))),
+        }
+    }
+}
+
+/// How the integrator chooses which light to sample at each bounce
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum LightSelection {
+    /// Every light equally often
+    Uniform,
+    /// In proportion to each light's power, so dim lights don't take samples from bright ones
+    #[default]
+    Power,
+}
+
+impl LightSelection {
+    pub const NAMES: [&'static str; 2] = ["uniform", "power"];
+}
+
+impl std::str::FromStr for LightSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(LightSelection::Uniform),
+            "power" => Ok(LightSelection::Power),
+            _ => Err(format!("unknown light selection `{}`, expected one of: {}", s, LightSelection::NAMES.join(", "))),
+        }
+    }
+}
+
+/// Every light in a world and its environment, gathered once per render, with the odds of
+/// picking each
+pub struct LightList<'a> {
+    lights: Vec<&'a dyn Light>,
+    cdf: Vec<f32>, // Running total of the pick probabilities, ending at 1
+    indices: HashMap<usize, usize>, // From a light's address to its place in `lights`
+}
+
+impl <'a> LightList<'a> {
+    pub fn new(world: &'a impl Hittable, environment: &'a Environment, selection: LightSelection) -> Self {
+        let mut lights = world.lights();
+        lights.extend(environment.light());
+        let world_radius = world.bounding_box().map_or(1.0, |b| 0.5 * (b.maximum - b.minimum).length());
+        let mut weights: Vec<f32> = match selection {
+            LightSelection::Uniform => vec![1.0; lights.len()],
+            LightSelection::Power => lights.iter().map(|light| light.power(world_radius).max(0.0)).collect(),
+        };
+        let total: f32 = weights.iter().sum();
+        // With no idea how bright anything is, fall back to picking evenly
+        if !(total > 0.0 && total.is_finite()) {
+            weights = vec![1.0; lights.len()];
+        }
+        let total: f32 = weights.iter().sum();
+        let mut running = 0.0;
+        let mut cdf: Vec<f32> = weights.iter().map(|w| { running += w / total; running }).collect();
+        if let Some(last) = cdf.last_mut() {
+            *last = 1.0;
+        }
+        let indices = lights.iter().enumerate().map(|(k, &light)| (address(light), k)).collect();
+        Self { lights, cdf, indices }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    /// Pick a light with a uniform number, along with the probability of picking it
+    pub fn pick(&self, u: f32) -> Option<(&'a dyn Light, f32)> {
+        let index = self.cdf.partition_point(|&c| c <= u).min(self.lights.len().checked_sub(1)?);
+        Some((self.lights[index], self.probability(index)))
+    }
+
+    /// The probability `pick` chooses `light`, 0 for lights not in the list
+    pub fn pick_pdf(&self, light: &dyn Light) -> f32 {
+        self.indices.get(&address(light)).map_or(0.0, |&index| self.probability(index))
+    }
+
+    fn probability(&self, index: usize) -> f32 {
+        self.cdf[index] - if index > 0 { self.cdf[index - 1] } else { 0.0 }
+    }
+}
+
+// Lights are told apart by where they live, as the world hands out references to itself
+fn address(light: &dyn Light) -> usize {
+    (light as *const dyn Light).cast::<()>() as usize
+}
+
+/// Light from a single point, the same in every direction. `intensity` is in watts per
+/// steradian, so a surface facing the light `d` away receives `intensity / d^2`.
+pub struct PointLight {
+    position: Point3,
+    color: Color,
+    intensity: f32,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, color: Color, intensity: f32) -> Self {
+        Self { position, color, intensity }
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Point3, _u: (f32, f32)) -> Option<LightSample> {
+        let (direction, distance) = toward(p, self.position)?;
+        Some(LightSample { direction, distance, radiance: (self.intensity / (distance * distance)) * self.color, pdf: 1.0 })
+    }
+
+    fn pdf(&self, _p: Point3, _direction: Vector3) -> f32 {
+        0.0
+    }
+
+    fn power(&self, _world_radius: f32) -> f32 {
+        4.0 * PI * self.intensity * self.color.luminance()
+    }
+
+    fn is_punctual(&self) -> bool {
+        true
+    }
+}
+
+/// A point light shining into a cone. Full `intensity` (watts per steradian) within
+/// `inner_angle` of the axis, fading smoothly to nothing at `outer_angle`, both in degrees.
+pub struct SpotLight {
+    position: Point3,
+    direction: Vector3,
+    color: Color,
+    intensity: f32,
+    cos_inner: f32,
+    cos_outer: f32,
+}
+
+impl SpotLight {
+    /// `direction` is the cone's axis, pointing away from the light
+    pub fn new(position: Point3, direction: Vector3, color: Color, intensity: f32, inner_angle: f32, outer_angle: f32) -> Self {
+        Self {
+            position,
+            direction: direction.unit_vector(),
+            color,
+            intensity,
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+        }
+    }
+
+    // 1 inside the inner cone, 0 outside the outer, smoothstepped between
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if self.cos_inner <= self.cos_outer {
+            return if cos_theta >= self.cos_outer { 1.0 } else { 0.0 };
+        }
+        let t = ((cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Point3, _u: (f32, f32)) -> Option<LightSample> {
+        let (direction, distance) = toward(p, self.position)?;
+        let falloff = self.falloff(-direction.dot(self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample { direction, distance, radiance: (falloff * self.intensity / (distance * distance)) * self.color, pdf: 1.0 })
+    }
+
+    fn pdf(&self, _p: Point3, _direction: Vector3) -> f32 {
+        0.0
+    }
+
+    fn power(&self, _world_radius: f32) -> f32 {
+        // The smoothstep's average over the falloff cone is close to halfway between the edges
+        let cone = 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer));
+        cone * self.intensity * self.color.luminance()
+    }
+
+    fn is_punctual(&self) -> bool {
+        true
+    }
+}
+
+/// Parallel light from far away, like the sun. `intensity` is the irradiance in watts per
+/// square metre on a surface facing the light. With an angular diameter, in degrees, the
+/// light comes from a small disk of sky instead of one direction, softening shadows.
+pub struct DirectionalLight {
+    direction: Vector3,
+    color: Color,
+    intensity: f32,
+    one_minus_cos_max: f32, // Of the disk's angular radius, 0 for a single direction
+}
+
+impl DirectionalLight {
+    /// `direction` is the way the light travels, away from the sun
+    pub fn new(direction: Vector3, color: Color, intensity: f32, angular_diameter: f32) -> Self {
+        let half_angle = (angular_diameter / 2.0).to_radians();
+        // 1 - cos(x) as 2 sin^2(x / 2), so tiny disks don't round to nothing
+        let one_minus_cos_max = 2.0 * (half_angle / 2.0).sin().powi(2);
+        Self { direction: direction.unit_vector(), color, intensity, one_minus_cos_max }
+    }
+
+    /// The way the light travels
+    pub fn direction(&self) -> Vector3 {
+        self.direction
+    }
+
+    /// The solid angle density `sample` picks `direction` with, toward the light. Zero outside
+    /// the disk, and for light from a single direction, which can't be found any other way.
+    pub fn disk_pdf(&self, direction: Vector3) -> f32 {
+        if self.one_minus_cos_max <= 0.0 {
+            return 0.0;
+        }
+        // 1 - cos of the angle from the axis, as half the squared distance between unit vectors
+        let one_minus_cos = 0.5 * (direction.unit_vector() + self.direction).length_squared();
+        if one_minus_cos <= self.one_minus_cos_max { 1.0 / (2.0 * PI * self.one_minus_cos_max) } else { 0.0 }
+    }
+
+    /// Radiance seen looking along `direction`, black off the disk
+    pub fn disk_radiance(&self, direction: Vector3) -> Color {
+        (self.intensity * self.disk_pdf(direction)) * self.color
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _p: Point3, u: (f32, f32)) -> Option<LightSample> {
+        let axis = -self.direction;
+        if self.one_minus_cos_max <= 0.0 {
+            return Some(LightSample { direction: axis, distance: f32::INFINITY, radiance: self.intensity * self.color, pdf: 1.0 });
+        }
+        // The disk is evenly bright, its irradiance spread over the solid angle it covers
+        let pdf = 1.0 / (2.0 * PI * self.one_minus_cos_max);
+        Some(LightSample {
+            direction: sample_cone(axis, self.one_minus_cos_max, u),
+            distance: f32::INFINITY,
+            radiance: (self.intensity * pdf) * self.color,
+            pdf,
+        })
+    }
+
+    fn pdf(&self, _p: Point3, _direction: Vector3) -> f32 {
+        0.0
+    }
+
+    fn power(&self, world_radius: f32) -> f32 {
+        PI * world_radius * world_radius * self.intensity * self.color.luminance()
+    }
+
+    fn is_punctual(&self) -> bool {
+        true
+    }
+}
+
+// The unit direction and distance from p to a point, None if they're the same
+fn toward(p: Point3, position: Point3) -> Option<(Vector3, f32)> {
+    let offset = position - p;
+    let distance = offset.length();
+    (distance > 0.0).then(|| (offset / distance, distance))
+}
+
+/// Weight for a sample drawn with density `pdf`, when another strategy could have drawn the
+/// same path with density `other_pdf` (Veach's power heuristic, with an exponent of 2)
+pub fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+// Spheres pick directions evenly over the cone they fill, which for a sphere seen from
+// outside only ever lands on the visible side. From inside, every direction hits it. Picked
+// by area, points on the far side are hidden by the near side and give nothing.
+impl Light for Sphere<'_> {
+    fn sample(&self, p: Point3, u: (f32, f32)) -> Option<LightSample> {
+        if self.material.light_sampling() == LightSampling::Area {
+            let point = self.center + self.radius.abs() * uniform_sphere(u);
+            return sample_area_point(self, p, point, 1.0 / sphere_area(self.radius));
+        }
+        let direction = match cone_to_sphere(p, self.center, self.radius) {
+            None => uniform_sphere(u),
+            Some((axis, one_minus_cos_max)) => sample_cone(axis, one_minus_cos_max, u),
+        };
+        let ray = Ray { origin: p, direction };
+        let rec = self.hit(&ray, 0.0, f32::INFINITY)?;
+        Some(LightSample {
+            direction,
+            distance: rec.t,
+            radiance: self.material.emitted(&ray, &rec),
+            pdf: self.pdf(p, direction),
+        })
+    }
+
+    fn pdf(&self, p: Point3, direction: Vector3) -> f32 {
+        if self.material.light_sampling() == LightSampling::Area {
+            return area_pdf_toward(self, p, direction, 1.0 / sphere_area(self.radius));
+        }
+        match cone_to_sphere(p, self.center, self.radius) {
+            None => 1.0 / (4.0 * PI),
+            Some((_, one_minus_cos_max)) => 1.0 / (2.0 * PI * one_minus_cos_max),
+        }
+    }
+
+    fn power(&self, _world_radius: f32) -> f32 {
+        surface_power(self.material, sphere_area(self.radius))
+    }
+}
+
+// Triangles pick directions evenly over the spherical triangle they cover, unless it's too
+// small or too large to do accurately, when they fall back to picking points by area
+impl Light for Triangle<'_> {
+    fn sample(&self, p: Point3, u: (f32, f32)) -> Option<LightSample> {
+        sample_flat(self, &[[self.v0, self.v1, self.v2]], self.material.light_sampling(), p, u)
+    }
+
+    fn pdf(&self, p: Point3, direction: Vector3) -> f32 {
+        flat_pdf(self, &[[self.v0, self.v1, self.v2]], self.material.light_sampling(), p, direction)
+    }
+
+    fn power(&self, _world_radius: f32) -> f32 {
+        surface_power(self.material, triangle_area([self.v0, self.v1, self.v2]))
+    }
+}
+
+// Rectangles are sampled as their two triangles, each picked in proportion to the solid
+// angle (or area) it covers, so together they're still even over the whole rectangle
+impl Light for Rect<'_> {
+    fn sample(&self, p: Point3, u: (f32, f32)) -> Option<LightSample> {
+        sample_flat(self, &self.triangles(), self.material.light_sampling(), p, u)
+    }
+
+    fn pdf(&self, p: Point3, direction: Vector3) -> f32 {
+        flat_pdf(self, &self.triangles(), self.material.light_sampling(), p, direction)
+    }
+
+    fn power(&self, _world_radius: f32) -> f32 {
+        surface_power(self.material, self.edge_u.cross(self.edge_v).length())
+    }
+}
+
+// Luminous power of a surface evenly covered in `material`, from each side that shines
+fn surface_power(material: &Material, area: f32) -> f32 {
+    let sides = if material.is_two_sided() { 2.0 } else { 1.0 };
+    sides * PI * area * material.average_emission().luminance()
+}
+
+fn sphere_area(radius: f32) -> f32 {
+    4.0 * PI * radius * radius
+}
+
+fn triangle_area([v0, v1, v2]: [Point3; 3]) -> f32 {
+    0.5 * (v1 - v0).cross(v2 - v0).length()
+}
+
+// Light reaching p from `point` on `shape`, picked with density `area_pdf` per unit area.
+// None if the point is hidden by another part of the shape or seen edge on.
+fn sample_area_point(shape: &dyn Hittable, p: Point3, point: Point3, area_pdf: f32) -> Option<LightSample> {
+    let (direction, distance) = toward(p, point)?;
+    let ray = Ray { origin: p, direction };
+    let rec = shape.hit(&ray, 0.0, f32::INFINITY)?;
+    if rec.t < distance * (1.0 - 1e-3) {
+        return None;
+    }
+    let cosine = rec.normal.dot(direction).abs();
+    if cosine <= 0.0 {
+        return None;
+    }
+    Some(LightSample {
+        direction,
+        distance: rec.t,
+        radiance: rec.material.emitted(&ray, &rec),
+        // Area density to solid angle density
+        pdf: area_pdf * rec.t * rec.t / cosine,
+    })
+}
+
+// The solid angle density of `sample_area_point` picking `direction` from p
+fn area_pdf_toward(shape: &dyn Hittable, p: Point3, direction: Vector3, area_pdf: f32) -> f32 {
+    let direction = direction.unit_vector();
+    match shape.hit(&Ray { origin: p, direction }, 0.0, f32::INFINITY) {
+        None => 0.0,
+        Some(rec) => {
+            let cosine = rec.normal.dot(direction).abs();
+            if cosine > 0.0 { area_pdf * rec.t * rec.t / cosine } else { 0.0 }
+        }
+    }
+}
+
+// The total solid angle of `triangles` seen from p, if they should be sampled by it
+fn spherical_sampling(triangles: &[[Point3; 3]], sampling: LightSampling, p: Point3) -> Option<f32> {
+    if sampling == LightSampling::Area {
+        return None;
+    }
+    let mut total = 0.0;
+    for &triangle in triangles {
+        let solid_angle = spherical_triangle_area(triangle, p);
+        if !(MIN_SPHERICAL_AREA..=MAX_SPHERICAL_AREA).contains(&solid_angle) {
+            return None;
+        }
+        total += solid_angle;
+    }
+    Some(total)
+}
+
+// Pick one of `weights` in proportion to its size, and stretch what's left of u back over 0 to 1
+fn pick_weighted(weights: impl Iterator<Item = f32>, total: f32, u: f32) -> (usize, f32) {
+    let mut u = u * total;
+    let mut last = (0, 0.0);
+    for (k, weight) in weights.enumerate() {
+        if u < weight {
+            return (k, u / weight);
+        }
+        u -= weight;
+        last = (k, 1.0);
+    }
+    last
+}
+
+// A flat light made of triangles, picked over the solid angle they cover or by area
+fn sample_flat(shape: &dyn Hittable, triangles: &[[Point3; 3]], sampling: LightSampling, p: Point3, (u1, u2): (f32, f32)) -> Option<LightSample> {
+    match spherical_sampling(triangles, sampling, p) {
+        Some(total) => {
+            let (k, u1) = pick_weighted(triangles.iter().map(|&t| spherical_triangle_area(t, p)), total, u1);
+            let direction = sample_spherical_triangle(triangles[k], p, (u1, u2))?;
+            let ray = Ray { origin: p, direction };
+            let rec = shape.hit(&ray, 0.0, f32::INFINITY)?;
+            Some(LightSample { direction, distance: rec.t, radiance: rec.material.emitted(&ray, &rec), pdf: 1.0 / total })
+        }
+        None => {
+            let total: f32 = triangles.iter().map(|&t| triangle_area(t)).sum();
+            let (k, u1) = pick_weighted(triangles.iter().map(|&t| triangle_area(t)), total, u1);
+            let [v0, v1, v2] = triangles[k];
+            let su = u1.sqrt();
+            let point = (1.0 - su) * v0 + (su * (1.0 - u2)) * v1 + (su * u2) * v2;
+            sample_area_point(shape, p, point, 1.0 / total)
+        }
+    }
+}
+
+// The solid angle density of `sample_flat` picking `direction` from p
+fn flat_pdf(shape: &dyn Hittable, triangles: &[[Point3; 3]], sampling: LightSampling, p: Point3, direction: Vector3) -> f32 {
+    match spherical_sampling(triangles, sampling, p) {
+        Some(total) => {
+            let hit = shape.hit(&Ray { origin: p, direction }, 0.0, f32::INFINITY).is_some();
+            if hit { 1.0 / total } else { 0.0 }
+        }
+        None => {
+            let total: f32 = triangles.iter().map(|&t| triangle_area(t)).sum();
+            area_pdf_toward(shape, p, direction, 1.0 / total)
+        }
+    }
+}
+
+// Solid angle of a triangle seen from p (Van Oosterom and Strackee 1983)
+fn spherical_triangle_area([v0, v1, v2]: [Point3; 3], p: Point3) -> f32 {
+    let (a, b, c) = ((v0 - p).unit_vector(), (v1 - p).unit_vector(), (v2 - p).unit_vector());
+    let area = 2.0 * a.dot(b.cross(c)).abs().atan2(1.0 + a.dot(b) + b.dot(c) + c.dot(a));
+    if area.is_finite() { area.abs() } else { 0.0 }
+}
+
+// A direction from p spread evenly over the solid angle of a triangle, by Arvo's method as
+// written in pbrt-v4. None if the triangle is seen edge on.
+fn sample_spherical_triangle([v0, v1, v2]: [Point3; 3], p: Point3, (u1, u2): (f32, f32)) -> Option<Vector3> {
+    let (a, b, c) = ((v0 - p).unit_vector(), (v1 - p).unit_vector(), (v2 - p).unit_vector());
+    // Normals of the great circles through each edge
+    let (n_ab, n_bc, n_ca) = (a.cross(b), b.cross(c), c.cross(a));
+    if n_ab.near_zero() || n_bc.near_zero() || n_ca.near_zero() {
+        return None;
+    }
+    let (n_ab, n_bc, n_ca) = (n_ab.unit_vector(), n_bc.unit_vector(), n_ca.unit_vector());
+    // Angles at each corner, which sum to the area plus pi
+    let alpha = angle_between(n_ab, -n_ca);
+    let beta = angle_between(n_bc, -n_ab);
+    let gamma = angle_between(n_ca, -n_bc);
+
+    // Cut the triangle down to a sampled fraction of its area, finding where the cut meets
+    // the edge from a to c
+    let area_plus_pi = PI + u1 * (alpha + beta + gamma - PI);
+    let (sin_alpha, cos_alpha) = alpha.sin_cos();
+    let sin_phi = area_plus_pi.sin() * cos_alpha - area_plus_pi.cos() * sin_alpha;
+    let cos_phi = area_plus_pi.cos() * cos_alpha + area_plus_pi.sin() * sin_alpha;
+    let k1 = cos_phi + cos_alpha;
+    let k2 = sin_phi - sin_alpha * a.dot(b);
+    let cos_b = ((k2 + (k2 * cos_phi - k1 * sin_phi) * cos_alpha) / ((k2 * sin_phi + k1 * cos_phi) * sin_alpha)).clamp(-1.0, 1.0);
+    let sin_b = (1.0 - cos_b * cos_b).max(0.0).sqrt();
+    let c_cut = cos_b * a + sin_b * perpendicular(c, a)?;
+
+    // Then pick evenly along the arc from b to that point
+    let cos_theta = 1.0 - u2 * (1.0 - c_cut.dot(b));
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let direction = cos_theta * b + sin_theta * perpendicular(c_cut, b)?;
+    direction.length_squared().is_finite().then_some(direction)
+}
+
+// The angle between two unit vectors, accurate when they're nearly parallel or opposite
+fn angle_between(v1: Vector3, v2: Vector3) -> f32 {
+    if v1.dot(v2) < 0.0 {
+        PI - 2.0 * ((v1 + v2).length() / 2.0).min(1.0).asin()
+    }
+    else {
+        2.0 * ((v2 - v1).length() / 2.0).min(1.0).asin()
+    }
+}
+
+// The unit part of v perpendicular to unit w, None if they're parallel
+fn perpendicular(v: Vector3, w: Vector3) -> Option<Vector3> {
+    let rest = v - v.dot(w) * w;
+    (rest.length_squared() > 0.0).then(|| rest.unit_vector())
+}
+
+// The unit axis from p to the sphere's center and 1 - cos of the cone's half angle, or None
+// from inside the sphere
+fn cone_to_sphere(p: Point3, center: Point3, radius: f32) -> Option<(Vector3, f32)> {
+    let to_center = center - p;
+    let distance_squared = to_center.length_squared();
+    let sin_squared = radius * radius / distance_squared;
+    if sin_squared >= 1.0 {
+        return None;
+    }
+    // 1 - sqrt(1 - x), without losing small cones to rounding
+    let one_minus_cos_max = sin_squared / (1.0 + (1.0 - sin_squared).sqrt());
+    Some((to_center / distance_squared.sqrt(), one_minus_cos_max))
+}
+
+// A direction spread evenly over the cone around unit `axis` where 1 - cos of the angle from
+// the axis is at most `one_minus_cos_max`
+fn sample_cone(axis: Vector3, one_minus_cos_max: f32, (u1, u2): (f32, f32)) -> Vector3 {
+    let cos_theta = 1.0 - u1 * one_minus_cos_max;
+    let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    let (a, b) = orthonormal_basis(axis);
+    (sin_theta * phi.cos()) * a + (sin_theta * phi.sin()) * b + cos_theta * axis
+}
+
+fn uniform_sphere((u1, u2): (f32, f32)) -> Vector3 {
+    let z = 1.0 - 2.0 * u1;
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Two unit vectors perpendicular to unit vector `w` and each other (Duff et al. 2017)
+pub fn orthonormal_basis(w: Vector3) -> (Vector3, Vector3) {
+    let sign = 1f32.copysign(w.z);
+    let a = -1.0 / (sign + w.z);
+    let b = w.x * w.y * a;
+    (Vector3::new(1.0 + sign * w.x * w.x * a, sign * b, -sign * w.x), Vector3::new(b, sign + w.y * w.y * a, -w.y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Integrator;
+    use crate::test_util::*;
+
+    #[test]
+    fn light_sampling_matches_brute_force() {
+        let floor_material = floor_material();
+        let lamp = Material::DiffuseLight {
+            color: Color::new(1.0, 1.0, 1.0).into(),
+            strength: 100.0,
+            two_sided: true,
+            sampling: LightSampling::SolidAngle,
+        };
+        let world = floor_world(&floor_material, vec![Box::new(Sphere{center: Vector3::new(0.0, 2.0, 0.0), radius: 0.1, material: &lamp})]);
+        let background = Environment::Constant(Color::new(0.0, 0.0, 0.0));
+        let lights = LightList::new(&world, &background, LightSelection::Power);
+        assert_eq!(lights.len(), 1);
+
+        // Straight down at the floor under the lamp, which lights it with albedo * L * (r / d)^2
+        let estimate = |integrator| estimates(integrator, &down_from(1.0), &world, &lights, &background, 2, 4000);
+        let (nee, path) = (estimate(Integrator::Nee), estimate(Integrator::Path));
+        assert_near("light sampling", mean(&nee), 0.125, 0.04);
+        assert_near("brute force", mean(&path), 0.125, 0.8);
+        assert!(spread(&nee) * 10.0 < spread(&path));
+        assert_eq!("nee".parse(), Ok(Integrator::Nee));
+    }
+
+    #[test]
+    fn punctual_lights_light_the_floor() {
+        use crate::scene::Scene;
+        let base = format!("{}[[lights]]\n", floor_scene(1.0));
+        // Outgoing radiance from the floor straight below the camera, averaged over samples
+        let floor = |light: &str| mean(&scene_estimates(&Scene::parse(&format!("{}{}", base, light)).unwrap(), &down_from(1.0), 1, 64));
+        // albedo / pi times the irradiance, 8 / 2^2 from the lights overhead and 2 from the sun
+        let expected = FLOOR_ALBEDO * 2.0 / PI;
+        assert!((floor("type = \"point\"\nposition = [0.0, 2.0, 0.0]\nintensity = 8.0\n") - expected).abs() < 1e-4);
+        let spot = |axis: &str| floor(&format!("type = \"spot\"\nposition = [0.0, 2.0, 0.0]\ndirection = {}\nintensity = 8.0\ninner_angle = 10.0\nouter_angle = 20.0\n", axis));
+        assert!((spot("[0.0, -1.0, 0.0]") - expected).abs() < 1e-4);
+        assert!(spot("[0.0, -1.0, 0.25]") > 0.0 && spot("[0.0, -1.0, 0.25]") < expected);
+        assert_eq!(spot("[0.0, -1.0, 1.0]"), 0.0);
+        assert!((floor("type = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nintensity = 2.0\n") - expected).abs() < 1e-4);
+        let soft_sun = floor("type = \"directional\"\ndirection = [0.0, -1.0, 0.0]\nintensity = 2.0\nangular_diameter = 10.0\n");
+        assert!(soft_sun < expected && soft_sun > 0.99 * expected);
+
+        let crossed_angles = "type = \"spot\"\nposition = [0.0, 2.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\nintensity = 8.0\ninner_angle = 30.0\nouter_angle = 20.0\n";
+        assert_eq!(invalid_field(&format!("{}{}", base, crossed_angles)), "inner_angle");
+        assert_eq!(invalid_field(&format!("{}type = \"point\"\nposition = [0.0, 2.0, 0.0]\nintensity = nan\n", base)), "intensity");
+
+        // Only nee renders these lights, so it's what scenes with them get unless they say
+        let point = format!("{}type = \"point\"\nposition = [0.0, 2.0, 0.0]\nintensity = 8.0\n", base);
+        assert_eq!(Scene::parse(&point).unwrap().config().integrator(), Integrator::Nee);
+        assert_eq!(Scene::parse(&floor_scene(1.0)).unwrap().config().integrator(), Integrator::Path);
+        let normals = point.replace("[render]\n", "[render]\nintegrator = \"normals\"\n");
+        assert_eq!(Scene::parse(&normals).unwrap().config().integrator(), Integrator::Normals);
+    }
+
+    #[test]
+    fn area_lights_sample_alike_and_shine_one_way() {
+        use crate::scene::Scene;
+        let base = floor_scene(0.5);
+        // A one metre square lamp a metre above the floor, facing down unless its edges are swapped
+        let lamp = |fields: &str, edges: &str| format!("[materials.lamp]\ntype = \"light\"\n{}\n\
+            [[primitives]]\ntype = \"rect\"\ncorner = [-0.5, 1.0, -0.5]\nedges = {}\nmaterial = \"lamp\"\n", fields, edges);
+        let down = "[[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]";
+        let up = "[[0.0, 0.0, 1.0], [1.0, 0.0, 0.0]]";
+        let under = |lamp: &str| mean(&scene_estimates(&Scene::parse(&format!("{}{}", base, lamp)).unwrap(), &down_from(0.5), 2, 2000));
+        // albedo / pi times the irradiance, pi times the form factor of four corner-aligned quarters
+        let quarter = (0.5 / 1.25f32.sqrt()) * (0.5 / 1.25f32.sqrt()).atan() / PI;
+        let expected = FLOOR_ALBEDO * 4.0 * quarter;
+        for sampling in ["solid_angle", "area"].iter() {
+            let lit = under(&lamp(&format!("two_sided = false\nsampling = \"{}\"", sampling), down));
+            assert_near(sampling, lit, expected, 0.02);
+        }
+        assert_eq!(under(&lamp("two_sided = false", up)), 0.0);
+        assert_near("two sided", under(&lamp("two_sided = true", up)), expected, 0.02);
+        for edges in ["[[1.0, 0.0, 0.0], [-2.0, 0.0, 0.0]]", "[[1.0, 0.0, 0.0], [0.0, 0.0, 0.0]]"].iter() {
+            assert_eq!(invalid_field(&format!("{}{}", base, lamp("", edges))), "edges");
+        }
+
+        // The same lamp as a mesh is sampled a triangle at a time
+        let floor_material = floor_material();
+        let mesh_lamp = Material::DiffuseLight {
+            color: Color::new(1.0, 1.0, 1.0).into(),
+            strength: 1.0,
+            two_sided: false,
+            sampling: LightSampling::SolidAngle,
+        };
+        let corners = vec![Vector3::new(-0.5, 1.0, -0.5), Vector3::new(0.5, 1.0, -0.5), Vector3::new(0.5, 1.0, 0.5), Vector3::new(-0.5, 1.0, 0.5)];
+        let world = floor_world(&floor_material, vec![Box::new(TriangleMesh::new(corners, None, None, vec![[0, 1, 2], [0, 2, 3]], &mesh_lamp).unwrap())]);
+        let background = Environment::Constant(Color::new(0.0, 0.0, 0.0));
+        let lights = LightList::new(&world, &background, LightSelection::Power);
+        assert_eq!(lights.len(), 2);
+        let lit = mean(&estimates(Integrator::Nee, &down_from(0.5), &world, &lights, &background, 2, 2000));
+        assert_near("the mesh", lit, expected, 0.02);
+
+        // Two lamps, one nine times brighter, picked by power or evenly
+        let two_lamps = format!("{}{}[materials.bright]\ntype = \"light\"\nstrength = 9.0\n\
+            [[primitives]]\ntype = \"rect\"\ncorner = [2.0, 1.0, -0.5]\nedges = {}\nmaterial = \"bright\"\n", base, lamp("", down), down);
+        let scene = Scene::parse(&two_lamps).unwrap();
+        let world = scene.world();
+        let by_power = LightList::new(&world, scene.config().environment(), LightSelection::Power);
+        let picked: Vec<f32> = [0.05, 0.5].iter().map(|&u| by_power.pick(u).unwrap().1).collect();
+        assert!((picked[0] - 0.1).abs() < 1e-4 && (picked[1] - 0.9).abs() < 1e-4, "picked with {:?}", picked);
+        assert_eq!(LightList::new(&world, scene.config().environment(), LightSelection::Uniform).pick(0.05).unwrap().1, 0.5);
+    }
+}
@@ -1,25 +1,364 @@
-
 use rustrays::util::*;
-use rustrays::*;
-use log::*;
+use rustrays::scene::*;
+use rustrays::render::*;
+use rustrays::samplers::*;
+use rustrays::framebuffer::*;
+use rustrays::hdr::{ExrCompression, ExrPixelType};
+use rustrays::tonemap::ToneMap;
+use rustrays::colors::Transfer;
+use rustrays::lights::LightSelection;
+use rustrays::environment::{Environment, EnvironmentMap};
+use rustrays::checkpoint;
+
+use std::fs;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+const USAGE: &str = "\
+RustRays - a ray tracer in one weekend, in Rust
+
+USAGE:
+    rustrays [OPTIONS] [SCENE_FILE]
+
+Renders SCENE_FILE (a TOML scene description), or a built-in scene.
+With neither, renders the metal-spheres scene to image.png.
+
+OPTIONS:
+    -s, --scene <name>          Built-in scene: metal-spheres, sphere-on-sphere, random-spheres,
+                                cornell-spheres
+    -w, --width <pixels>        Image width, the height follows the aspect ratio unless given too
+    -H, --height <pixels>       Image height, the width follows the aspect ratio unless given too
+    -n, --samples <count>       Samples per pixel
+    -d, --max-depth <bounces>   Maximum ray bounces
+    -o, --output <path>         Output file [default: image.png]
+    -f, --format <format>       png, ppm, or linear hdr, pfm or exr [default: from the output
+                                extension, else png]
+        --tone-map <name>       clamp, reinhard, reinhard-extended, hable or aces, for png and ppm
+                                [default: clamp]
+        --white-point <value>   Radiance reinhard-extended maps to white [default: 4]
+        --exposure <stops>      Brighten png and ppm output, or darken if negative [default: 0]
+        --transfer <name>       Encoding of png and ppm values: srgb, linear, gamma2.2 or rec709
+                                [default: srgb]
+        --bit-depth <bits>      8 or 16 bits per png channel [default: 8]
+        --alpha                 Leave the background transparent, writing coverage as png alpha
+        --exr-pixel <type>      half or float channels in EXR output [default: half]
+        --exr-compression <c>   none or zip for EXR output [default: zip]
+    -i, --integrator <name>     path, nee (path tracing that samples lights directly), normals,
+                                diffuse or davenbusters [default: the scene's, else path]
+        --light-selection <how> How nee picks a light: power or uniform [default: power]
+        --environment <path>    Light the scene with an equirectangular .hdr, .pfm or .exr map,
+                                in place of the scene's background
+        --target-error <error>  Sample each pixel until its relative error is this low, instead of
+                                a fixed count
+        --min-samples <count>   Fewest samples per pixel when adaptive [default: 16]
+        --max-samples <count>   Most samples per pixel when adaptive [default: 1024]
+        --sample-counts <path>  Also write a PNG of how many samples each pixel took
+        --progressive           Render one sample per pixel per pass, until the sample count, the
+                                time limit or Ctrl-C, keeping the output image up to date
+        --time-limit <seconds>  Stop a progressive render after this long, and with no -n keep
+                                sampling until then
+        --update-passes <count> Write the progressive image every this many passes
+        --update-secs <seconds> Write the progressive image every this many seconds
+        --checkpoint <path>     Save the progressive render's samples here whenever the image is
+                                written
+        --resume                Add to the samples in the --checkpoint file, which must be from the
+                                same scene and settings. -n counts the samples already taken.
+        --sampler <name>        independent, stratified, halton, sobol or cmj [default: independent]
+    -t, --threads <count>       Worker threads, 0 for one per core [default: 0]
+        --seed <number>         Seed for a reproducible render
+    -h, --help                  Print this help
+";
+
+#[derive(Default)]
+struct Options {
+    scene_file: Option<String>,
+    builtin: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    samples: Option<u32>,
+    max_depth: Option<u32>,
+    output: Option<String>,
+    format: Option<ImageFormat>,
+    exr_pixel: Option<ExrPixelType>,
+    tone_map: Option<ToneMap>,
+    white_point: Option<f32>,
+    transfer: Option<Transfer>,
+    exposure: Option<f32>,
+    bit_depth: Option<BitDepth>,
+    alpha: bool,
+    exr_compression: Option<ExrCompression>,
+    integrator: Option<Integrator>,
+    light_selection: Option<LightSelection>,
+    environment: Option<String>,
+    sampler: Option<SamplerKind>,
+    target_error: Option<f32>,
+    min_samples: Option<u32>,
+    max_samples: Option<u32>,
+    sample_counts: Option<String>,
+    progressive: bool,
+    time_limit: Option<f32>,
+    update_passes: Option<u32>,
+    update_secs: Option<f32>,
+    checkpoint: Option<String>,
+    resume: bool,
+    threads: Option<usize>,
+    seed: Option<u64>,
+}
+
+// Returns None when help was asked for
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-s" | "--scene" => options.builtin = Some(value(&arg)?),
+            "-w" | "--width" => options.width = Some(positive(&arg, &value(&arg)?)?),
+            "-H" | "--height" => options.height = Some(positive(&arg, &value(&arg)?)?),
+            "-n" | "--samples" => options.samples = Some(positive(&arg, &value(&arg)?)?),
+            "-d" | "--max-depth" => options.max_depth = Some(positive(&arg, &value(&arg)?)?),
+            "-o" | "--output" => options.output = Some(value(&arg)?),
+            "-f" | "--format" => options.format = Some(value(&arg)?.parse()?),
+            "--tone-map" => options.tone_map = Some(value(&arg)?.parse()?),
+            "--white-point" => options.white_point = Some(fraction(&arg, &value(&arg)?)?),
+            "--exposure" => options.exposure = Some(stops(&arg, &value(&arg)?)?),
+            "--transfer" => options.transfer = Some(value(&arg)?.parse()?),
+            "--bit-depth" => options.bit_depth = Some(value(&arg)?.parse()?),
+            "--alpha" => options.alpha = true,
+            "--exr-pixel" => options.exr_pixel = Some(value(&arg)?.parse()?),
+            "--exr-compression" => options.exr_compression = Some(value(&arg)?.parse()?),
+            "-i" | "--integrator" => options.integrator = Some(value(&arg)?.parse()?),
+            "--light-selection" => options.light_selection = Some(value(&arg)?.parse()?),
+            "--environment" => options.environment = Some(value(&arg)?),
+            "--progressive" => options.progressive = true,
+            "--time-limit" => options.time_limit = Some(fraction(&arg, &value(&arg)?)?),
+            "--update-passes" => options.update_passes = Some(positive(&arg, &value(&arg)?)?),
+            "--update-secs" => options.update_secs = Some(fraction(&arg, &value(&arg)?)?),
+            "--checkpoint" => options.checkpoint = Some(value(&arg)?),
+            "--resume" => options.resume = true,
+            "--sampler" => options.sampler = Some(value(&arg)?.parse()?),
+            "--target-error" => options.target_error = Some(fraction(&arg, &value(&arg)?)?),
+            "--min-samples" => options.min_samples = Some(positive(&arg, &value(&arg)?)?),
+            "--max-samples" => options.max_samples = Some(positive(&arg, &value(&arg)?)?),
+            "--sample-counts" => options.sample_counts = Some(value(&arg)?),
+            "-t" | "--threads" => options.threads = Some(number(&arg, &value(&arg)?)?),
+            "--seed" => options.seed = Some(number(&arg, &value(&arg)?)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ if options.scene_file.is_some() => return Err(format!("unexpected argument `{}`, only one scene file can be rendered", arg)),
+            _ => options.scene_file = Some(arg),
+        }
+    }
+    if options.scene_file.is_some() && options.builtin.is_some() {
+        return Err("give either a scene file or --scene, not both".to_string());
+    }
+    if !options.progressive && (options.time_limit.is_some() || options.update_passes.is_some() || options.update_secs.is_some() || options.checkpoint.is_some()) {
+        return Err("--time-limit, --update-passes, --update-secs and --checkpoint only apply with --progressive".to_string());
+    }
+    if options.resume && options.checkpoint.is_none() {
+        return Err("--resume needs a --checkpoint file to resume from".to_string());
+    }
+    if options.progressive && (options.target_error.is_some() || options.sample_counts.is_some()) {
+        return Err("--target-error and --sample-counts don't apply with --progressive".to_string());
+    }
+    let output_format = options.format.or_else(|| ImageFormat::from_path(options.output.as_deref().unwrap_or("image.png")));
+    if !matches!(output_format, Some(ImageFormat::Exr { .. })) && (options.exr_pixel.is_some() || options.exr_compression.is_some()) {
+        return Err("--exr-pixel and --exr-compression only apply to EXR output".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a whole number, got `{}`", name, value))
+}
+
+fn fraction(name: &str, value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(x) if x > 0.0 && x.is_finite() => Ok(x),
+        _ => Err(format!("{} expects a positive number, got `{}`", name, value)),
+    }
+}
+
+fn stops(name: &str, value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(x) if x.is_finite() => Ok(x),
+        _ => Err(format!("{} expects a number of stops, got `{}`", name, value)),
+    }
+}
+
+fn positive(name: &str, value: &str) -> Result<u32, String> {
+    match number(name, value)? {
+        0 => Err(format!("{} must be at least 1", name)),
+        n => Ok(n),
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let mut scene = match (&options.scene_file, &options.builtin) {
+        (Some(path), _) => Scene::load(path).map_err(|err| format!("{}: {}", path, err))?,
+        (None, Some(name)) => builtin_scene(name)
+            .ok_or_else(|| format!("unknown built-in scene `{}`, expected one of: {}", name, BUILTIN_SCENES.join(", ")))?,
+        (None, None) => builtin_scene("metal-spheres").unwrap(),
+    };
+    // What a checkpoint has to have been saved from
+    let scene_source = match (&options.scene_file, &options.builtin) {
+        (Some(path), _) => std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?,
+        (None, name) => name.as_deref().unwrap_or("metal-spheres").as_bytes().to_vec(),
+    };
+
+    let mut config = scene.config().clone().into_builder();
+    if let Some(width) = options.width {
+        config = config.width(width);
+    }
+    if let Some(height) = options.height {
+        config = config.height(height);
+    }
+    if let Some(samples) = options.samples {
+        // A fixed count on the command line wins over adaptive sampling from the scene file
+        config = config.samples_per_pixel(samples).fixed_samples();
+    }
+    let adaptive = match options.target_error {
+        Some(target_error) => Some(AdaptiveSampling { target_error, ..scene.config().adaptive().unwrap_or_else(|| AdaptiveSampling::new(target_error)) }),
+        None if options.samples.is_some() => None,
+        None => scene.config().adaptive(),
+    };
+    match adaptive {
+        Some(mut adaptive) => {
+            adaptive.min_samples = options.min_samples.unwrap_or(adaptive.min_samples);
+            adaptive.max_samples = options.max_samples.unwrap_or(adaptive.max_samples);
+            config = config.adaptive(adaptive);
+        }
+        None if options.min_samples.is_some() || options.max_samples.is_some() => {
+            return Err("--min-samples and --max-samples only apply with --target-error".to_string());
+        }
+        None => (),
+    }
+    if let Some(max_depth) = options.max_depth {
+        config = config.max_depth(max_depth);
+    }
+    if let Some(integrator) = options.integrator {
+        config = config.integrator(integrator);
+    }
+    if let Some(light_selection) = options.light_selection {
+        config = config.light_selection(light_selection);
+    }
+    if let Some(path) = &options.environment {
+        let map = EnvironmentMap::load(path, 0.0, 1.0).map_err(|err| format!("could not load environment {}: {}", path, err))?;
+        config = config.environment(Environment::Map(Arc::new(map)));
+    }
+    if let Some(sampler) = options.sampler {
+        config = config.sampler(sampler);
+    }
+    if let Some(threads) = options.threads {
+        config = config.threads(threads);
+    }
+    if let Some(seed) = options.seed {
+        config = config.seed(seed);
+    }
+    if let Some(exposure) = options.exposure {
+        config = config.exposure(exposure);
+    }
+    // A white point on its own means extended Reinhard
+    let tone_map = match (options.tone_map, options.white_point) {
+        (None | Some(ToneMap::ExtendedReinhard { .. }), Some(white_point)) => Some(ToneMap::ExtendedReinhard { white_point }),
+        (Some(_), Some(_)) => return Err("--white-point only applies to reinhard-extended".to_string()),
+        (tone_map, None) => tone_map,
+    };
+    if let Some(tone_map) = tone_map {
+        config = config.tone_map(tone_map);
+    }
+    if let Some(transfer) = options.transfer {
+        config = config.transfer(transfer);
+    }
+    if let Some(bit_depth) = options.bit_depth {
+        config = config.bit_depth(bit_depth);
+    }
+    if options.alpha {
+        config = config.alpha(true);
+    }
+    scene.set_config(config.build().map_err(|err| err.to_string())?);
+    if scene.has_punctual_lights() && scene.config().integrator() == Integrator::Path {
+        eprintln!("warning: point, spot and directional lights are only rendered by the nee integrator");
+    }
+
+    let output = options.output.unwrap_or_else(|| "image.png".to_string());
+    let format = match options.format.or_else(|| ImageFormat::from_path(&output)).unwrap_or_default() {
+        ImageFormat::Exr { pixel_type, compression } => ImageFormat::Exr {
+            pixel_type: options.exr_pixel.unwrap_or(pixel_type),
+            compression: options.exr_compression.unwrap_or(compression),
+        },
+        format => format,
+    };
+
+    let world = scene.world();
+    if options.progressive {
+        let progressive = Progressive {
+            // A time limit on its own means sample until it runs out
+            max_samples: match (options.samples, options.time_limit) {
+                (None, Some(_)) => None,
+                _ => Some(scene.config().samples_per_pixel()),
+            },
+            time_limit: options.time_limit.map(Duration::from_secs_f32),
+            write_every_passes: options.update_passes,
+            write_every: options.update_secs.map(Duration::from_secs_f32),
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        // The first Ctrl-C finishes the pass and writes the image, a second one exits straight away
+        signal_hook::flag::register_conditional_shutdown(signal_hook::consts::SIGINT, 1, Arc::clone(&stop))
+            .and_then(|_| signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&stop)))
+            .map_err(|err| format!("could not handle Ctrl-C: {}", err))?;
+        let scene_hash = checkpoint::scene_hash(&scene_source, scene.config());
+        let accumulator = match (&options.checkpoint, options.resume) {
+            (Some(path), true) => checkpoint::load(path, scene.config(), scene_hash).map_err(|err| err.to_string())?,
+            _ => Accumulator::new(scene.config()),
+        };
+        let checkpoint_path = &options.checkpoint;
+        let save = |accumulator: &Accumulator| -> Result<(), String> {
+            eprintln!("{} samples per pixel", accumulator.samples());
+            write_image_atomically(scene.config(), &accumulator.average(), &output, format)
+                .map_err(|err| format!("could not write {}: {}", output, err))?;
+            match checkpoint_path {
+                Some(path) => checkpoint::save(path, accumulator, scene_hash).map_err(|err| format!("could not save checkpoint {}: {}", path, err)),
+                None => Ok(()),
+            }
+        };
+        let accumulator = render_progressive(scene.config(), &world, &scene.camera, &progressive, accumulator, &stop, |accumulator| {
+            save(accumulator).map_err(std::io::Error::other)
+        });
+        accumulator.map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+
+    let (image, counts) = render_pixels_and_counts(scene.config(), &world, &scene.camera);
+    format.save(&image, &scene.config().output(), &output).map_err(|err| format!("could not write {}: {}", output, err))?;
+    if let Some(path) = options.sample_counts {
+        write_sample_counts_png(scene.config(), &counts, &path).map_err(|err| format!("could not write {}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+// Write next to the output and rename over it, so a render stopped part way through a write
+// still leaves the last complete image behind
+fn write_image_atomically(config: &SceneConfig, image: &Framebuffer, path: &str, format: ImageFormat) -> std::io::Result<()> {
+    let partial = format!("{}.partial", path);
+    format.save(image, &config.output(), &partial)?;
+    fs::rename(&partial, path)
+}
 
 fn main() {
-    //set_max_level(LevelFilter::Info);
-    //println!("RustRays!");
-    
-    //output_color_gradient();
-    //output_blue_white_gradient();
-    //output_sphere_on_sphere();
-    output_metal_spheres();
-
-    let v1 = vectors::Vector3 {x:1.0,y:1.0,z:1.0};
-    let v2 = vectors::Vector3 {x:1.0,y:2.0,z:3.0};
-    let v3 = vectors::Vector3 {x:2.0,y:2.0,z:2.0};
-
-    info!("Vector (1,1,1) + (1,2,3) = {}", v1+v2);
-    info!("Vector (2,2,2) * (1,2,3) = {}", v3*v2);
-    info!("Vector (2,2,2) . (1,2,3) = {}", v3.dot(v2));
-    info!("Vector (2,2,2) x (1,2,3) = {}", v3.cross(v2));
-
-    eprintln!("Done!");
+    match parse_args(std::env::args().skip(1)) {
+        Err(message) => {
+            eprintln!("error: {}\n\nRun with --help for usage.", message);
+            process::exit(2);
+        }
+        Ok(None) => print!("{}", USAGE),
+        Ok(Some(options)) => {
+            if let Err(message) = run(options) {
+                eprintln!("error: {}", message);
+                process::exit(1);
+            }
+            eprintln!("Done!");
+        }
+    }
 }
@@ -4,42 +4,127 @@ use super::primitives::*;
 use super::rays::*;
 use super::colors::*;
 use super::util::*;
+use super::textures::*;
+use super::samplers::*;
+use super::vectors::*;
+use super::lights::*;
+
+use std::f32::consts::PI;
 
 pub enum Material {
     Diffuse {
-        albedo: Color,
+        albedo: Texture,
     },
     Metal {
-        albedo: Color,
+        albedo: Texture,
         fuzz: f32,
     },
     Dialectric {
-        albedo: Color,
+        albedo: Texture,
         index_of_refraction: f32,
-    }
+    },
+    DiffuseLight {
+        color: Texture,
+        strength: f32,
+        two_sided: bool, // Otherwise only the front face shines
+        sampling: LightSampling,
+    },
 }
 
 impl Material {
     pub fn new(albedo: Color) -> Self {
         Material::Diffuse {
-            albedo,
+            albedo: albedo.into(),
         }
     }
 
-    pub fn scatter(&self, r: &Ray, rec: &HitRecord) -> (Color, Ray) {
+    /// Light given off at the hit point, black for everything but lights
+    pub fn emitted(&self, _r: &Ray, rec: &HitRecord) -> Color {
+        match self {
+            Self::DiffuseLight { two_sided: false, .. } if !rec.front_face => Color::new(0.0, 0.0, 0.0),
+            Self::DiffuseLight { color, strength, .. } => *strength * color.value(rec.u, rec.v, rec.p),
+            _ => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Light given off averaged over the surface, roughly, for weighing lights against each
+    /// other. Black for everything but lights.
+    pub fn average_emission(&self) -> Color {
+        const STEPS: u32 = 8;
+        match self {
+            Self::DiffuseLight { color, strength, .. } => {
+                let mut sum = Color::new(0.0, 0.0, 0.0);
+                for i in 0..STEPS {
+                    for j in 0..STEPS {
+                        let (u, v) = ((i as f32 + 0.5) / STEPS as f32, (j as f32 + 0.5) / STEPS as f32);
+                        sum += color.value(u, v, Vector3::new(0.0, 0.0, 0.0));
+                    }
+                }
+                (*strength / (STEPS * STEPS) as f32) * sum
+            }
+            _ => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Whether light leaves both faces of the surface, true for everything but one-sided lights
+    pub fn is_two_sided(&self) -> bool {
+        !matches!(self, Self::DiffuseLight { two_sided: false, .. })
+    }
+
+    /// How a light made of this material is sampled
+    pub fn light_sampling(&self) -> LightSampling {
+        match self {
+            Self::DiffuseLight { sampling, .. } => *sampling,
+            _ => LightSampling::default(),
+        }
+    }
+
+    /// Whether the material gives off light
+    pub fn emits(&self) -> bool {
+        matches!(self, Self::DiffuseLight { .. })
+    }
+
+    /// Whether `scatter` reflects or refracts in a single direction, which `eval` and `pdf`
+    /// can't describe. Fuzzy metal counts, its lobe has no density to evaluate.
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Self::Metal { .. } | Self::Dialectric { .. })
+    }
+
+    /// The BSDF times the cosine at the surface, for light arriving from `direction` and
+    /// leaving back along `r`. Black for specular materials and lights.
+    pub fn eval(&self, _r: &Ray, rec: &HitRecord, direction: Vector3) -> Color {
+        match self {
+            Self::Diffuse { albedo } => {
+                let cosine = rec.normal.dot(direction.unit_vector()).max(0.0);
+                (cosine / PI) * albedo.value(rec.u, rec.v, rec.p)
+            }
+            _ => Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// The solid angle density `scatter` picks `direction` with, zero for specular materials
+    pub fn pdf(&self, _r: &Ray, rec: &HitRecord, direction: Vector3) -> f32 {
+        match self {
+            Self::Diffuse { .. } => rec.normal.dot(direction.unit_vector()).max(0.0) / PI,
+            _ => 0.0,
+        }
+    }
+
+    /// Attenuation and bounced ray, or None if the ray is absorbed
+    pub fn scatter(&self, r: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
         match self {
             Self::Diffuse { albedo } => {
-                let mut scatter_dir = rand_lamb_vector(rec);
+                let mut scatter_dir = rand_lamb_vector(rec, sampler);
                 if scatter_dir.near_zero() {
                     scatter_dir = rec.normal;
                 };
                 let scattered = Ray { origin: rec.p, direction: scatter_dir};
-                (*albedo, scattered)
+                Some((albedo.value(rec.u, rec.v, rec.p), scattered))
             }
             Self::Metal { albedo, fuzz } => {
                 let reflected = r.direction.unit_vector().reflect(rec.normal);
-                let scattered = Ray{origin: rec.p, direction: reflected + *fuzz*rand_in_unit_sphere()};
-                (*albedo, scattered)
+                let scattered = Ray{origin: rec.p, direction: reflected + *fuzz*rand_in_unit_sphere(sampler)};
+                Some((albedo.value(rec.u, rec.v, rec.p), scattered))
             }
             Self::Dialectric { albedo, index_of_refraction } => {
                 let refraction_ratio = if rec.front_face {1.0/index_of_refraction} else {*index_of_refraction};
@@ -47,17 +132,18 @@ impl Material {
                 let cos_theta = (-unit_direction).dot(rec.normal).min(1.0);
                 let sin_theta = (1.0 - cos_theta*cos_theta).sqrt();
                 let cannot_refract = ( refraction_ratio * sin_theta ) > 1.0;
-                if cannot_refract || Material::reflectance(cos_theta, refraction_ratio) > rand() {
+                if cannot_refract || Material::reflectance(cos_theta, refraction_ratio) > sampler.get_1d() {
                     let reflected = unit_direction.reflect(rec.normal);
                     let scattered = Ray{origin: rec.p, direction: reflected};
-                    (*albedo, scattered)
+                    Some((albedo.value(rec.u, rec.v, rec.p), scattered))
                 }
                 else {
                     let refracted = unit_direction.refract(rec.normal, refraction_ratio);
                     let scattered = Ray{origin: rec.p, direction: refracted};
-                    (*albedo, scattered)
+                    Some((albedo.value(rec.u, rec.v, rec.p), scattered))
                 }
             }
+            Self::DiffuseLight { .. } => None,
         }
     }
 
@@ -71,7 +157,34 @@ impl Material {
 impl Default for Material {
     fn default() -> Self {
         Material::Diffuse {
-            albedo: Color::new(0.5, 0.5, 0.5)
+            albedo: Color::new(0.5, 0.5, 0.5).into()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{render, environment};
+
+    #[test]
+    fn lights_emit_and_background_can_be_black() {
+        let lamp = Material::DiffuseLight {
+            color: Color::new(1.0, 0.5, 0.25).into(),
+            strength: 4.0,
+            two_sided: true,
+            sampling: LightSampling::SolidAngle,
+        };
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-2.0), material: &lamp, radius: 0.5}));
+        let background = environment::Environment::Constant(Color::new(0.0, 0.0, 0.0));
+
+        let toward = Ray { origin: Vector3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+        let (c, coverage) = render::ray_color(&toward, &world, &background, 5, &mut IndependentSampler::new(0));
+        assert_eq!((c.r, c.g, c.b, coverage), (4.0, 2.0, 1.0, 1.0));
+
+        let away = Ray { origin: Vector3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, 1.0) };
+        let (c, coverage) = render::ray_color(&away, &world, &background, 5, &mut IndependentSampler::new(0));
+        assert_eq!((c.r, c.g, c.b, coverage), (0.0, 0.0, 0.0, 0.0));
+    }
+}
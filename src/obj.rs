@@ -0,0 +1,371 @@
+// Wavefront OBJ and MTL import
+use super::vectors::*;
+use super::colors::*;
+use super::materials::*;
+use super::primitives::*;
+use super::lights::*;
+use Vector3 as Point3;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Why an OBJ or MTL file could not be loaded
+#[derive(Debug)]
+pub enum ObjError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, line: usize, message: String },
+    Mesh { path: PathBuf, error: MeshError },
+}
+
+impl std::fmt::Display for ObjError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ObjError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ObjError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message),
+            ObjError::Mesh { path, error } => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+// Triangles sharing a material, with vertices already merged across v/vt/vn
+struct ObjGroup {
+    positions: Vec<Point3>,
+    normals: Vec<Option<Vector3>>,
+    uvs: Vec<Option<(f32, f32)>>,
+    indices: Vec<[usize; 3]>,
+    material: Option<usize>,
+    merged: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+}
+
+impl ObjGroup {
+    fn new(material: Option<usize>) -> Self {
+        Self {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            indices: Vec::new(),
+            material,
+            merged: HashMap::new(),
+        }
+    }
+}
+
+/// A loaded OBJ model, with the materials from its MTL libraries
+pub struct ObjFile {
+    groups: Vec<ObjGroup>,
+    material_names: Vec<String>,
+    materials: Vec<Material>,
+}
+
+impl ObjFile {
+    /// Load an OBJ file. `mtllib` paths are resolved next to it.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ObjFile, ObjError> {
+        let path = path.as_ref();
+        let text = read(path)?;
+        let mut parser = Parser { path, line: 0 };
+
+        let mut positions: Vec<Point3> = Vec::new();
+        let mut normals: Vec<Vector3> = Vec::new();
+        let mut uvs: Vec<(f32, f32)> = Vec::new();
+        let mut material_names = Vec::new();
+        let mut materials = Vec::new();
+        let mut groups = vec![ObjGroup::new(None)];
+        let mut current = 0; // The group faces are added to
+
+        for (number, line) in text.lines().enumerate() {
+            parser.line = number + 1;
+            let mut words = line.split('#').next().unwrap().split_whitespace();
+            let keyword = match words.next() {
+                None => continue,
+                Some(keyword) => keyword,
+            };
+            let args: Vec<&str> = words.collect();
+            match keyword {
+                "v" => positions.push(parser.vector(&args)?),
+                "vn" => normals.push(parser.vector(&args)?),
+                "vt" => {
+                    let uv = parser.floats(&args, 1, 3)?;
+                    uvs.push((uv[0], uv.get(1).copied().unwrap_or(0.0)));
+                }
+                "f" => {
+                    if args.len() < 3 {
+                        return Err(parser.error("a face needs at least 3 vertices"));
+                    }
+                    let group = &mut groups[current];
+                    let mut face = Vec::with_capacity(args.len());
+                    for arg in &args {
+                        let mut refs = arg.split('/');
+                        let v = parser.index(refs.next(), positions.len(), "position")?.unwrap();
+                        let vt = parser.index(refs.next(), uvs.len(), "texture coordinate")?;
+                        let vn = parser.index(refs.next(), normals.len(), "normal")?;
+                        let next = group.positions.len();
+                        let vertex = *group.merged.entry((v, vt, vn)).or_insert(next);
+                        if vertex == next {
+                            group.positions.push(positions[v]);
+                            group.uvs.push(vt.map(|i| uvs[i]));
+                            group.normals.push(vn.map(|i| normals[i]));
+                        }
+                        face.push(vertex);
+                    }
+                    // Fan triangulation, fine for the convex polygons modelling tools export
+                    for k in 1..face.len() - 1 {
+                        group.indices.push([face[0], face[k], face[k + 1]]);
+                    }
+                }
+                "usemtl" => {
+                    let name = args.first().ok_or_else(|| parser.error("usemtl needs a material name"))?;
+                    let material = material_names.iter().position(|n| n == name)
+                        .ok_or_else(|| parser.error(&format!("no material named `{}` in the mtllib files", name)))?;
+                    // Faces go back in with the others of their material, wherever they are in the file
+                    current = match groups.iter().position(|g| g.material == Some(material)) {
+                        Some(group) => group,
+                        None => {
+                            groups.push(ObjGroup::new(Some(material)));
+                            groups.len() - 1
+                        }
+                    };
+                }
+                "mtllib" => {
+                    if args.is_empty() {
+                        return Err(parser.error("mtllib needs a file name"));
+                    }
+                    for file in args {
+                        for (name, material) in load_mtl(path.with_file_name(file))? {
+                            material_names.push(name);
+                            materials.push(material);
+                        }
+                    }
+                }
+                // Object and group names, smoothing groups and the like don't change the geometry
+                _ => (),
+            }
+        }
+
+        groups.retain(|g| !g.indices.is_empty());
+        for group in &groups {
+            check_mesh(group.positions.len(), Some(group.normals.len()), Some(group.uvs.len()), &group.indices)
+                .map_err(|error| ObjError::Mesh { path: path.to_path_buf(), error })?;
+        }
+        Ok(ObjFile { groups, material_names, materials })
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.material_names.iter().position(|n| n == name).map(|i| &self.materials[i])
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.groups.iter().map(|g| g.indices.len()).sum()
+    }
+
+    /// One mesh per material used, with `default_material` for faces before any `usemtl`
+    pub fn meshes<'a>(&'a self, default_material: &'a Material) -> Vec<TriangleMesh<'a>> {
+        self.groups.iter().map(|g| {
+            let material = g.material.map_or(default_material, |i| &self.materials[i]);
+            group_mesh(g, material)
+        }).collect()
+    }
+
+    /// Meshes that all use `material`, ignoring the MTL files
+    pub fn meshes_with_material<'a>(&self, material: &'a Material) -> Vec<TriangleMesh<'a>> {
+        self.groups.iter().map(|g| group_mesh(g, material)).collect()
+    }
+}
+
+fn group_mesh<'a>(group: &ObjGroup, material: &'a Material) -> TriangleMesh<'a> {
+    // Vertex normals only help if every vertex has one, otherwise shade flat
+    let normals = group.normals.iter().copied().collect::<Option<Vec<_>>>();
+    let uvs = if group.uvs.iter().any(|uv| uv.is_some()) {
+        Some(group.uvs.iter().map(|uv| uv.unwrap_or((0.0, 0.0))).collect())
+    }
+    else {
+        None
+    };
+    TriangleMesh::new(group.positions.clone(), normals, uvs, group.indices.clone(), material)
+        .expect("OBJ groups are checked when the file is loaded")
+}
+
+/// Read the materials from an MTL file, mapping each onto the closest `Material` variant:
+/// emissive (`Ke`) becomes a light, transparent (`d` < 1) a dielectric with index `Ni`,
+/// mostly specular (`Ks` brighter than `Kd`) a metal with fuzz from `Ns`, and the rest diffuse.
+pub fn load_mtl<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Material)>, ObjError> {
+    let path = path.as_ref();
+    let text = read(path)?;
+    let mut parser = Parser { path, line: 0 };
+
+    let mut materials = Vec::new();
+    let mut current: Option<(String, MtlParams)> = None;
+    for (number, line) in text.lines().enumerate() {
+        parser.line = number + 1;
+        let mut words = line.split('#').next().unwrap().split_whitespace();
+        let keyword = match words.next() {
+            None => continue,
+            Some(keyword) => keyword,
+        };
+        let args: Vec<&str> = words.collect();
+        if keyword == "newmtl" {
+            let name = args.first().ok_or_else(|| parser.error("newmtl needs a material name"))?;
+            if let Some((name, params)) = current.take() {
+                materials.push((name, params.material()));
+            }
+            current = Some((name.to_string(), MtlParams::default()));
+            continue;
+        }
+        let params = match &mut current {
+            None if matches!(keyword, "Kd" | "Ks" | "Ns" | "Ni" | "d" | "Tr" | "Ke") => return Err(parser.error(&format!("{} before any newmtl", keyword))),
+            None => continue,
+            Some((_, params)) => params,
+        };
+        match keyword {
+            "Kd" => params.kd = parser.color(&args)?,
+            "Ks" => params.ks = parser.color(&args)?,
+            "Ke" => params.ke = parser.color(&args)?,
+            "Ns" => params.ns = parser.floats(&args, 1, 1)?[0],
+            "Ni" => params.ni = parser.floats(&args, 1, 1)?[0],
+            "d" => params.d = parser.floats(&args, 1, 1)?[0],
+            "Tr" => params.d = 1.0 - parser.floats(&args, 1, 1)?[0],
+            // Illumination models, texture maps and the rest we can't use yet
+            _ => (),
+        }
+    }
+    if let Some((name, params)) = current.take() {
+        materials.push((name, params.material()));
+    }
+    Ok(materials)
+}
+
+struct MtlParams {
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    ns: f32,
+    ni: f32,
+    d: f32,
+}
+
+impl Default for MtlParams {
+    fn default() -> Self {
+        Self {
+            kd: Color::new(0.8, 0.8, 0.8),
+            ks: Color::new(0.0, 0.0, 0.0),
+            ke: Color::new(0.0, 0.0, 0.0),
+            ns: 0.0,
+            ni: 1.5,
+            d: 1.0,
+        }
+    }
+}
+
+impl MtlParams {
+    fn material(&self) -> Material {
+        let brightest = |c: Color| c.r.max(c.g).max(c.b);
+        if brightest(self.ke) > 0.0 {
+            Material::DiffuseLight {
+                color: (self.ke / brightest(self.ke)).into(),
+                strength: brightest(self.ke),
+                two_sided: true,
+                sampling: LightSampling::default(),
+            }
+        }
+        else if self.d < 1.0 {
+            Material::Dialectric { albedo: Color::new(1.0, 1.0, 1.0).into(), index_of_refraction: self.ni }
+        }
+        else if brightest(self.ks) > brightest(self.kd) {
+            // A Phong exponent of 0 is completely rough, large exponents approach a mirror
+            Material::Metal { albedo: self.ks.into(), fuzz: (2.0 / (self.ns.max(0.0) + 2.0)).sqrt() }
+        }
+        else {
+            Material::Diffuse { albedo: self.kd.into() }
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|error| ObjError::Io { path: path.to_path_buf(), error })
+}
+
+// Keeps track of where we are, for error messages
+struct Parser<'p> {
+    path: &'p Path,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> ObjError {
+        ObjError::Parse { path: self.path.to_path_buf(), line: self.line, message: message.to_string() }
+    }
+
+    fn floats(&self, args: &[&str], min: usize, max: usize) -> Result<Vec<f32>, ObjError> {
+        if args.len() < min || args.len() > max {
+            return Err(self.error(&format!("expected {} numbers, found {}", if min == max { min.to_string() } else { format!("{} to {}", min, max) }, args.len())));
+        }
+        args.iter().map(|a| a.parse::<f32>().map_err(|_| self.error(&format!("`{}` is not a number", a)))).collect()
+    }
+
+    fn vector(&self, args: &[&str]) -> Result<Vector3, ObjError> {
+        // Some exporters append vertex colors or a w coordinate, which we ignore
+        let v = self.floats(args, 3, 7)?;
+        Ok(Vector3::new(v[0], v[1], v[2]))
+    }
+
+    fn color(&self, args: &[&str]) -> Result<Color, ObjError> {
+        let c = self.floats(args, 1, 3)?;
+        // A single value is grey
+        Ok(if c.len() == 1 { Color::new(c[0], c[0], c[0]) } else if c.len() == 3 { Color::new(c[0], c[1], c[2]) } else {
+            return Err(self.error("expected 1 or 3 color components"));
+        })
+    }
+
+    // Resolve a 1-based (or negative, counting back from the end) index. Empty means not given.
+    fn index(&self, text: Option<&str>, count: usize, what: &str) -> Result<Option<usize>, ObjError> {
+        let text = match text {
+            None | Some("") if what != "position" => return Ok(None),
+            None | Some("") => return Err(self.error("face vertex is missing its position")),
+            Some(text) => text,
+        };
+        let i: i64 = text.parse().map_err(|_| self.error(&format!("`{}` is not a {} index", text, what)))?;
+        let resolved = if i > 0 { i - 1 } else { count as i64 + i };
+        if i == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(&format!("{} index {} out of range, {} defined so far", what, i, count)));
+        }
+        Ok(Some(resolved as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rays, scene, test_util};
+
+    #[test]
+    fn obj_files_load_with_their_materials() {
+        let model = ObjFile::load("scenes/pyramid.obj").unwrap();
+        // A quad, four triangles and a hexagon fanned into four
+        assert_eq!(model.triangle_count(), 2 + 4 + 4);
+        assert!(matches!(model.material("gold"), Some(Material::Metal { .. })));
+        assert!(matches!(model.material("glass"), Some(Material::Dialectric { .. })));
+        assert!(matches!(model.material("matte"), Some(Material::Diffuse { .. })));
+
+        let scene = scene::Scene::load("scenes/pyramid.toml").unwrap();
+        let down = rays::Ray { origin: Vector3::new(0.0, 5.0, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) };
+        assert!((scene.world().hit(&down, 0.001, f32::INFINITY).unwrap().t - 3.0).abs() < 1e-4);
+
+        let bad = test_util::TempFile::new("obj");
+        std::fs::write(bad.path(), "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n").unwrap();
+        match ObjFile::load(bad.path()) {
+            Err(ObjError::Parse { line, .. }) => assert_eq!(line, 4),
+            _ => panic!("expected a parse error"),
+        }
+
+        // Faces are grouped by material, however the file jumps between them
+        let (mtl, model) = (test_util::TempFile::new("mtl"), test_util::TempFile::new("obj"));
+        std::fs::write(mtl.path(), "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n").unwrap();
+        let faces = "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\nusemtl blue\nf 1 2 3\nusemtl red\nf 3 2 1\n";
+        std::fs::write(model.path(), format!("mtllib {}\n{}", mtl.path().file_name().unwrap().to_str().unwrap(), faces)).unwrap();
+        let model = ObjFile::load(model.path()).unwrap();
+        let default_material = Material::default();
+        let meshes = model.meshes(&default_material);
+        assert_eq!(meshes.iter().map(|m| m.triangle_count()).collect::<Vec<_>>(), vec![2, 1]);
+    }
+}
@@ -2,6 +2,9 @@
 use super::vectors::*;
 use super::rays::*;
 use super::materials::*;
+use super::bvh::*;
+use super::lights::*;
+use Vector3 as Point3;
 
 #[derive(Copy, Clone)]
 pub struct HitRecord<'a> {
@@ -9,7 +12,10 @@ pub struct HitRecord<'a> {
     pub normal: Vector3,
     pub material: &'a Material,
     pub t: f32,
+    pub u: f32, // surface coordinates
+    pub v: f32,
     pub front_face: bool,
+    pub light: Option<&'a dyn Light>, // The light that was hit, if it can be sampled directly
 }
 
 impl std::fmt::Display for HitRecord<'_> {
@@ -18,8 +24,15 @@ impl std::fmt::Display for HitRecord<'_> {
     }
 }
 
-pub trait Hittable {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
+// Send + Sync so a world can be shared by the render threads
+pub trait Hittable: Send + Sync {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>>;
+    fn bounding_box(&self) -> Option<Aabb>; // None for objects without finite bounds
+
+    /// Emitters in this object that can be sampled directly, none by default
+    fn lights(&self) -> Vec<&dyn Light> {
+        Vec::new()
+    }
 }
 
 #[derive(Default)]
@@ -38,7 +51,7 @@ impl <'a> HittableList <'a> {
 }
 
 impl Hittable for HittableList <'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let mut temp_rec = None;
         //Was able to remove "hit_anything" because that logic is encapsulated in the use of Option<>, yay Rust!
         let mut closest_so_far = t_max;
@@ -54,6 +67,16 @@ impl Hittable for HittableList <'_> {
         }
         temp_rec
     }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let mut hittables = self.hittables.iter();
+        let first = hittables.next()?.bounding_box()?;
+        hittables.try_fold(first, |acc, h| Some(acc.surrounding_box(h.bounding_box()?)))
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        self.hittables.iter().flat_map(|h| h.lights()).collect()
+    }
 }
 
 pub struct Sphere <'a>{
@@ -63,7 +86,7 @@ pub struct Sphere <'a>{
 }
 
 impl Hittable for Sphere <'_> {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
         let oc = r.origin - self.center;
         let a = r.direction.length_squared();
         let half_b = oc.dot(r.direction);
@@ -87,6 +110,298 @@ impl Hittable for Sphere <'_> {
         let outward_normal = (p - self.center)/self.radius;
         let front_face = r.direction.dot(outward_normal) < 0.0;
         let normal = if front_face {outward_normal} else {-outward_normal};
-        Some(HitRecord {t:root, p:p, normal:normal, material:self.material, front_face:front_face})
+        let (u, v) = sphere_uv((p - self.center)/self.radius.abs());
+        let light = if self.material.emits() { Some(self as &dyn Light) } else { None };
+        Some(HitRecord {t:root, p, normal, material:self.material, u, v, front_face, light})
+    }
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vector3::new(self.radius.abs(), self.radius.abs(), self.radius.abs()); // radius is negative for hollow spheres
+        Some(Aabb::new(self.center - r, self.center + r))
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        if self.material.emits() { vec![self] } else { Vec::new() }
+    }
+}
+
+// Map a point on the unit sphere to u (angle around the y axis from x = -1) and v (from the bottom)
+pub fn sphere_uv(p: Vector3) -> (f32, f32) {
+    let theta = (-p.y).clamp(-1.0, 1.0).acos();
+    let phi = (-p.z).atan2(p.x) + std::f32::consts::PI;
+    (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI)
+}
+
+// Möller–Trumbore, returning t and the barycentric weights of v1 and v2
+fn intersect_triangle(r: &Ray, v0: Point3, v1: Point3, v2: Point3, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let pvec = r.direction.cross(edge2);
+    let det = edge1.dot(pvec);
+    // Exactly parallel. Nearly parallel rays land far outside the edges and fail the checks below.
+    if det == 0.0 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let tvec = r.origin - v0;
+    let b1 = tvec.dot(pvec) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let qvec = tvec.cross(edge1);
+    let b2 = r.direction.dot(qvec) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(qvec) * inv_det;
+    if t < t_min || t_max < t {
+        return None;
+    }
+    Some((t, b1, b2))
+}
+
+fn triangle_box(v0: Point3, v1: Point3, v2: Point3) -> Aabb {
+    Aabb::new(v0.min(v1).min(v2), v0.max(v1).max(v2))
+}
+
+/// A single flat triangle. Counter-clockwise vertices, seen from the front.
+pub struct Triangle <'a> {
+    pub v0: Point3,
+    pub v1: Point3,
+    pub v2: Point3,
+    pub material: &'a Material,
+}
+
+impl Hittable for Triangle <'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let (t, b1, b2) = intersect_triangle(r, self.v0, self.v1, self.v2, t_min, t_max)?;
+        let outward_normal = (self.v1 - self.v0).cross(self.v2 - self.v0).unit_vector();
+        let front_face = r.direction.dot(outward_normal) < 0.0;
+        let normal = if front_face {outward_normal} else {-outward_normal};
+        let light = if self.material.emits() { Some(self as &dyn Light) } else { None };
+        Some(HitRecord {t, p:r.at(t), normal, material:self.material, u:b1, v:b2, front_face, light})
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle_box(self.v0, self.v1, self.v2))
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        if self.material.emits() { vec![self] } else { Vec::new() }
+    }
+}
+
+/// A flat parallelogram from `corner` along `edge_u` and `edge_v`, usually a rectangle. The
+/// front faces along `edge_u` cross `edge_v`, and u and v run from 0 to 1 along the edges.
+pub struct Rect <'a> {
+    pub corner: Point3,
+    pub edge_u: Vector3,
+    pub edge_v: Vector3,
+    pub material: &'a Material,
+}
+
+impl Rect <'_> {
+    /// The two triangles covering the rectangle, each counter-clockwise from the front
+    pub fn triangles(&self) -> [[Point3; 3]; 2] {
+        let (c, u, v) = (self.corner, self.edge_u, self.edge_v);
+        [[c, c + u, c + u + v], [c, c + u + v, c + v]]
+    }
+}
+
+impl Hittable for Rect <'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let n = self.edge_u.cross(self.edge_v);
+        let denom = n.dot(r.direction);
+        if denom == 0.0 {
+            return None; // Ray is parallel to the rectangle
+        }
+        let t = n.dot(self.corner - r.origin) / denom;
+        if t < t_min || t_max < t {
+            return None;
+        }
+        // Coordinates of the hit along each edge, from the plane's own basis
+        let p = r.at(t);
+        let q = p - self.corner;
+        let w = n / n.length_squared();
+        let u = w.dot(q.cross(self.edge_v));
+        let v = w.dot(self.edge_u.cross(q));
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+        let outward_normal = n.unit_vector();
+        let front_face = r.direction.dot(outward_normal) < 0.0;
+        let normal = if front_face {outward_normal} else {-outward_normal};
+        let light = if self.material.emits() { Some(self as &dyn Light) } else { None };
+        Some(HitRecord {t, p, normal, material:self.material, u, v, front_face, light})
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let [[a, b, c], [_, _, d]] = self.triangles();
+        Some(triangle_box(a, b, c).surrounding_box(Aabb::new(d, d)))
     }
-}
\ No newline at end of file
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        if self.material.emits() { vec![self] } else { Vec::new() }
+    }
+}
+
+/// Indexed triangles sharing one vertex list. With vertex normals the surface is smooth shaded.
+pub struct TriangleMesh <'a> {
+    positions: Vec<Point3>,
+    normals: Option<Vec<Vector3>>,
+    uvs: Option<Vec<(f32, f32)>>,
+    indices: Vec<[usize; 3]>,
+    material: &'a Material,
+    bvh: BvhTree,
+    emitters: Vec<Triangle<'a>>, // One per face when the material emits, for light sampling
+}
+
+/// Why a triangle mesh could not be built
+#[derive(Debug, PartialEq)]
+pub enum MeshError {
+    IndexOutOfRange { face: usize, index: usize, positions: usize },
+    NormalCount { normals: usize, positions: usize },
+    UvCount { uvs: usize, positions: usize },
+}
+
+impl std::fmt::Display for MeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MeshError::IndexOutOfRange { face, index, positions } => {
+                write!(f, "triangle {} uses vertex {}, but the mesh only has {}", face, index, positions)
+            }
+            MeshError::NormalCount { normals, positions } => write!(f, "{} normals for {} positions, they must match", normals, positions),
+            MeshError::UvCount { uvs, positions } => write!(f, "{} uvs for {} positions, they must match", uvs, positions),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+impl <'a> TriangleMesh <'a> {
+    /// Normals and uvs, when given, hold one entry per position
+    pub fn new(positions: Vec<Point3>, normals: Option<Vec<Vector3>>, uvs: Option<Vec<(f32, f32)>>, indices: Vec<[usize; 3]>, material: &'a Material) -> Result<Self, MeshError> {
+        check_mesh(positions.len(), normals.as_ref().map(Vec::len), uvs.as_ref().map(Vec::len), &indices)?;
+        let boxes: Vec<Aabb> = indices.iter().map(|&[a, b, c]| triangle_box(positions[a], positions[b], positions[c])).collect();
+        let emitters = if material.emits() {
+            indices.iter().map(|&[a, b, c]| Triangle { v0: positions[a], v1: positions[b], v2: positions[c], material }).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            bvh: BvhTree::build(&boxes, SplitMethod::Sah),
+            emitters,
+            positions,
+            normals,
+            uvs,
+            indices,
+            material,
+        })
+    }
+
+    pub fn positions(&self) -> &[Point3] {
+        &self.positions
+    }
+
+    pub fn indices(&self) -> &[[usize; 3]] {
+        &self.indices
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn hit_face(&self, face: usize, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        let [i0, i1, i2] = self.indices[face];
+        let (t, b1, b2) = intersect_triangle(r, self.positions[i0], self.positions[i1], self.positions[i2], t_min, t_max)?;
+        let b0 = 1.0 - b1 - b2;
+
+        // Front and back are decided by the winding, as for a lone triangle
+        let outward_normal = (self.positions[i1] - self.positions[i0]).cross(self.positions[i2] - self.positions[i0]).unit_vector();
+        let front_face = r.direction.dot(outward_normal) < 0.0;
+        let shading_normal = match &self.normals {
+            None => outward_normal,
+            Some(n) => {
+                let smooth = (b0*n[i0] + b1*n[i1] + b2*n[i2]).unit_vector();
+                // Vertex normals that disagree with the winding would flip the lighting
+                if smooth.dot(outward_normal) < 0.0 {-smooth} else {smooth}
+            }
+        };
+        let normal = if front_face {shading_normal} else {-shading_normal};
+
+        let (u, v) = match &self.uvs {
+            None => (b1, b2),
+            Some(uv) => (b0*uv[i0].0 + b1*uv[i1].0 + b2*uv[i2].0, b0*uv[i0].1 + b1*uv[i1].1 + b2*uv[i2].1),
+        };
+        let light = self.emitters.get(face).map(|triangle| triangle as &dyn Light);
+        Some(HitRecord {t, p:r.at(t), normal, material:self.material, u, v, front_face, light})
+    }
+}
+
+impl Hittable for TriangleMesh <'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.bvh.hit(r, t_min, t_max, |face, r, t_min, t_max| self.hit_face(face, r, t_min, t_max))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bvh.bounding_box()
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        self.emitters.iter().map(|triangle| triangle as &dyn Light).collect()
+    }
+}
+
+// What `TriangleMesh::new` checks, for loaders that want to report bad data before building one
+pub(crate) fn check_mesh(positions: usize, normals: Option<usize>, uvs: Option<usize>, indices: &[[usize; 3]]) -> Result<(), MeshError> {
+    for (face, triangle) in indices.iter().enumerate() {
+        if let Some(&index) = triangle.iter().find(|&&i| i >= positions) {
+            return Err(MeshError::IndexOutOfRange { face, index, positions });
+        }
+    }
+    match (normals, uvs) {
+        (Some(normals), _) if normals != positions => Err(MeshError::NormalCount { normals, positions }),
+        (_, Some(uvs)) if uvs != positions => Err(MeshError::UvCount { uvs, positions }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangles_report_front_face_and_smooth_normals() {
+        let material = Material::default();
+        let v = [Vector3::new(-1.0, -1.0, -2.0), Vector3::new(1.0, -1.0, -2.0), Vector3::new(0.0, 1.0, -2.0)];
+        let triangle = Triangle{v0: v[0], v1: v[1], v2: v[2], material: &material};
+
+        let front = Ray { origin: Vector3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+        let hit = triangle.hit(&front, 0.001, f32::INFINITY).unwrap();
+        assert!(hit.front_face && (hit.t - 2.0).abs() < 1e-5 && hit.normal.z > 0.99);
+
+        let back = Ray { origin: Vector3::new(0.0, 0.0, -4.0), direction: Vector3::new(0.0, 0.0, 1.0) };
+        let hit = triangle.hit(&back, 0.001, f32::INFINITY).unwrap();
+        assert!(!hit.front_face && hit.normal.z < -0.99);
+
+        // However small the triangle, straight on it's still hit
+        let tiny = Triangle{v0: 1e-7 * v[0] + Vector3::new(0.0, 0.0, -2.0), v1: 1e-7 * v[1] + Vector3::new(0.0, 0.0, -2.0), v2: 1e-7 * v[2] + Vector3::new(0.0, 0.0, -2.0), material: &material};
+        assert!(tiny.hit(&front, 0.001, f32::INFINITY).is_some());
+
+        // A quad whose vertex normals lean outwards, like a patch of a sphere
+        let positions = vec![Vector3::new(-1.0, -1.0, -2.0), Vector3::new(1.0, -1.0, -2.0), Vector3::new(1.0, 1.0, -2.0), Vector3::new(-1.0, 1.0, -2.0)];
+        let normals = positions.iter().map(|p| (*p + Vector3::new(0.0, 0.0, 2.0) + Vector3::new(0.0, 0.0, 1.0)).unit_vector()).collect();
+        let too_few_uvs = TriangleMesh::new(positions.clone(), None, Some(vec![(0.0, 0.0)]), vec![[0, 1, 2]], &material);
+        assert_eq!(too_few_uvs.err(), Some(MeshError::UvCount { uvs: 1, positions: 4 }));
+        let out_of_range = TriangleMesh::new(positions.clone(), None, None, vec![[0, 1, 2], [0, 2, 4]], &material);
+        assert_eq!(out_of_range.err(), Some(MeshError::IndexOutOfRange { face: 1, index: 4, positions: 4 }));
+        let mesh = TriangleMesh::new(positions, Some(normals), None, vec![[0, 1, 2], [0, 2, 3]], &material).unwrap();
+        assert_eq!(mesh.triangle_count(), 2);
+
+        let center = mesh.hit(&front, 0.001, f32::INFINITY).unwrap();
+        assert!(center.front_face && (center.normal.z - 1.0).abs() < 0.05);
+        let off_center = Ray { origin: Vector3::new(0.5, 0.5, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+        let hit = mesh.hit(&off_center, 0.001, f32::INFINITY).unwrap();
+        assert!(hit.normal.x > 0.1 && hit.normal.y > 0.1);
+    }
+}
@@ -4,138 +4,893 @@ use super::colors::*;
 use super::primitives::*;
 use super::util::*;
 use super::rays::*;
+use super::samplers::*;
+use super::framebuffer::*;
+use super::tonemap::*;
+use super::lights::*;
+use super::environment::*;
+use super::vectors::*;
+use Vector3 as Point3;
 
 use std::path::Path;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-const ASPECT_RATIO: f32 = 16.0 / 9.0;
 const IMAGE_WIDTH: u32 = 400;
-const IMAGE_HEIGHT: u32 = (IMAGE_WIDTH as f32 / ASPECT_RATIO) as u32;
+const IMAGE_HEIGHT: u32 = IMAGE_WIDTH * 9 / 16;
 const SAMPLES_PER_PIXEL: u32 = 100;
 const MAX_DEPTH: u32 = 10;
-const DYN_RANGE: u32 = 256;
+const TILE_SIZE: u32 = 32;
+const MIN_SAMPLES: u32 = 16;
+// Progressive renders lay sampler patterns out for runs of this many samples, whatever the
+// total, so a render resumed with more samples carries on the same patterns
+const PROGRESSIVE_RUN: u32 = 16;
+const MAX_SAMPLES: u32 = 1024;
+// Relative error is measured against at least this brightness, so near-black pixels
+// don't chase tiny absolute noise
+const ERROR_FLOOR: f32 = 0.05;
 
-const INFINITY: f32 = std::f32::INFINITY;
+const INFINITY: f32 = f32::INFINITY;
+// Shadow rays stop just short of the light, so they don't hit the light itself
+const SHADOW_RAY_END: f32 = 0.999;
 
+/// How to render: image size, sampling and output settings. Built with `SceneConfig::builder`.
+#[derive(Clone)]
 pub struct SceneConfig {
-    aspect_ratio: f32,
     image_width: u32,
     image_height: u32,
-    pub samples_per_pixel: u32,
-    pub max_depth: u32,
-    pub dyn_range: u32,
+    samples_per_pixel: u32,
+    max_depth: u32,
+    output: OutputSettings,
+    threads: usize, // 0 uses one worker per available core
+    tile_size: u32,
+    seed: Option<u64>, // Some(seed) makes every pixel reproducible, whatever the thread count
+    integrator: Integrator,
+    light_selection: LightSelection,
+    sampler: SamplerKind,
+    adaptive: Option<AdaptiveSampling>,
+    environment: Environment,
+    alpha: bool, // Primary rays that miss are transparent, and coverage is kept as alpha
+}
+
+/// Per-pixel sample counts that follow the noise. Each pixel takes `min_samples`, then keeps
+/// going until the standard error of its mean brightness, relative to that brightness, drops
+/// below `target_error`, or it reaches `max_samples`. Samples are taken in runs of
+/// `min_samples`, each spread evenly over the pixel by the sampler on its own, so a pixel that
+/// stops early is as well stratified as one that doesn't. Only the last run before
+/// `max_samples` can be cut short.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub target_error: f32,
+}
+
+impl AdaptiveSampling {
+    /// 16 to 1024 samples per pixel, stopping at `target_error`
+    pub fn new(target_error: f32) -> Self {
+        Self { min_samples: MIN_SAMPLES, max_samples: MAX_SAMPLES, target_error }
+    }
+}
+
+/// Why a `SceneConfigBuilder` could not build
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// A dimension was zero, given or derived from the aspect ratio
+    ZeroSize { width: u32, height: u32 },
+    /// The aspect ratio wasn't a positive number
+    AspectRatio(f32),
+    ZeroSamples,
+    ZeroTileSize,
+    /// Adaptive sampling needs 1 <= min_samples <= max_samples
+    AdaptiveSamples { min_samples: u32, max_samples: u32 },
+    /// The adaptive target error wasn't a positive number
+    TargetError(f32),
+    /// The exposure wasn't a finite number of stops
+    Exposure(f32),
+    /// The extended Reinhard white point wasn't a positive number
+    WhitePoint(f32),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::ZeroSize { width, height } => write!(f, "image size {}x{} has no pixels", width, height),
+            ConfigError::AspectRatio(aspect_ratio) => write!(f, "aspect ratio {} must be positive", aspect_ratio),
+            ConfigError::ZeroSamples => write!(f, "samples per pixel must be at least 1"),
+            ConfigError::ZeroTileSize => write!(f, "tile size must be at least 1"),
+            ConfigError::AdaptiveSamples { min_samples, max_samples } =>
+                write!(f, "adaptive sampling needs between 1 and max samples ({}) as min samples, got {}", max_samples, min_samples),
+            ConfigError::TargetError(error) => write!(f, "target error {} must be positive", error),
+            ConfigError::Exposure(exposure) => write!(f, "exposure {} must be a finite number of stops", exposure),
+            ConfigError::WhitePoint(white_point) => write!(f, "white point {} must be positive", white_point),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Which ray_color function renders the image
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Integrator {
+    /// Path tracing through the scene materials. Only light that paths hit counts, so point,
+    /// spot and directional lights are left out.
+    Path,
+    /// Surface normals mapped to colors
+    Normals,
+    /// Grey diffuse bounces, ignoring materials
+    Diffuse,
+    /// Diffuse bounces tinted by normal direction
+    Davenbusters,
+    /// Path tracing that also samples a light at every diffuse bounce, combining the two
+    /// with multiple importance sampling
+    Nee,
+}
+
+impl Integrator {
+    pub const NAMES: [&'static str; 5] = ["path", "normals", "diffuse", "davenbusters", "nee"];
+
+    /// The color seen along `r`, and its coverage: 1 if `r` hit the scene, 0 if it only saw
+    /// the environment. The debug integrators always see the sky, they're no use in the dark.
+    pub fn ray_color(&self, r: &Ray, world: &impl Hittable, lights: &LightList, environment: &Environment, depth: u32, sampler: &mut dyn Sampler) -> (Color, f32) {
+        match self {
+            Integrator::Path => ray_color(r, world, environment, depth, sampler),
+            Integrator::Nee => ray_color_nee(r, world, lights, environment, depth, sampler),
+            Integrator::Normals => ray_color_normals(r, world),
+            Integrator::Diffuse => ray_color_bounce(r, world, depth, sampler),
+            Integrator::Davenbusters => ray_color_bounce_davenbusters(r, world, depth, sampler),
+        }
+    }
+}
+
+impl std::str::FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Integrator::Path),
+            "normals" => Ok(Integrator::Normals),
+            "diffuse" => Ok(Integrator::Diffuse),
+            "davenbusters" => Ok(Integrator::Davenbusters),
+            "nee" => Ok(Integrator::Nee),
+            _ => Err(format!("unknown integrator `{}`, expected one of: {}", s, Integrator::NAMES.join(", "))),
+        }
+    }
 }
 
 impl SceneConfig {
     pub fn new() -> Self {
         Self {
-            aspect_ratio: ASPECT_RATIO,
             image_width: IMAGE_WIDTH,
             image_height: IMAGE_HEIGHT,
             samples_per_pixel: SAMPLES_PER_PIXEL,
             max_depth: MAX_DEPTH,
-            dyn_range: DYN_RANGE,
+            output: OutputSettings::default(),
+            threads: 0,
+            tile_size: TILE_SIZE,
+            seed: None,
+            integrator: Integrator::Path,
+            light_selection: LightSelection::default(),
+            sampler: SamplerKind::Independent,
+            adaptive: None,
+            environment: Environment::Gradient,
+            alpha: false,
+        }
+    }
+
+    /// Start from the defaults, a 400x225 image at 100 samples per pixel
+    pub fn builder() -> SceneConfigBuilder {
+        SceneConfig::new().into_builder()
+    }
+
+    /// Start from this config, to change a few settings. The size is kept unless overridden.
+    pub fn into_builder(self) -> SceneConfigBuilder {
+        SceneConfigBuilder {
+            config: self,
+            width: None,
+            height: None,
+            aspect_ratio: None,
+        }
+    }
+
+    pub fn image_width(&self) -> u32 {
+        self.image_width
+    }
+
+    pub fn image_height(&self) -> u32 {
+        self.image_height
+    }
+
+    /// Width over height, what the camera's viewport needs to match
+    pub fn aspect_ratio(&self) -> f32 {
+        self.image_width as f32 / self.image_height as f32
+    }
+
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
+
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
+
+    pub fn dyn_range(&self) -> u32 {
+        self.output.dyn_range
+    }
+
+    /// How the image is exposed, tone mapped and encoded for PNG and PPM files
+    pub fn output(&self) -> OutputSettings {
+        self.output
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn integrator(&self) -> Integrator {
+        self.integrator
+    }
+
+    /// How the nee integrator picks a light to sample
+    pub fn light_selection(&self) -> LightSelection {
+        self.light_selection
+    }
+
+    pub fn sampler(&self) -> SamplerKind {
+        self.sampler
+    }
+
+    /// Some when the sample count varies per pixel, in which case it replaces `samples_per_pixel`
+    pub fn adaptive(&self) -> Option<AdaptiveSampling> {
+        self.adaptive
+    }
+
+    /// The most samples any pixel can take
+    pub fn max_samples(&self) -> u32 {
+        self.adaptive.map_or(self.samples_per_pixel, |a| a.max_samples)
+    }
+
+    /// What rays that miss see, and light from far away
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// Whether images get an alpha channel of primary ray coverage, showing no background
+    pub fn alpha(&self) -> bool {
+        self.alpha
+    }
+
+    fn worker_count(&self) -> usize {
+        if self.threads > 0 {
+            self.threads
+        }
+        else {
+            thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
         }
     }
 
-    pub fn set_width(&mut self, width: u32) {
-        self.image_width = width;
-        self.image_height = (width as f32 / self.aspect_ratio) as u32;
+    // Split the image into tiles, in scanline order starting at the top row
+    fn tiles(&self) -> Vec<Tile> {
+        let size = self.tile_size;
+        let mut tiles = Vec::new();
+        for row in (0..self.image_height).step_by(size as usize) {
+            for col in (0..self.image_width).step_by(size as usize) {
+                tiles.push(Tile {
+                    col,
+                    row,
+                    width: size.min(self.image_width - col),
+                    height: size.min(self.image_height - row),
+                });
+            }
+        }
+        tiles
     }
 }
 
-pub fn render_image_png(scene: &SceneConfig, world: &HittableList, cam: &Camera, filename: &str) {
-    // Render Image
-    
-    let path = Path::new(filename);
-    let file = File::create(path).unwrap();
-    let ref mut w = BufWriter::new(file);
-    let mut encoder = png::Encoder::new(w, scene.image_width, scene.image_height);
-    encoder.set_color(png::ColorType::RGB);
-    encoder.set_depth(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().unwrap();
-    let mut data: Vec<u8> = Vec::new();
+/// Settings for a `SceneConfig`, checked when it's built.
+///
+/// Give the width, the height, or both. With only one, the other follows the aspect ratio
+/// (16:9 unless set). With both, the aspect ratio is ignored.
+pub struct SceneConfigBuilder {
+    config: SceneConfig,
+    width: Option<u32>,
+    height: Option<u32>,
+    aspect_ratio: Option<f32>,
+}
+
+impl SceneConfigBuilder {
+    pub fn width(mut self, width: u32) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn height(mut self, height: u32) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    pub fn aspect_ratio(mut self, aspect_ratio: f32) -> Self {
+        self.aspect_ratio = Some(aspect_ratio);
+        self
+    }
+
+    pub fn samples_per_pixel(mut self, samples_per_pixel: u32) -> Self {
+        self.config.samples_per_pixel = samples_per_pixel;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: u32) -> Self {
+        self.config.max_depth = max_depth;
+        self
+    }
+
+    pub fn dyn_range(mut self, dyn_range: u32) -> Self {
+        self.config.output.dyn_range = dyn_range;
+        self
+    }
+
+    /// Brighten (or darken, if negative) PNG and PPM output by this many stops
+    pub fn exposure(mut self, exposure: f32) -> Self {
+        self.config.output.exposure = exposure;
+        self
+    }
+
+    pub fn tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.config.output.tone_map = tone_map;
+        self
+    }
 
-    for j in (0..scene.image_height).rev() {
-        //std::io::stderr().write_fmt("\nScanlines remaining: {} ", j);
-        //eprintln!("\nScanlines remaining: {} ", j);
-        for i in 0..scene.image_width {
-            /*let u = i as f32 / (IMAGE_WIDTH as f32 - 1.0);
-            let v = j as f32 / (IMAGE_HEIGHT as f32 - 1.0);
-            let r = Ray {origin: origin, direction: (lower_left_corner + u*horizontal + v*vertical - origin)};
+    /// How PNG and PPM values are encoded, sRGB unless set
+    pub fn transfer(mut self, transfer: Transfer) -> Self {
+        self.config.output.transfer = transfer;
+        self
+    }
+
+    pub fn bit_depth(mut self, bit_depth: BitDepth) -> Self {
+        self.config.output.bit_depth = bit_depth;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn tile_size(mut self, tile_size: u32) -> Self {
+        self.config.tile_size = tile_size;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.config.integrator = integrator;
+        self
+    }
+
+    pub fn light_selection(mut self, light_selection: LightSelection) -> Self {
+        self.config.light_selection = light_selection;
+        self
+    }
+
+    pub fn sampler(mut self, sampler: SamplerKind) -> Self {
+        self.config.sampler = sampler;
+        self
+    }
 
-            let pixel_color = ray_color_normals(&r, &world);*/
-            let mut pixel_color = Color::new(0.0,0.0,0.0);
-            for _s in 0..scene.samples_per_pixel {
-                let u = (i as f32 + rand())/(scene.image_width as f32 - 1.0);
-                let v = (j as f32 + rand())/(scene.image_height as f32 - 1.0);
-                let r = cam.get_ray(u, v);
-                pixel_color += ray_color(&r, world, scene.max_depth);
+    pub fn adaptive(mut self, adaptive: AdaptiveSampling) -> Self {
+        self.config.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Back to the same number of samples for every pixel
+    pub fn fixed_samples(mut self) -> Self {
+        self.config.adaptive = None;
+        self
+    }
+
+    pub fn environment(mut self, environment: Environment) -> Self {
+        self.config.environment = environment;
+        self
+    }
+
+    pub fn alpha(mut self, alpha: bool) -> Self {
+        self.config.alpha = alpha;
+        self
+    }
+
+    pub fn build(self) -> Result<SceneConfig, ConfigError> {
+        let mut config = self.config;
+        let aspect_ratio = self.aspect_ratio.unwrap_or_else(|| config.aspect_ratio());
+        if !(aspect_ratio > 0.0 && aspect_ratio.is_finite()) {
+            return Err(ConfigError::AspectRatio(aspect_ratio));
+        }
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, (width as f32 / aspect_ratio).round() as u32),
+            (None, Some(height)) => ((height as f32 * aspect_ratio).round() as u32, height),
+            // An aspect ratio alone reshapes the image around its current width
+            (None, None) if self.aspect_ratio.is_some() => (config.image_width, (config.image_width as f32 / aspect_ratio).round() as u32),
+            (None, None) => (config.image_width, config.image_height),
+        };
+        if width == 0 || height == 0 {
+            return Err(ConfigError::ZeroSize { width, height });
+        }
+        if config.samples_per_pixel == 0 {
+            return Err(ConfigError::ZeroSamples);
+        }
+        if config.tile_size == 0 {
+            return Err(ConfigError::ZeroTileSize);
+        }
+        if let Some(AdaptiveSampling { min_samples, max_samples, target_error }) = config.adaptive {
+            if min_samples == 0 || min_samples > max_samples {
+                return Err(ConfigError::AdaptiveSamples { min_samples, max_samples });
             }
-            pixel_color /= scene.samples_per_pixel as f32;
-            pixel_color.gamma_correct();
-            let png_color = pixel_color.get_png_color();
-            for val in png_color {
-                data.push(val);
+            if !(target_error > 0.0 && target_error.is_finite()) {
+                return Err(ConfigError::TargetError(target_error));
             }
         }
+        if !config.output.exposure.is_finite() {
+            return Err(ConfigError::Exposure(config.output.exposure));
+        }
+        if let ToneMap::ExtendedReinhard { white_point } = config.output.tone_map {
+            if !(white_point > 0.0 && white_point.is_finite()) {
+                return Err(ConfigError::WhitePoint(white_point));
+            }
+        }
+        config.image_width = width;
+        config.image_height = height;
+        Ok(config)
+    }
+}
+
+impl Default for SceneConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A rectangle of pixels, with rows counted from the top of the image
+struct Tile {
+    col: u32,
+    row: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Render every pixel, handing tiles out to a pool of `scene.threads` workers
+pub fn render_pixels(scene: &SceneConfig, world: &impl Hittable, cam: &Camera) -> Framebuffer {
+    render_pixels_and_counts(scene, world, cam).0
+}
+
+/// `render_pixels`, also returning how many samples each pixel took, top row first
+pub fn render_pixels_and_counts(scene: &SceneConfig, world: &impl Hittable, cam: &Camera) -> (Framebuffer, Vec<u32>) {
+    // Unseeded renders still get per-pixel streams, just from a seed that differs every run
+    let seed = scene.seed.unwrap_or_else(rand::random);
+    let lights = LightList::new(world, &scene.environment, scene.light_selection);
+    let results = render_tiles(scene, |tile| render_tile(scene, world, &lights, cam, tile, seed));
+    let pixels = results.iter().map(|&(color, _, _)| color).collect();
+    let image = Framebuffer::from_pixels(scene.image_width, scene.image_height, pixels);
+    let image = match scene.alpha {
+        true => image.with_alpha(results.iter().map(|&(_, alpha, _)| alpha).collect()),
+        false => image,
+    };
+    (image, results.iter().map(|&(_, _, count)| count).collect())
+}
+
+// Run `work` on every tile with a pool of `scene.threads` workers, and stitch the per-pixel
+// results together, top row first
+fn render_tiles<T, F>(scene: &SceneConfig, work: F) -> Vec<T>
+where T: Copy + Default + Send, F: Fn(&Tile) -> Vec<T> + Sync {
+    let tiles = scene.tiles();
+    let next_tile = AtomicUsize::new(0);
+    let finished = Mutex::new(Vec::with_capacity(tiles.len()));
+
+    thread::scope(|s| {
+        for _ in 0..scene.worker_count() {
+            s.spawn(|| loop {
+                // Workers pull the next tile until none are left, so a slow tile doesn't stall the others
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                let tile = match tiles.get(index) {
+                    None => break,
+                    Some(tile) => tile,
+                };
+                let results = work(tile);
+                finished.lock().unwrap().push((index, results));
+            });
+        }
+    });
+
+    let mut pixels = vec![T::default(); (scene.image_width * scene.image_height) as usize];
+    for (index, results) in finished.into_inner().unwrap() {
+        let tile = &tiles[index];
+        for (y, line) in results.chunks(tile.width as usize).enumerate() {
+            let start = ((tile.row + y as u32) * scene.image_width + tile.col) as usize;
+            pixels[start..start + line.len()].copy_from_slice(line);
+        }
+    }
+    pixels
+}
+
+// One path through pixel (i, j), from sample `index` of the pixel's sequence, and whether the
+// camera ray hit anything. Without alpha every sample counts as a hit.
+fn sample_pixel(scene: &SceneConfig, world: &impl Hittable, lights: &LightList, cam: &Camera, sampler: &mut dyn Sampler, (i, j): (u32, u32), index: u32) -> (Color, f32) {
+    sampler.start_sample(i, j, index);
+    let (du, dv) = sampler.get_2d();
+    let u = (i as f32 + du)/(scene.image_width.max(2) - 1) as f32;
+    let v = (j as f32 + dv)/(scene.image_height.max(2) - 1) as f32;
+    let r = cam.get_ray(u, v, sampler);
+    let (color, coverage) = scene.integrator.ray_color(&r, world, lights, &scene.environment, scene.max_depth, sampler);
+    if scene.alpha && coverage == 0.0 {
+        return (Color::new(0.0,0.0,0.0), 0.0);
+    }
+    (color, 1.0)
+}
+
+// Each pixel's mean color and coverage, and the samples it took
+fn render_tile(scene: &SceneConfig, world: &impl Hittable, lights: &LightList, cam: &Camera, tile: &Tile, seed: u64) -> Vec<(Color, f32, u32)> {
+    let (width, height) = (tile.width as usize, tile.height as usize);
+    let mut sums = vec![Color::new(0.0,0.0,0.0); width * height];
+    let mut coverage = vec![0.0; width * height];
+    let mut stats: Vec<PixelStats> = (0..width * height).map(|_| PixelStats::default()).collect();
+    let (min_samples, max_samples) = match scene.adaptive {
+        None => (scene.samples_per_pixel, scene.samples_per_pixel),
+        Some(adaptive) => (adaptive.min_samples, adaptive.max_samples),
+    };
+    // Stratified and low discrepancy patterns are laid out for a run of min_samples at a time
+    let mut sampler = scene.sampler.sampler(seed, min_samples);
+
+    // Every pixel takes the minimum, then more runs of as many go to the pixels still noisy
+    let mut active = vec![true; width * height];
+    while active.contains(&true) {
+        for (k, _) in active.iter().enumerate().filter(|(_, &a)| a) {
+            let (x, y) = (k % width, k / width);
+            let i = tile.col + x as u32;
+            let j = scene.image_height - 1 - (tile.row + y as u32);
+            let start = stats[k].count;
+            for s in start..(start + min_samples).min(max_samples) {
+                let (sample, hit) = sample_pixel(scene, world, lights, cam, sampler.as_mut(), (i, j), s);
+                sums[k] += sample;
+                coverage[k] += hit;
+                stats[k].add(sample.luminance());
+            }
+        }
+
+        let target_error = match scene.adaptive {
+            None => break,
+            Some(adaptive) => adaptive.target_error,
+        };
+        // A pixel only stops once its neighbours have converged too. On its own, a pixel whose
+        // first few samples all happened to miss the light would look noise free.
+        let converged: Vec<bool> = stats.iter().map(|st| st.relative_error() < target_error).collect();
+        for (k, a) in active.iter_mut().enumerate() {
+            let (x, y) = (k % width, k / width);
+            let neighbourhood_converged = (y.saturating_sub(1)..(y + 2).min(height))
+                .all(|ny| (x.saturating_sub(1)..(x + 2).min(width)).all(|nx| converged[ny * width + nx]));
+            *a = stats[k].count < max_samples && !neighbourhood_converged;
+        }
+    }
+
+    sums.iter().zip(&coverage).zip(&stats).map(|((&sum, &hits), st)| (sum / st.count as f32, hits / st.count as f32, st.count)).collect()
+}
+
+// Running mean and variance of a pixel's sample brightness (Welford's method)
+#[derive(Default)]
+struct PixelStats {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl PixelStats {
+    fn add(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value as f64 - self.mean);
     }
 
-    writer.write_image_data(&data).unwrap();
+    // Standard error of the mean over the mean, infinite until there are two samples
+    fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let variance = self.m2 / (self.count - 1) as f64;
+        ((variance / self.count as f64).sqrt() / self.mean.max(ERROR_FLOOR as f64)) as f32
+    }
 }
 
-pub fn render_image_ppmstdout(scene: &SceneConfig, world: &HittableList, cam: &Camera) {
-    // Render Image
-    
-    println!("P3");
-    println!("{} {}", scene.image_width, scene.image_height);
-    println!("{}", (scene.dyn_range-1) as u32);
+/// When a progressive render stops, and how often it writes out the image so far.
+/// With no sample count or time limit it runs until stopped.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Progressive {
+    /// Stop once every pixel has this many samples
+    pub max_samples: Option<u32>,
+    /// Stop after the pass that runs past this much time
+    pub time_limit: Option<Duration>,
+    /// Write the image every this many passes
+    pub write_every_passes: Option<u32>,
+    /// Write the image once this much time has passed since the last write
+    pub write_every: Option<Duration>,
+}
 
-    for j in (0..scene.image_height).rev() {
-        //std::io::stderr().write_fmt("\nScanlines remaining: {} ", j);
-        eprintln!("\nScanlines remaining: {} ", j);
-        for i in 0..scene.image_width {
-            /*let u = i as f32 / (IMAGE_WIDTH as f32 - 1.0);
-            let v = j as f32 / (IMAGE_HEIGHT as f32 - 1.0);
-            let r = Ray {origin: origin, direction: (lower_left_corner + u*horizontal + v*vertical - origin)};
+/// Sum of every sample taken so far in a progressive render, one sample per pixel per pass,
+/// and the seed the samples were drawn with
+pub struct Accumulator {
+    width: u32,
+    height: u32,
+    seed: u64,
+    sums: Vec<Color>,
+    coverage: Option<Vec<f32>>, // Some for renders with alpha
+    counts: Vec<u32>,
+}
+
+impl Accumulator {
+    /// An empty buffer, seeded from the scene or at random
+    pub fn new(scene: &SceneConfig) -> Self {
+        let (width, height) = (scene.image_width, scene.image_height);
+        let pixels = (width * height) as usize;
+        let seed = scene.seed.unwrap_or_else(rand::random);
+        let coverage = scene.alpha.then(|| vec![0.0; pixels]);
+        Self { width, height, seed, sums: vec![Color::new(0.0,0.0,0.0); pixels], coverage, counts: vec![0; pixels] }
+    }
+
+    /// A buffer from saved sums, coverage if the render has alpha, and counts. None unless
+    /// there is one of each per pixel.
+    pub fn from_parts(width: u32, height: u32, seed: u64, sums: Vec<Color>, coverage: Option<Vec<f32>>, counts: Vec<u32>) -> Option<Self> {
+        let pixels = (width * height) as usize;
+        let complete = sums.len() == pixels && counts.len() == pixels && coverage.as_ref().is_none_or(|c| c.len() == pixels);
+        complete.then_some(Self { width, height, seed, sums, coverage, counts })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
 
-            let pixel_color = ray_color_normals(&r, &world);*/
-            let mut pixel_color = Color::new(0.0,0.0,0.0);
-            for _s in 0..scene.samples_per_pixel {
-                let u = (i as f32 + rand())/(scene.image_width as f32 - 1.0);
-                let v = (j as f32 + rand())/(scene.image_height as f32 - 1.0);
-                let r = cam.get_ray(u, v);
-                pixel_color += ray_color(&r, world, scene.max_depth);
+    /// Per-pixel sums of the samples, top row first
+    pub fn sums(&self) -> &[Color] {
+        &self.sums
+    }
+
+    /// Per-pixel sums of primary ray hits, top row first, for renders with alpha
+    pub fn coverage(&self) -> Option<&[f32]> {
+        self.coverage.as_deref()
+    }
+
+    /// Per-pixel sample counts, top row first
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Samples taken in every pixel, the fewest any pixel has
+    pub fn samples(&self) -> u32 {
+        self.counts.iter().copied().min().unwrap_or(0)
+    }
+
+    /// The image so far, black in pixels with no samples yet
+    pub fn average(&self) -> Framebuffer {
+        let pixels = self.sums.iter().zip(&self.counts).map(|(&sum, &count)| sum / count.max(1) as f32).collect();
+        let image = Framebuffer::from_pixels(self.width, self.height, pixels);
+        match &self.coverage {
+            None => image,
+            Some(coverage) => image.with_alpha(coverage.iter().zip(&self.counts).map(|(&hits, &count)| hits / count.max(1) as f32).collect()),
+        }
+    }
+
+    fn add_pass(&mut self, pass: &[(Color, f32)]) {
+        for ((sum, count), &(sample, _)) in self.sums.iter_mut().zip(self.counts.iter_mut()).zip(pass) {
+            *sum += sample;
+            *count += 1;
+        }
+        if let Some(coverage) = &mut self.coverage {
+            for (hits, &(_, hit)) in coverage.iter_mut().zip(pass) {
+                *hits += hit;
             }
-            pixel_color /= scene.samples_per_pixel as f32;
-            pixel_color.gamma_correct();
-            println!("{}", pixel_color);
         }
     }
 }
 
-pub fn ray_color(r: &Ray, world: &impl Hittable, depth: u32) -> Color {
+/// Add passes of one sample per pixel to `accumulator` until `progressive` says to stop or `stop`
+/// is set, calling `write` with the accumulated image as it goes and once more at the end.
+/// An empty accumulator always gets at least one pass, and a pass in flight is finished before
+/// stopping. Adaptive sampling settings are ignored.
+pub fn render_progressive<W>(scene: &SceneConfig, world: &impl Hittable, cam: &Camera, progressive: &Progressive, mut accumulator: Accumulator, stop: &AtomicBool, mut write: W) -> std::io::Result<Accumulator>
+where W: FnMut(&Accumulator) -> std::io::Result<()> {
+    let seed = accumulator.seed;
+    let lights = LightList::new(world, &scene.environment, scene.light_selection);
+    let started = Instant::now();
+    let mut last_write = started;
+    let finished = |accumulator: &Accumulator| stop.load(Ordering::Relaxed)
+        || progressive.max_samples.is_some_and(|n| accumulator.samples() >= n)
+        || progressive.time_limit.is_some_and(|limit| started.elapsed() >= limit);
+
+    while accumulator.samples() == 0 || !finished(&accumulator) {
+        let index = accumulator.samples();
+        let pass = render_tiles(scene, |tile| {
+            let mut sampler = scene.sampler.sampler(seed, PROGRESSIVE_RUN);
+            let mut samples = Vec::with_capacity((tile.width * tile.height) as usize);
+            for y in 0..tile.height {
+                let j = scene.image_height - 1 - (tile.row + y);
+                for i in tile.col..tile.col + tile.width {
+                    samples.push(sample_pixel(scene, world, &lights, cam, sampler.as_mut(), (i, j), index));
+                }
+            }
+            samples
+        });
+        accumulator.add_pass(&pass);
+
+        let write_due = progressive.write_every_passes.is_some_and(|n| accumulator.samples().is_multiple_of(n))
+            || progressive.write_every.is_some_and(|every| last_write.elapsed() >= every);
+        if write_due && !finished(&accumulator) {
+            write(&accumulator)?;
+            last_write = Instant::now();
+        }
+    }
+
+    write(&accumulator)?;
+    Ok(accumulator)
+}
+
+pub fn render_image_png(scene: &SceneConfig, world: &impl Hittable, cam: &Camera, filename: &str) -> std::io::Result<()> {
+    ImageFormat::Png.save(&render_pixels(scene, world, cam), &scene.output, filename)
+}
+
+pub fn render_image_ppm(scene: &SceneConfig, world: &impl Hittable, cam: &Camera, filename: &str) -> std::io::Result<()> {
+    ImageFormat::Ppm.save(&render_pixels(scene, world, cam), &scene.output, filename)
+}
+
+/// Write per-pixel sample counts as a grayscale PNG, white for pixels that took `max_samples`
+pub fn write_sample_counts_png(scene: &SceneConfig, counts: &[u32], filename: &str) -> std::io::Result<()> {
+    let file = File::create(Path::new(filename))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), scene.image_width, scene.image_height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let max_samples = scene.max_samples() as f32;
+    let data: Vec<u8> = counts.iter().map(|&n| (255.0 * n as f32 / max_samples).round().min(255.0) as u8).collect();
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
+
+pub fn render_image_ppmstdout(scene: &SceneConfig, world: &impl Hittable, cam: &Camera) -> std::io::Result<()> {
+    let stdout = std::io::stdout();
+    let mut w = BufWriter::new(stdout.lock());
+    write_ppm(&render_pixels(scene, world, cam), &scene.output, &mut w)?;
+    w.flush()
+}
+
+/// Path tracing, returning the color along `r` and 1 if it hit anything, else 0
+pub fn ray_color(r: &Ray, world: &impl Hittable, environment: &Environment, depth: u32, sampler: &mut dyn Sampler) -> (Color, f32) {
     if depth == 0 {
-        return Color::new(0.0,0.0,0.0);
+        return (Color::new(0.0,0.0,0.0), 0.0);
     }
 
     match world.hit(r, 0.001, INFINITY) {
-        None => ray_color_bg(r),
+        None => (environment.color(r), 0.0),
         Some(hit_record) => {
-            let (attenuation, scattered) = hit_record.material.scatter(r, &hit_record);
-            attenuation * ray_color(&scattered, world, depth-1)
+            let emitted = hit_record.material.emitted(r, &hit_record);
+            match hit_record.material.scatter(r, &hit_record, sampler) {
+                None => (emitted, 1.0),
+                Some((attenuation, scattered)) => (emitted + attenuation * ray_color(&scattered, world, environment, depth-1, sampler).0, 1.0),
+            }
         },
     }
 }
 
-pub fn ray_color_bounce_davenbusters(r: &Ray, world: &impl Hittable, depth: u32) -> Color {
+/// Path tracing with next event estimation: at each diffuse bounce a shadow ray goes to a
+/// randomly picked light as well as the bounced ray, and whichever found the light is weighted
+/// by the power heuristic. Light reached through mirrors and glass, or from lights that can't
+/// be sampled, is only found by bouncing and counts in full. Point, spot and directional
+/// lights are only found by sampling them. Also returns 1 if `r` hit anything, else 0.
+pub fn ray_color_nee(r: &Ray, world: &impl Hittable, lights: &LightList, environment: &Environment, depth: u32, sampler: &mut dyn Sampler) -> (Color, f32) {
+    let mut color = Color::new(0.0,0.0,0.0);
+    let mut coverage = 0.0;
+    let mut throughput = Color::new(1.0,1.0,1.0);
+    let mut ray = Ray { origin: r.origin, direction: r.direction };
+    // Where the ray came from, and the density it was scattered with, or None for camera
+    // rays and specular bounces, which light sampling couldn't have found
+    let mut scattered_from: Option<(Point3, f32)> = None;
+
+    for bounce in 0..depth {
+        let hit_record = match world.hit(&ray, 0.001, INFINITY) {
+            None => {
+                // Environments that are sampled as lights are weighed against that like any other
+                let weight = match (scattered_from, environment.light()) {
+                    (Some((origin, bsdf_pdf)), Some(light)) => {
+                        power_heuristic(bsdf_pdf, lights.pick_pdf(light) * light.pdf(origin, ray.direction))
+                    }
+                    _ => 1.0,
+                };
+                color += weight * throughput * environment.color(&ray);
+                break;
+            }
+            Some(hit_record) => hit_record,
+        };
+        if bounce == 0 {
+            coverage = 1.0;
+        }
+
+        let emitted = hit_record.material.emitted(&ray, &hit_record);
+        let weight = match (scattered_from, hit_record.light) {
+            (Some((origin, bsdf_pdf)), Some(light)) => {
+                let light_pdf = lights.pick_pdf(light) * light.pdf(origin, ray.direction.unit_vector());
+                power_heuristic(bsdf_pdf, light_pdf)
+            }
+            _ => 1.0,
+        };
+        color += weight * throughput * emitted;
+
+        let material = hit_record.material;
+        if !material.is_specular() {
+            color += throughput * sample_light(&ray, &hit_record, world, lights, sampler);
+        }
+
+        match material.scatter(&ray, &hit_record, sampler) {
+            None => break,
+            Some((attenuation, scattered)) => {
+                scattered_from = if material.is_specular() {
+                    None
+                }
+                else {
+                    Some((hit_record.p, material.pdf(&ray, &hit_record, scattered.direction)))
+                };
+                throughput *= attenuation;
+                ray = scattered;
+            }
+        }
+    }
+    (color, coverage)
+}
+
+// Light arriving at a hit straight from one randomly picked light, MIS weighted against the
+// material having scattered toward it
+fn sample_light(r: &Ray, hit_record: &HitRecord, world: &impl Hittable, lights: &LightList, sampler: &mut dyn Sampler) -> Color {
+    let black = Color::new(0.0,0.0,0.0);
+    // The dimensions are drawn whether or not there's a light, so the bounces after line up
+    let u_pick = sampler.get_1d();
+    let u_light = sampler.get_2d();
+    let (light, pick_pdf) = match lights.pick(u_pick) {
+        None => return black,
+        Some(picked) => picked,
+    };
+    let sample = match light.sample(hit_record.p, u_light) {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return black,
+    };
+    let f = hit_record.material.eval(r, hit_record, sample.direction);
+    if f.luminance() <= 0.0 {
+        return black;
+    }
+    let shadow_ray = Ray { origin: hit_record.p, direction: sample.direction };
+    if world.hit(&shadow_ray, 0.001, sample.distance * SHADOW_RAY_END).is_some() {
+        return black;
+    }
+    let light_pdf = pick_pdf * sample.pdf;
+    let weight = if light.is_punctual() { 1.0 } else { power_heuristic(light_pdf, hit_record.material.pdf(r, hit_record, sample.direction)) };
+    (weight / light_pdf) * f * sample.radiance
+}
+
+pub fn ray_color_bounce_davenbusters(r: &Ray, world: &impl Hittable, depth: u32, sampler: &mut dyn Sampler) -> (Color, f32) {
     if depth == 0 {
-        return Color::new(0.0,0.0,0.0);
+        return (Color::new(0.0,0.0,0.0), 0.0);
     }
 
     match world.hit(r, 0.001, INFINITY) {
-        None => ray_color_bg(r),
+        None => (ray_color_bg(r), 0.0),
         Some(hit_record) => {
             //println!("Hit: {}", hit_record);
             let vertnormcomp = hit_record.normal.y * 3.0 + 6.0;
@@ -159,8 +914,124 @@ pub fn ray_color_bounce_davenbusters(r: &Ray, world: &impl Hittable, depth: u32)
                 Color::new(1.0, 0.0, 1.0 - vertnormcomp)
             };
 
-            let target = rand_lamb_vector(&hit_record);
-            color * ray_color_bounce_davenbusters(&Ray{origin:hit_record.p, direction:target}, world, depth-1)
+            let target = rand_lamb_vector(&hit_record, sampler);
+            (color * ray_color_bounce_davenbusters(&Ray{origin:hit_record.p, direction:target}, world, depth-1, sampler).0, 1.0)
         },
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{materials, scene};
+
+    #[test]
+    fn seeded_render_matches_across_thread_counts() {
+        let material = materials::Material::default();
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.5}));
+        let cam = Camera::default();
+
+        let scene = SceneConfig::builder().width(48).samples_per_pixel(4).tile_size(8).seed(7);
+        let scene = scene.threads(1).build().unwrap();
+        let single = render_pixels(&scene, &world, &cam);
+        let scene = scene.into_builder().threads(4).build().unwrap();
+        let multi = render_pixels(&scene, &world, &cam);
+
+        assert_eq!(single.pixels().len(), multi.pixels().len());
+        for (a, b) in single.pixels().iter().zip(multi.pixels()) {
+            assert_eq!((a.r.to_bits(), a.g.to_bits(), a.b.to_bits()), (b.r.to_bits(), b.g.to_bits(), b.b.to_bits()));
+        }
+
+        // Another seed draws different samples
+        let scene = scene.into_builder().seed(8).build().unwrap();
+        let reseeded = render_pixels(&scene, &world, &cam);
+        assert!(single.pixels().iter().zip(reseeded.pixels()).any(|(a, b)| a.r != b.r));
+    }
+
+    #[test]
+    fn scene_config_sizes_follow_the_aspect_ratio() {
+        let config = SceneConfig::builder().width(1920).height(1080).build().unwrap();
+        assert_eq!((config.image_width(), config.image_height()), (1920, 1080));
+        let square = SceneConfig::builder().width(300).aspect_ratio(1.0).build().unwrap();
+        assert_eq!((square.image_width(), square.image_height()), (300, 300));
+        let portrait = SceneConfig::builder().height(400).aspect_ratio(0.5).build().unwrap();
+        assert_eq!((portrait.image_width(), portrait.image_height()), (200, 400));
+
+        // Changing only the width keeps the shape
+        let wider = portrait.into_builder().width(100).build().unwrap();
+        assert_eq!((wider.image_width(), wider.image_height()), (100, 200));
+
+        assert_eq!(SceneConfig::builder().width(0).build().err(), Some(ConfigError::ZeroSize { width: 0, height: 0 }));
+        assert_eq!(SceneConfig::builder().samples_per_pixel(0).build().err(), Some(ConfigError::ZeroSamples));
+        assert_eq!(SceneConfig::builder().aspect_ratio(-1.0).build().err(), Some(ConfigError::AspectRatio(-1.0)));
+
+        // The camera's viewport matches the image, so the view stays centered whatever its shape
+        let camera = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 90.0\n";
+        let scene = scene::Scene::parse(&format!("{}\n[render]\nwidth = 200\nheight = 100\n", camera)).unwrap();
+        assert_eq!(scene.config().aspect_ratio(), 2.0);
+        let corner = scene.camera.get_ray(1.0, 1.0, &mut IndependentSampler::new(0)).direction;
+        assert!((corner.x - 2.0).abs() < 1e-5 && (corner.y - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_noise() {
+        let material = materials::Material::default();
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.3}));
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,-100.3,-1.0), material: &material, radius: 100.0}));
+        let cam = Camera::new(Vector3::new(0.0,0.0,0.0), Vector3::new(0.0,0.0,-1.0), Vector3::new(0.0,1.0,0.0), 90.0, 1.0, 0.0, 1.0);
+
+        let adaptive = AdaptiveSampling { min_samples: 8, max_samples: 128, target_error: 0.01 };
+        let config = SceneConfig::builder().width(32).height(32).seed(1).adaptive(adaptive)
+            .environment(Environment::Constant(Color::new(0.7, 0.8, 1.0))).build().unwrap();
+        let (pixels, counts) = render_pixels_and_counts(&config, &world, &cam);
+        assert_eq!(pixels.pixels().len(), counts.len());
+        // The flat background converges straight away, the sphere lit partly by the ground keeps sampling
+        assert_eq!(counts[0], 8);
+        assert!(counts[16 * 32 + 16] > 8 && counts.iter().all(|&n| n <= 128));
+        // Pixels stop after whole runs of the minimum, and each run covers every stratum
+        assert!(counts.iter().all(|&n| n % 8 == 0));
+        let mut sampler = SamplerKind::Stratified.sampler(1, 8);
+        for run in 0..3 {
+            let mut strata: Vec<u32> = (0..8).map(|s| {
+                sampler.start_sample(0, 0, 8 * run + s);
+                (sampler.get_1d() * 8.0) as u32
+            }).collect();
+            strata.sort_unstable();
+            assert_eq!(strata, (0..8).collect::<Vec<_>>());
+        }
+
+        let fixed = config.into_builder().fixed_samples().samples_per_pixel(4).build().unwrap();
+        assert!(render_pixels_and_counts(&fixed, &world, &cam).1.iter().all(|&n| n == 4));
+
+        let backwards = AdaptiveSampling { min_samples: 64, max_samples: 16, target_error: 0.01 };
+        assert_eq!(SceneConfig::builder().adaptive(backwards).build().err(), Some(ConfigError::AdaptiveSamples { min_samples: 64, max_samples: 16 }));
+    }
+
+    #[test]
+    fn progressive_renders_accumulate_passes() {
+        use std::sync::atomic::AtomicBool;
+        let material = materials::Material::default();
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.5}));
+        let cam = Camera::default();
+        let config = SceneConfig::builder().width(16).height(9).tile_size(8).seed(3).build().unwrap();
+
+        let mut writes = Vec::new();
+        let progressive = Progressive { max_samples: Some(6), write_every_passes: Some(2), ..Progressive::default() };
+        let mut record = |acc: &Accumulator| { writes.push(acc.samples()); Ok(()) };
+        let acc = render_progressive(&config, &world, &cam, &progressive, Accumulator::new(&config), &AtomicBool::new(false), &mut record).unwrap();
+        assert_eq!(acc.samples(), 6);
+        // Every other pass, then once at the end
+        assert_eq!(writes, vec![2, 4, 6]);
+        let image = acc.average();
+        assert_eq!((image.width(), image.height()), (16, 9));
+        assert!(image.pixels().iter().all(|c| c.r.is_finite() && c.r > 0.0));
+
+        // Already stopped, as after Ctrl-C: one pass still makes a whole image
+        let stopped = render_progressive(&config, &world, &cam, &Progressive::default(), Accumulator::new(&config), &AtomicBool::new(true), |_| Ok(())).unwrap();
+        assert_eq!(stopped.samples(), 1);
+        assert!(stopped.average().pixels().iter().all(|c| c.g > 0.0));
+    }
+}
@@ -0,0 +1,423 @@
+// Samplers
+use super::util::*;
+
+// Enough dimensions for the pixel, the lens and a dozen or so bounces, after which Halton
+// falls back to independent samples
+const PRIMES: [u32; 48] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+    137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223,
+];
+
+// Largest f32 below 1, so a sample never lands on the far edge of its domain
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Source of the random numbers for one path. Every sample of a pixel starts with
+/// `start_sample`, then the renderer, camera and materials draw dimensions in turn:
+/// first the position in the pixel, then the lens, then whatever each bounce needs.
+pub trait Sampler {
+    /// Begin sample `index` of pixel (i, j). The values drawn depend only on these and the seed,
+    /// not on what was drawn before.
+    fn start_sample(&mut self, i: u32, j: u32, index: u32);
+
+    /// The next dimension, in [0, 1)
+    fn get_1d(&mut self) -> f32;
+
+    /// The next two dimensions, in [0, 1) each, well spread over the square as a pair
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+/// Which `Sampler` the renderer uses
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum SamplerKind {
+    /// Every dimension drawn at random
+    #[default]
+    Independent,
+    /// A jittered grid per dimension, shuffled between dimensions
+    Stratified,
+    /// Radical inverses in a prime base per dimension, digits scrambled per pixel
+    Halton,
+    /// The first two Sobol dimensions, scrambled and reshuffled for each pair of dimensions
+    Sobol,
+    /// Kensler's correlated multi-jittered patterns, stratified in 2D and in each 1D projection
+    Cmj,
+}
+
+impl SamplerKind {
+    pub const NAMES: [&'static str; 5] = ["independent", "stratified", "halton", "sobol", "cmj"];
+
+    /// A sampler drawing `samples_per_pixel` samples per pixel, or more if asked for
+    pub fn sampler(&self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        let state = SampleState::new(seed);
+        let samples_per_pixel = samples_per_pixel.max(1);
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler { state }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler { state, samples_per_pixel }),
+            SamplerKind::Halton => Box::new(HaltonSampler { state }),
+            SamplerKind::Sobol => Box::new(SobolSampler { state, samples_per_pixel }),
+            SamplerKind::Cmj => Box::new(CmjSampler { state, samples_per_pixel }),
+        }
+    }
+}
+
+impl std::str::FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "cmj" => Ok(SamplerKind::Cmj),
+            _ => Err(format!("unknown sampler `{}`, expected one of: {}", s, SamplerKind::NAMES.join(", "))),
+        }
+    }
+}
+
+// Where a sampler is: which pixel, which sample, which dimension, plus a generator for jitter
+// and for dimensions a sequence has run out of
+struct SampleState {
+    seed: u64,
+    pixel_seed: u64,
+    index: u32,
+    dimension: u32,
+    rng: Rng,
+}
+
+impl SampleState {
+    fn new(seed: u64) -> Self {
+        Self { seed, pixel_seed: seed, index: 0, dimension: 0, rng: Rng::new(seed) }
+    }
+
+    fn start(&mut self, i: u32, j: u32, index: u32) {
+        self.pixel_seed = mix_seed(self.seed, ((j as u64) << 32) | i as u64);
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Rng::new(mix_seed(self.pixel_seed, index as u64));
+    }
+
+    // Claim the next `count` dimensions, returning the first
+    fn take(&mut self, count: u32) -> u32 {
+        let dimension = self.dimension;
+        self.dimension += count;
+        dimension
+    }
+
+    // The same for every sample of this pixel, different for each dimension
+    fn scramble(&self, dimension: u32) -> u32 {
+        mix_seed(self.pixel_seed, 0x5851_F42D_0000_0000 | dimension as u64) as u32
+    }
+}
+
+/// Every dimension of every sample drawn at random
+pub struct IndependentSampler {
+    state: SampleState,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { state: SampleState::new(seed) }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, i: u32, j: u32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.state.rng.rand()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.state.rng.rand(), self.state.rng.rand())
+    }
+}
+
+/// Each dimension split into one stratum per sample, 1D or as near square a 2D grid as fits,
+/// with a jittered point in each. The strata are visited in a different order per dimension
+/// so the dimensions don't line up.
+pub struct StratifiedSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, i: u32, j: u32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.take(1);
+        let n = self.samples_per_pixel;
+        let stratum = shuffled(self.state.index, n, self.state.scramble(dimension));
+        ((stratum as f32 + self.state.rng.rand()) / n as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.take(2);
+        let (columns, rows) = grid(self.samples_per_pixel);
+        let cell = shuffled(self.state.index, columns * rows, self.state.scramble(dimension));
+        let x = ((cell % columns) as f32 + self.state.rng.rand()) / columns as f32;
+        let y = ((cell / columns) as f32 + self.state.rng.rand()) / rows as f32;
+        (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+}
+
+/// The Halton sequence, a base per dimension. Each pixel permutes the digits of every
+/// dimension its own way, which breaks up the patterns the larger bases make at low
+/// sample counts and keeps neighbouring pixels from repeating each other.
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    fn sample(&mut self, dimension: u32) -> f32 {
+        match PRIMES.get(dimension as usize) {
+            None => self.state.rng.rand(),
+            Some(&base) => scrambled_radical_inverse(self.state.index, base, self.state.scramble(dimension)).min(ONE_MINUS_EPSILON),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, i: u32, j: u32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.take(1);
+        self.sample(dimension)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.take(2);
+        (self.sample(dimension), self.sample(dimension + 1))
+    }
+}
+
+/// A (0, 2)-sequence: the first two Sobol dimensions, XOR scrambled per pixel. Each pair of
+/// dimensions reuses them with their own scramble and sample order, which keeps every 2D
+/// projection well stratified without a table of direction numbers.
+pub struct SobolSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+}
+
+impl SobolSampler {
+    // Shuffle the sample order within each run of samples_per_pixel samples
+    fn index(&self, dimension: u32) -> u32 {
+        let n = self.samples_per_pixel;
+        let index = self.state.index;
+        index - index % n + shuffled(index % n, n, self.state.scramble(dimension) ^ 0x2545_F491)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, i: u32, j: u32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.take(1);
+        let index = self.index(dimension);
+        unit_float(index.reverse_bits() ^ self.state.scramble(dimension))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.take(2);
+        let index = self.index(dimension);
+        (unit_float(index.reverse_bits() ^ self.state.scramble(dimension)),
+         unit_float(sobol_second_dimension(index) ^ self.state.scramble(dimension + 1)))
+    }
+}
+
+/// Correlated multi-jittered sampling (Kensler 2013): jittered in a 2D grid and also in the
+/// finer 1D strata of each axis. A new pattern for every pixel and dimension, and for every
+/// run of samples_per_pixel samples if more are drawn.
+pub struct CmjSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+}
+
+impl CmjSampler {
+    // Index within the current pattern, and the pattern's seed
+    fn pattern(&self, dimension: u32) -> (u32, u32) {
+        let n = self.samples_per_pixel;
+        let round = self.state.index / n;
+        (self.state.index % n, self.state.scramble(dimension) ^ round.wrapping_mul(0x9E37_79B9))
+    }
+}
+
+impl Sampler for CmjSampler {
+    fn start_sample(&mut self, i: u32, j: u32, index: u32) {
+        self.state.start(i, j, index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.state.take(1);
+        let (index, pattern) = self.pattern(dimension);
+        let n = self.samples_per_pixel;
+        let stratum = permute(index, n, pattern.wrapping_mul(0x68BC_21EB));
+        let jitter = hash_float(index, pattern.wrapping_mul(0x02E5_BE93));
+        ((stratum as f32 + jitter) / n as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.take(2);
+        let (index, pattern) = self.pattern(dimension);
+        let (x, y) = cmj(index, self.samples_per_pixel, pattern);
+        (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+}
+
+// As near square a grid as holds n cells
+fn grid(n: u32) -> (u32, u32) {
+    let columns = (n as f32).sqrt().ceil() as u32;
+    (columns, n.div_ceil(columns))
+}
+
+// Sample `index` of a shuffled run of n, wrapping round for indices past the end
+fn shuffled(index: u32, n: u32, pattern: u32) -> u32 {
+    permute(index % n, n, pattern ^ (index / n).wrapping_mul(0x9E37_79B9))
+}
+
+// Mirror the base `base` digits of index about the point, permuting each digit position
+// differently. The zeros after the last digit are permuted too, until they're too small to matter.
+fn scrambled_radical_inverse(mut index: u32, base: u32, pattern: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = inv_base;
+    let mut reversed = 0.0;
+    let mut position = 0u32;
+    while index > 0 || inv_base_n > 1e-9 {
+        let digit = permute(index % base, base, pattern ^ position.wrapping_mul(0x9E37_79B9));
+        reversed += digit as f64 * inv_base_n;
+        inv_base_n *= inv_base;
+        index /= base;
+        position += 1;
+    }
+    reversed as f32
+}
+
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+fn unit_float(bits: u32) -> f32 {
+    // The top 24 bits are all an f32 can hold, which keeps the result below 1
+    (bits >> 8) as f32 / 16_777_216.0
+}
+
+fn cmj(index: u32, n: u32, pattern: u32) -> (f32, f32) {
+    let (m, rows) = grid(n);
+    let cells = m * rows;
+    let s = permute(index, cells, pattern.wrapping_mul(0x5163_3E2D));
+    let sx = permute(s % m, m, pattern.wrapping_mul(0xA511_E9B3));
+    let sy = permute(s / m, rows, pattern.wrapping_mul(0x63D8_3595));
+    let jx = hash_float(s, pattern.wrapping_mul(0xA399_D265));
+    let jy = hash_float(s, pattern.wrapping_mul(0x711A_D6A5));
+    (((s % m) as f32 + (sy as f32 + jx) / rows as f32) / m as f32,
+     ((s / m) as f32 + (sx as f32 + jy) / m as f32) / rows as f32)
+}
+
+// Kensler's hashed permutation of 0..l, a different one for each pattern
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    if l <= 1 {
+        return 0;
+    }
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xE170_893D);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_EB3F);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_FA69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74DC_B303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9E50_1CC3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xC860_A3DF);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    ((i as u64 + p as u64) % l as u64) as u32
+}
+
+// Kensler's hash of i to [0, 1)
+fn hash_float(mut i: u32, p: u32) -> f32 {
+    i ^= p;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xB365_34E5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93FC_4795);
+    i ^= 0xDF6E_307F;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | p >> 18);
+    unit_float(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stratified_samplers_beat_independent_sampling() {
+        // Estimate the integral of x*y over the square per pixel, from the pixel dimensions and
+        // from a pair further along the path, and measure the error over a block of pixels
+        let rms_error = |kind: SamplerKind| {
+            let spp = 64;
+            let mut sampler = kind.sampler(11, spp);
+            let (mut first, mut later) = (0.0, 0.0);
+            for pixel in 0..64 {
+                let (mut sum_first, mut sum_later) = (0.0, 0.0);
+                for s in 0..spp {
+                    sampler.start_sample(pixel, 0, s);
+                    let (x, y) = sampler.get_2d();
+                    sampler.get_2d();
+                    sampler.get_1d();
+                    let (x2, y2) = sampler.get_2d();
+                    for v in [x, y, x2, y2] {
+                        assert!((0.0..1.0).contains(&v));
+                    }
+                    sum_first += x * y;
+                    sum_later += x2 * y2;
+                }
+                first += (sum_first / spp as f32 - 0.25).powi(2);
+                later += (sum_later / spp as f32 - 0.25).powi(2);
+            }
+            ((first / 64.0).sqrt(), (later / 64.0).sqrt())
+        };
+
+        let (independent_first, independent_later) = rms_error(SamplerKind::Independent);
+        for kind in [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::Cmj] {
+            let (first, later) = rms_error(kind);
+            assert!(first < 0.5 * independent_first && later < 0.75 * independent_later, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,1031 @@
+// Scene description files
+//
+// Scenes are written in TOML:
+//
+//     color_space = "linear"      # optional, how colors below are encoded: srgb (the default),
+//                                 #   linear, gamma2.2 or rec709. Colors taken from the built-in
+//                                 #   scenes are already linear, so scenes ported from them
+//                                 #   say linear
+//
+//     [camera]
+//     lookfrom = [-2.0, 2.0, 1.0]
+//     lookat = [0.0, 0.0, -1.0]
+//     vfov = 40.0
+//     aspect_ratio = 1.7778       # optional, 16:9 by default, ignored if both width and height are set
+//     aperture = 0.4              # optional, defaults to a pinhole
+//
+//     [render]                    # optional, SceneConfig fields
+//     width = 800                 # give either or both, the other follows the aspect ratio
+//     height = 450
+//     samples_per_pixel = 150
+//     sampler = "sobol"           # optional: independent (the default), stratified, halton, sobol, cmj
+//     target_error = 0.01         # optional, samples each pixel until its relative error is this low,
+//     min_samples = 16            #   taking between min_samples and max_samples
+//     max_samples = 1024          #   instead of samples_per_pixel
+//     background = [0.0, 0.0, 0.0] # optional, defaults to the sky gradient, same as a constant
+//                                 #   environment
+//     tone_map = "aces"           # optional: clamp (the default), reinhard, reinhard-extended, hable, aces
+//     white_point = 4.0           # optional, where reinhard-extended reaches white, which it
+//                                 #   implies when tone_map is left out
+//     exposure = 1.0              # optional, stops to brighten (or darken) PNG and PPM output by
+//     transfer = "srgb"           # optional, PNG and PPM encoding: srgb (the default), linear,
+//                                 #   gamma2.2, rec709
+//     bit_depth = 16              # optional, bits per PNG channel: 8 (the default) or 16
+//     alpha = true                # optional, makes the background transparent, keeping coverage
+//                                 #   as alpha in PNGs
+//     integrator = "nee"          # optional, path (the default), nee, normals, diffuse or
+//                                 #   davenbusters, nee when there are [[lights]]
+//     light_selection = "uniform" # optional, how nee picks a light: power (the default) or uniform
+//
+//     [environment]               # optional, what rays that miss see, instead of a background
+//     type = "map"                #   also gradient (the default) and constant, with a color
+//     file = "sky.hdr"            # an equirectangular .hdr, .pfm or .exr, relative to the scene
+//     rotation = 90.0             # optional, degrees to turn the map about the up axis
+//     intensity = 2.0             # optional, scales the map's radiance
+//
+//     [environment]               # or a daylight sky with a sun disk, lit like any other light
+//     type = "sky"
+//     sun_elevation = 30.0        # degrees above the horizon, 0 to 90
+//     sun_azimuth = 90.0          # optional, degrees clockwise from -z seen from above, so 90
+//                                 #   is toward +x
+//     turbidity = 3.0             # optional, haze from 1.7 (very clear) to 10 (murky), 3 by default
+//     intensity = 1.0             # optional, scales the sky and sun
+//
+//     [textures.tiles]            # optional, for materials to use instead of a flat color
+//     type = "checker"              # also solid, uv_checker, noise, turbulence and marble
+//     even = [0.2, 0.3, 0.1]
+//     odd = [0.9, 0.9, 0.9]
+//     scale = 10.0
+//
+//     [textures.earth]
+//     type = "image"                # a PNG, path relative to the scene file
+//     file = "earth.png"
+//     filter = "bilinear"           # or nearest
+//     wrap = "repeat"               # or clamp, mirror
+//
+//     [materials.ground]
+//     type = "diffuse"
+//     albedo = [0.8, 0.8, 0.0]      # or texture = "tiles"
+//
+//     [materials.lamp]
+//     type = "light"                # spheres, triangles, rects and meshes made of it are area lights
+//     color = [1.0, 0.9, 0.8]       # optional, white by default, or texture = "tiles"
+//     strength = 10.0               # optional, 1 by default
+//     two_sided = false             # optional, true by default, false shines from the front only
+//     sampling = "area"             # optional, solid_angle (the default) or area
+//
+//     [[primitives]]
+//     type = "sphere"
+//     center = [0.0, -100.5, -1.0]
+//     radius = 100.0
+//     material = "ground"
+//
+//     [[primitives]]
+//     type = "triangle"             # counter-clockwise seen from the front
+//     vertices = [[0.0, 0.0, -2.0], [1.0, 0.0, -2.0], [0.0, 1.0, -2.0]]
+//     material = "ground"
+//
+//     [[primitives]]
+//     type = "rect"                 # a parallelogram, facing along the first edge cross the second
+//     corner = [-0.5, 2.0, -1.5]
+//     edges = [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0]]
+//     material = "lamp"
+//
+//     [[primitives]]
+//     type = "mesh"                 # path relative to the scene file
+//     file = "teapot.obj"
+//     material = "ground"           # optional, overrides the OBJ's own materials
+//
+//     [[lights]]                    # optional, lit only by the nee integrator, so they make it
+//                                   #   the default
+//     type = "point"
+//     position = [0.0, 4.0, 0.0]
+//     color = [1.0, 0.9, 0.8]       # optional, white by default
+//     intensity = 40.0              # watts per steradian
+//
+//     [[lights]]
+//     type = "spot"                 # a point light in a cone
+//     position = [0.0, 4.0, 0.0]
+//     direction = [0.0, -1.0, 0.0]  # the cone's axis
+//     intensity = 40.0
+//     inner_angle = 15.0            # degrees from the axis, full intensity inside
+//     outer_angle = 25.0            #   and fading out by here
+//
+//     [[lights]]
+//     type = "directional"          # sunlight
+//     direction = [-1.0, -2.0, -1.0] # the way the light travels
+//     intensity = 3.0               # watts per square metre, facing the light
+//     angular_diameter = 0.53       # optional, degrees of sky the sun covers, 0 for hard shadows
+use super::vectors::*;
+use super::colors::*;
+use super::cameras::*;
+use super::materials::*;
+use super::primitives::*;
+use super::bvh::*;
+use super::render::*;
+use super::obj::*;
+use super::textures::*;
+use super::tonemap::*;
+use super::lights::*;
+use super::rays::*;
+use super::environment::*;
+use super::sky::*;
+use Vector3 as Point3;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use toml::Spanned;
+
+/// Why a scene file could not be loaded
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    /// The text is not valid TOML, or a field has the wrong type or is missing
+    Parse { line: usize, field: Option<String>, message: String },
+    /// The file is well formed but describes something we can't build
+    Invalid { line: usize, field: String, message: String },
+}
+
+impl std::fmt::Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SceneError::Io(err) => write!(f, "could not read scene: {}", err),
+            SceneError::Parse { line, field: Some(field), message } => write!(f, "line {}, field `{}`: {}", line, field, message),
+            SceneError::Parse { line, field: None, message } => write!(f, "line {}: {}", line, message),
+            SceneError::Invalid { line, field, message } => write!(f, "line {}, field `{}`: {}", line, field, message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<std::io::Error> for SceneError {
+    fn from(err: std::io::Error) -> Self {
+        SceneError::Io(err)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    color_space: Option<Spanned<String>>,
+    camera: CameraDesc,
+    #[serde(default)]
+    render: RenderDesc,
+    #[serde(default)]
+    textures: BTreeMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    materials: BTreeMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    primitives: Vec<Spanned<PrimitiveDesc>>,
+    #[serde(default)]
+    lights: Vec<Spanned<LightDesc>>,
+    environment: Option<Spanned<EnvironmentDesc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    lookfrom: [f32; 3],
+    lookat: [f32; 3],
+    #[serde(default = "default_vup")]
+    vup: [f32; 3],
+    vfov: f32,
+    aspect_ratio: Option<Spanned<f32>>,
+    #[serde(default)]
+    aperture: f32,
+    focus_dist: Option<f32>, // defaults to the distance from lookfrom to lookat
+}
+
+fn default_vup() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderDesc {
+    width: Option<Spanned<u32>>,
+    height: Option<Spanned<u32>>,
+    samples_per_pixel: Option<Spanned<u32>>,
+    max_depth: Option<u32>,
+    threads: Option<usize>,
+    tile_size: Option<Spanned<u32>>,
+    seed: Option<u64>,
+    sampler: Option<Spanned<String>>,
+    target_error: Option<Spanned<f32>>,
+    min_samples: Option<Spanned<u32>>,
+    max_samples: Option<Spanned<u32>>,
+    background: Option<[f32; 3]>,
+    tone_map: Option<Spanned<String>>,
+    transfer: Option<Spanned<String>>,
+    white_point: Option<Spanned<f32>>,
+    exposure: Option<Spanned<f32>>,
+    bit_depth: Option<Spanned<u32>>,
+    alpha: Option<bool>,
+    integrator: Option<Spanned<String>>,
+    light_selection: Option<Spanned<String>>,
+}
+
+// Materials and primitives are flat tables with a `type` key. A serde tagged enum would be
+// tidier, but it buffers the table and loses the positions we need for error messages.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextureDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    color: Option<[f32; 3]>,
+    even: Option<[f32; 3]>,
+    odd: Option<[f32; 3]>,
+    scale: Option<f32>,
+    columns: Option<u32>,
+    rows: Option<u32>,
+    seed: Option<u64>,
+    file: Option<String>,
+    filter: Option<String>,
+    wrap: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    albedo: Option<[f32; 3]>,
+    texture: Option<String>,
+    fuzz: Option<f32>,
+    index_of_refraction: Option<f32>,
+    color: Option<[f32; 3]>,
+    strength: Option<f32>,
+    two_sided: Option<bool>,
+    sampling: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrimitiveDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    center: Option<[f32; 3]>,
+    radius: Option<f32>,
+    vertices: Option<[[f32; 3]; 3]>,
+    corner: Option<[f32; 3]>,
+    edges: Option<[[f32; 3]; 2]>,
+    file: Option<String>,
+    material: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LightDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    position: Option<[f32; 3]>,
+    direction: Option<[f32; 3]>,
+    color: Option<[f32; 3]>,
+    intensity: f32,
+    inner_angle: Option<f32>,
+    outer_angle: Option<f32>,
+    angular_diameter: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    color: Option<[f32; 3]>,
+    file: Option<String>,
+    rotation: Option<f32>,
+    intensity: Option<f32>,
+    sun_elevation: Option<f32>,
+    sun_azimuth: Option<f32>,
+    turbidity: Option<f32>,
+}
+
+enum Primitive {
+    Sphere { center: Point3, radius: f32, material: usize },
+    Triangle { vertices: [Point3; 3], material: usize },
+    Rect { corner: Point3, edges: [Vector3; 2], material: usize },
+    Mesh { obj: usize, material: Option<usize> },
+}
+
+/// Handle to a material added to a `Scene`
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MaterialId(usize);
+
+/// A loaded scene: the camera, render settings, and everything needed to build the world
+pub struct Scene {
+    pub camera: Camera,
+    config: SceneConfig,
+    material_names: Vec<String>,
+    materials: Vec<Material>,
+    primitives: Vec<Primitive>,
+    objs: Vec<ObjFile>,
+    lights: Vec<Box<dyn Light>>, // Lights that aren't surfaces
+    default_material: Material, // For OBJ faces without a material
+}
+
+impl Scene {
+    /// An empty scene, for building up in code. The camera is fitted to the config's aspect ratio.
+    pub fn new(mut camera: Camera, config: SceneConfig) -> Self {
+        camera.set_aspect_ratio(config.aspect_ratio());
+        Self {
+            camera,
+            config,
+            material_names: Vec::new(),
+            materials: Vec::new(),
+            primitives: Vec::new(),
+            objs: Vec::new(),
+            lights: Vec::new(),
+            default_material: Material::default(),
+        }
+    }
+
+    pub fn config(&self) -> &SceneConfig {
+        &self.config
+    }
+
+    /// Change the render settings, refitting the camera if the image changes shape
+    pub fn set_config(&mut self, config: SceneConfig) {
+        self.camera.set_aspect_ratio(config.aspect_ratio());
+        self.config = config;
+    }
+
+    pub fn add_material(&mut self, name: &str, material: Material) -> MaterialId {
+        self.material_names.push(name.to_string());
+        self.materials.push(material);
+        MaterialId(self.materials.len() - 1)
+    }
+
+    pub fn add_sphere(&mut self, center: Point3, radius: f32, material: MaterialId) {
+        self.primitives.push(Primitive::Sphere { center, radius, material: material.0 });
+    }
+
+    pub fn add_triangle(&mut self, vertices: [Point3; 3], material: MaterialId) {
+        self.primitives.push(Primitive::Triangle { vertices, material: material.0 });
+    }
+
+    /// Add a rectangle from `corner` along two edges, facing along their cross product
+    pub fn add_rect(&mut self, corner: Point3, edges: [Vector3; 2], material: MaterialId) {
+        self.primitives.push(Primitive::Rect { corner, edges, material: material.0 });
+    }
+
+    /// Add a light that isn't a surface, like a point or directional light
+    pub fn add_light(&mut self, light: impl Light + 'static) {
+        self.lights.push(Box::new(light));
+    }
+
+    /// Add a loaded OBJ model, optionally replacing its own materials
+    pub fn add_obj(&mut self, obj: ObjFile, material: Option<MaterialId>) {
+        self.objs.push(obj);
+        self.primitives.push(Primitive::Mesh { obj: self.objs.len() - 1, material: material.map(|m| m.0) });
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        Scene::parse_in(&text, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    /// Parse a scene, resolving files it refers to from the current directory
+    pub fn parse(text: &str) -> Result<Scene, SceneError> {
+        Scene::parse_in(text, Path::new(""))
+    }
+
+    fn parse_in(text: &str, base_dir: &Path) -> Result<Scene, SceneError> {
+        let file: SceneFile = toml::from_str(text).map_err(|err| {
+            let span = err.span().unwrap_or(0..0);
+            SceneError::Parse {
+                line: line_of(text, span.start),
+                field: key_at(text, span.start, err.message()),
+                message: err.message().to_string(),
+            }
+        })?;
+
+        // Colors are linear once they're in the scene, so decode them as they're read
+        let color_space: Transfer = match &file.color_space {
+            None => Transfer::Srgb,
+            Some(name) => name.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, name.span().start),
+                field: "color_space".to_string(),
+                message,
+            })?,
+        };
+        let color = |c: [f32; 3]| color_space.decode_color(color(c));
+
+        let c = &file.camera;
+        let lookfrom = vector(c.lookfrom);
+        let lookat = vector(c.lookat);
+        let focus_dist = c.focus_dist.unwrap_or_else(|| (lookfrom - lookat).length());
+
+        let r = &file.render;
+        let mut builder = SceneConfig::builder();
+        if let Some(aspect_ratio) = &c.aspect_ratio {
+            builder = builder.aspect_ratio(*aspect_ratio.get_ref());
+        }
+        if let Some(width) = &r.width {
+            builder = builder.width(*positive(text, width, "width")?);
+        }
+        if let Some(height) = &r.height {
+            builder = builder.height(*positive(text, height, "height")?);
+        }
+        if let Some(samples) = &r.samples_per_pixel {
+            builder = builder.samples_per_pixel(*positive(text, samples, "samples_per_pixel")?);
+        }
+        if let Some(tile_size) = &r.tile_size {
+            builder = builder.tile_size(*positive(text, tile_size, "tile_size")?);
+        }
+        if let Some(max_depth) = r.max_depth {
+            builder = builder.max_depth(max_depth);
+        }
+        if let Some(threads) = r.threads {
+            builder = builder.threads(threads);
+        }
+        if let Some(seed) = r.seed {
+            builder = builder.seed(seed);
+        }
+        if let Some(sampler) = &r.sampler {
+            builder = builder.sampler(sampler.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, sampler.span().start),
+                field: "sampler".to_string(),
+                message,
+            })?);
+        }
+        if let Some(background) = r.background {
+            builder = builder.environment(Environment::Constant(color(background)));
+        }
+        if let Some(desc) = &file.environment {
+            let table = Table { text, span: desc.span() };
+            if r.background.is_some() {
+                return Err(table.invalid("type", "give either an environment or a background in [render], not both".to_string()));
+            }
+            let desc = desc.get_ref();
+            let color_field = [("color", desc.color.is_some())];
+            let map_fields = [("file", desc.file.is_some()), ("rotation", desc.rotation.is_some())];
+            let sky_fields = [
+                ("sun_elevation", desc.sun_elevation.is_some()),
+                ("sun_azimuth", desc.sun_azimuth.is_some()),
+                ("turbidity", desc.turbidity.is_some()),
+            ];
+            let intensity = || {
+                let intensity = desc.intensity.unwrap_or(1.0);
+                if !(intensity >= 0.0 && intensity.is_finite()) {
+                    return Err(table.invalid("intensity", "must be a finite number, not negative".to_string()));
+                }
+                Ok(intensity)
+            };
+            let environment = match desc.kind.get_ref().as_str() {
+                "gradient" => {
+                    table.only(&color_field)?;
+                    table.only(&map_fields)?;
+                    table.only(&sky_fields)?;
+                    table.only(&[("intensity", desc.intensity.is_some())])?;
+                    Environment::Gradient
+                }
+                "constant" => {
+                    table.only(&map_fields)?;
+                    table.only(&sky_fields)?;
+                    table.only(&[("intensity", desc.intensity.is_some())])?;
+                    Environment::Constant(color(table.required("color", desc.color)?))
+                }
+                "map" => {
+                    table.only(&color_field)?;
+                    table.only(&sky_fields)?;
+                    let intensity = intensity()?;
+                    let file = table.required("file", desc.file.as_ref())?;
+                    let map = EnvironmentMap::load(base_dir.join(file), desc.rotation.unwrap_or(0.0), intensity)
+                        .map_err(|err| table.invalid("file", err.to_string()))?;
+                    Environment::Map(Arc::new(map))
+                }
+                "sky" => {
+                    table.only(&color_field)?;
+                    table.only(&map_fields)?;
+                    let intensity = intensity()?;
+                    let elevation = table.required("sun_elevation", desc.sun_elevation)?;
+                    if !(0.0..=90.0).contains(&elevation) {
+                        return Err(table.invalid("sun_elevation", "must be from 0 to 90 degrees".to_string()));
+                    }
+                    let turbidity = desc.turbidity.unwrap_or(3.0);
+                    if !(1.7..=10.0).contains(&turbidity) {
+                        return Err(table.invalid("turbidity", "must be from 1.7 to 10".to_string()));
+                    }
+                    Environment::Sky(Arc::new(Sky::new(elevation, desc.sun_azimuth.unwrap_or(0.0), turbidity, intensity)))
+                }
+                other => return Err(table.unknown_type(&desc.kind, other, "gradient, constant, map, sky")),
+            };
+            builder = builder.environment(environment);
+        }
+        if let Some(exposure) = &r.exposure {
+            builder = builder.exposure(*exposure.get_ref());
+        }
+        let mut tone_map = match &r.tone_map {
+            None => None,
+            Some(name) => Some(name.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, name.span().start),
+                field: "tone_map".to_string(),
+                message,
+            })?),
+        };
+        // A white point on its own means extended Reinhard, as on the command line
+        match (&tone_map, &r.white_point) {
+            (None | Some(ToneMap::ExtendedReinhard { .. }), Some(value)) => {
+                tone_map = Some(ToneMap::ExtendedReinhard { white_point: *value.get_ref() })
+            }
+            (Some(_), Some(value)) => return Err(SceneError::Invalid {
+                line: line_of(text, value.span().start),
+                field: "white_point".to_string(),
+                message: "only used with tone_map = \"reinhard-extended\"".to_string(),
+            }),
+            _ => (),
+        }
+        if let Some(tone_map) = tone_map {
+            builder = builder.tone_map(tone_map);
+        }
+        if let Some(transfer) = &r.transfer {
+            builder = builder.transfer(transfer.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, transfer.span().start),
+                field: "transfer".to_string(),
+                message,
+            })?);
+        }
+        if let Some(bit_depth) = &r.bit_depth {
+            builder = builder.bit_depth(bit_depth.get_ref().to_string().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, bit_depth.span().start),
+                field: "bit_depth".to_string(),
+                message,
+            })?);
+        }
+        if let Some(alpha) = r.alpha {
+            builder = builder.alpha(alpha);
+        }
+        // Point, spot and directional lights are only found by sampling them, so nee renders
+        // scenes with any unless they ask for another integrator
+        match &r.integrator {
+            Some(integrator) => builder = builder.integrator(integrator.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, integrator.span().start),
+                field: "integrator".to_string(),
+                message,
+            })?),
+            None if !file.lights.is_empty() => builder = builder.integrator(Integrator::Nee),
+            None => (),
+        }
+        if let Some(light_selection) = &r.light_selection {
+            builder = builder.light_selection(light_selection.get_ref().parse().map_err(|message| SceneError::Invalid {
+                line: line_of(text, light_selection.span().start),
+                field: "light_selection".to_string(),
+                message,
+            })?);
+        }
+        let adaptive_fields = [(&r.min_samples, "min_samples"), (&r.max_samples, "max_samples")];
+        match &r.target_error {
+            None => {
+                if let Some((Some(value), field)) = adaptive_fields.iter().find(|(value, _)| value.is_some()) {
+                    return Err(SceneError::Invalid {
+                        line: line_of(text, value.span().start),
+                        field: field.to_string(),
+                        message: "only used with target_error".to_string(),
+                    });
+                }
+            }
+            Some(target_error) => {
+                let mut adaptive = AdaptiveSampling::new(*target_error.get_ref());
+                if let Some(min_samples) = &r.min_samples {
+                    adaptive.min_samples = *min_samples.get_ref();
+                }
+                if let Some(max_samples) = &r.max_samples {
+                    adaptive.max_samples = *max_samples.get_ref();
+                }
+                builder = builder.adaptive(adaptive);
+            }
+        }
+        // Zero sizes and samples were caught above, so what's left is down to the aspect ratio,
+        // the adaptive settings or the output settings
+        let config = builder.build().map_err(|err| {
+            let (field, span) = match err {
+                ConfigError::TargetError(_) => ("target_error", r.target_error.as_ref().map(|v| v.span())),
+                ConfigError::AdaptiveSamples { .. } => ("min_samples", r.min_samples.as_ref().or(r.max_samples.as_ref()).map(|v| v.span())),
+                ConfigError::Exposure(_) => ("exposure", r.exposure.as_ref().map(|v| v.span())),
+                ConfigError::WhitePoint(_) => ("white_point", r.white_point.as_ref().map(|v| v.span())),
+                _ => ("aspect_ratio", c.aspect_ratio.as_ref().map(|v| v.span())),
+            };
+            SceneError::Invalid {
+                line: span.map_or(1, |span| line_of(text, span.start)),
+                field: field.to_string(),
+                message: err.to_string(),
+            }
+        })?;
+        let camera = Camera::new(lookfrom, lookat, vector(c.vup), c.vfov, config.aspect_ratio(), c.aperture, focus_dist);
+
+        let mut textures = BTreeMap::new();
+        for (name, desc) in &file.textures {
+            let table = Table { text, span: desc.span() };
+            let desc = desc.get_ref();
+            let checker_fields = [("even", desc.even.is_some()), ("odd", desc.odd.is_some())];
+            let grid_fields = [("columns", desc.columns.is_some()), ("rows", desc.rows.is_some())];
+            let image_fields = [("file", desc.file.is_some()), ("filter", desc.filter.is_some()), ("wrap", desc.wrap.is_some())];
+            if desc.kind.get_ref() != "image" {
+                table.only(&image_fields)?;
+            }
+            let texture = match desc.kind.get_ref().as_str() {
+                "solid" => {
+                    table.only(&checker_fields)?;
+                    table.only(&grid_fields)?;
+                    table.only(&[("scale", desc.scale.is_some()), ("seed", desc.seed.is_some())])?;
+                    Texture::Solid(color(table.required("color", desc.color)?))
+                }
+                "checker" => {
+                    table.only(&grid_fields)?;
+                    table.only(&[("color", desc.color.is_some()), ("seed", desc.seed.is_some())])?;
+                    Texture::checker(color(table.required("even", desc.even)?), color(table.required("odd", desc.odd)?), desc.scale.unwrap_or(10.0))
+                }
+                "uv_checker" => {
+                    table.only(&[("color", desc.color.is_some()), ("scale", desc.scale.is_some()), ("seed", desc.seed.is_some())])?;
+                    Texture::uv_checker(color(table.required("even", desc.even)?), color(table.required("odd", desc.odd)?),
+                        desc.columns.unwrap_or(8), desc.rows.unwrap_or(8))
+                }
+                kind @ ("noise" | "turbulence" | "marble") => {
+                    table.only(&checker_fields)?;
+                    table.only(&grid_fields)?;
+                    let kind = match kind {
+                        "noise" => NoiseKind::Plain,
+                        "turbulence" => NoiseKind::Turbulence,
+                        _ => NoiseKind::Marble,
+                    };
+                    Texture::noise(color(desc.color.unwrap_or([1.0, 1.0, 1.0])), desc.scale.unwrap_or(1.0), kind, desc.seed.unwrap_or(0))
+                }
+                "image" => {
+                    table.only(&checker_fields)?;
+                    table.only(&grid_fields)?;
+                    table.only(&[("color", desc.color.is_some()), ("scale", desc.scale.is_some()), ("seed", desc.seed.is_some())])?;
+                    let file = table.required("file", desc.file.as_ref())?;
+                    let mut image = ImageTexture::load(base_dir.join(file)).map_err(|err| table.invalid("file", err.to_string()))?;
+                    image.set_filter(match desc.filter.as_deref() {
+                        None | Some("bilinear") => Filter::Bilinear,
+                        Some("nearest") => Filter::Nearest,
+                        Some(other) => return Err(table.invalid("filter", format!("unknown filter `{}`, expected nearest or bilinear", other))),
+                    });
+                    image.set_wrap(match desc.wrap.as_deref() {
+                        None | Some("repeat") => Wrap::Repeat,
+                        Some("clamp") => Wrap::Clamp,
+                        Some("mirror") => Wrap::Mirror,
+                        Some(other) => return Err(table.invalid("wrap", format!("unknown wrap mode `{}`, expected repeat, clamp or mirror", other))),
+                    });
+                    Texture::Image(image)
+                }
+                other => return Err(table.unknown_type(&desc.kind, other, "solid, checker, uv_checker, noise, turbulence, marble, image")),
+            };
+            textures.insert(name.as_str(), texture);
+        }
+
+        let mut material_names = Vec::new();
+        let mut materials = Vec::new();
+        for (name, desc) in &file.materials {
+            let table = Table { text, span: desc.span() };
+            let desc = desc.get_ref();
+            let light_fields = [
+                ("color", desc.color.is_some()),
+                ("strength", desc.strength.is_some()),
+                ("two_sided", desc.two_sided.is_some()),
+                ("sampling", desc.sampling.is_some()),
+            ];
+            // A material's color can come from a flat color field or a named texture, not both
+            let surface = |field: &str, value: Option<[f32; 3]>, default: Option<[f32; 3]>| -> Result<Texture, SceneError> {
+                match (&desc.texture, value) {
+                    (Some(_), Some(_)) => Err(table.invalid("texture", format!("give either `{}` or `texture`, not both", field))),
+                    (Some(texture), None) => textures.get(texture.as_str()).cloned()
+                        .ok_or_else(|| table.invalid("texture", format!("no texture named `{}`", texture))),
+                    (None, value) => Ok(color(table.required(field, value.or(default))?).into()),
+                }
+            };
+            let material = match desc.kind.get_ref().as_str() {
+                "diffuse" => {
+                    table.only(&[("fuzz", desc.fuzz.is_some()), ("index_of_refraction", desc.index_of_refraction.is_some())])?;
+                    table.only(&light_fields)?;
+                    Material::Diffuse { albedo: surface("albedo", desc.albedo, None)? }
+                }
+                "metal" => {
+                    table.only(&[("index_of_refraction", desc.index_of_refraction.is_some())])?;
+                    table.only(&light_fields)?;
+                    Material::Metal { albedo: surface("albedo", desc.albedo, None)?, fuzz: desc.fuzz.unwrap_or(0.0) }
+                }
+                "dielectric" | "dialectric" => {
+                    table.only(&[("fuzz", desc.fuzz.is_some())])?;
+                    table.only(&light_fields)?;
+                    Material::Dialectric {
+                        albedo: surface("albedo", desc.albedo, Some([1.0, 1.0, 1.0]))?,
+                        index_of_refraction: table.required("index_of_refraction", desc.index_of_refraction)?,
+                    }
+                }
+                "light" => {
+                    table.only(&[("albedo", desc.albedo.is_some()), ("fuzz", desc.fuzz.is_some()), ("index_of_refraction", desc.index_of_refraction.is_some())])?;
+                    Material::DiffuseLight {
+                        color: surface("color", desc.color, Some([1.0, 1.0, 1.0]))?,
+                        strength: desc.strength.unwrap_or(1.0),
+                        two_sided: desc.two_sided.unwrap_or(true),
+                        sampling: match &desc.sampling {
+                            None => LightSampling::default(),
+                            Some(sampling) => sampling.parse().map_err(|message| table.invalid("sampling", message))?,
+                        },
+                    }
+                }
+                other => return Err(table.unknown_type(&desc.kind, other, "diffuse, metal, dielectric, light")),
+            };
+            material_names.push(name.clone());
+            materials.push(material);
+        }
+
+        let mut primitives = Vec::new();
+        let mut objs = Vec::new();
+        for desc in &file.primitives {
+            let table = Table { text, span: desc.span() };
+            let desc = desc.get_ref();
+            let rect_fields = [("corner", desc.corner.is_some()), ("edges", desc.edges.is_some())];
+            let material = match &desc.material {
+                None => None,
+                Some(name) => Some(material_names.iter().position(|n| n == name).ok_or_else(|| SceneError::Invalid {
+                    line: table.line("material"),
+                    field: "material".to_string(),
+                    message: format!("no material named `{}`", name),
+                })?),
+            };
+            primitives.push(match desc.kind.get_ref().as_str() {
+                "sphere" => {
+                    table.only(&[("vertices", desc.vertices.is_some()), ("file", desc.file.is_some())])?;
+                    table.only(&rect_fields)?;
+                    Primitive::Sphere {
+                        center: vector(table.required("center", desc.center)?),
+                        radius: table.required("radius", desc.radius)?,
+                        material: table.required("material", material)?,
+                    }
+                }
+                "triangle" => {
+                    table.only(&[("center", desc.center.is_some()), ("radius", desc.radius.is_some()), ("file", desc.file.is_some())])?;
+                    table.only(&rect_fields)?;
+                    Primitive::Triangle {
+                        vertices: table.required("vertices", desc.vertices)?.map(vector),
+                        material: table.required("material", material)?,
+                    }
+                }
+                "rect" => {
+                    table.only(&[("center", desc.center.is_some()), ("radius", desc.radius.is_some()), ("vertices", desc.vertices.is_some()), ("file", desc.file.is_some())])?;
+                    let corner = vector(table.required("corner", desc.corner)?);
+                    let edges = table.required("edges", desc.edges)?.map(vector);
+                    // The sine of the angle between them, so the check doesn't depend on the scene's scale
+                    let [u, v] = edges;
+                    let sin_angle = u.cross(v).length() / (u.length() * v.length());
+                    if !(sin_angle > 1e-6 && sin_angle.is_finite()) {
+                        return Err(table.invalid("edges", "must have some length and not be parallel".to_string()));
+                    }
+                    Primitive::Rect {
+                        corner,
+                        edges,
+                        material: table.required("material", material)?,
+                    }
+                }
+                "mesh" => {
+                    table.only(&[("center", desc.center.is_some()), ("radius", desc.radius.is_some()), ("vertices", desc.vertices.is_some())])?;
+                    table.only(&rect_fields)?;
+                    let file = table.required("file", desc.file.as_ref())?;
+                    let obj = ObjFile::load(base_dir.join(file)).map_err(|err| table.invalid("file", err.to_string()))?;
+                    objs.push(obj);
+                    Primitive::Mesh { obj: objs.len() - 1, material }
+                }
+                other => return Err(table.unknown_type(&desc.kind, other, "sphere, triangle, rect, mesh")),
+            });
+        }
+
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for desc in &file.lights {
+            let table = Table { text, span: desc.span() };
+            let desc = desc.get_ref();
+            if !(desc.intensity >= 0.0 && desc.intensity.is_finite()) {
+                return Err(table.invalid("intensity", "must be a finite number, not negative".to_string()));
+            }
+            let light_color = color(desc.color.unwrap_or([1.0, 1.0, 1.0]));
+            let direction = |table: &Table| -> Result<Vector3, SceneError> {
+                let direction = vector(table.required("direction", desc.direction)?);
+                if direction.near_zero() {
+                    return Err(table.invalid("direction", "must not be zero".to_string()));
+                }
+                Ok(direction)
+            };
+            let spot_fields = [("inner_angle", desc.inner_angle.is_some()), ("outer_angle", desc.outer_angle.is_some())];
+            lights.push(match desc.kind.get_ref().as_str() {
+                "point" => {
+                    table.only(&[("direction", desc.direction.is_some()), ("angular_diameter", desc.angular_diameter.is_some())])?;
+                    table.only(&spot_fields)?;
+                    Box::new(PointLight::new(vector(table.required("position", desc.position)?), light_color, desc.intensity))
+                }
+                "spot" => {
+                    table.only(&[("angular_diameter", desc.angular_diameter.is_some())])?;
+                    let inner_angle = table.required("inner_angle", desc.inner_angle)?;
+                    let outer_angle = table.required("outer_angle", desc.outer_angle)?;
+                    if !(0.0..=180.0).contains(&outer_angle) {
+                        return Err(table.invalid("outer_angle", "must be between 0 and 180 degrees".to_string()));
+                    }
+                    if !(0.0..=outer_angle).contains(&inner_angle) {
+                        return Err(table.invalid("inner_angle", "must be between 0 and outer_angle".to_string()));
+                    }
+                    let position = vector(table.required("position", desc.position)?);
+                    Box::new(SpotLight::new(position, direction(&table)?, light_color, desc.intensity, inner_angle, outer_angle))
+                }
+                "directional" => {
+                    table.only(&[("position", desc.position.is_some())])?;
+                    table.only(&spot_fields)?;
+                    let angular_diameter = desc.angular_diameter.unwrap_or(0.0);
+                    if !(0.0..180.0).contains(&angular_diameter) {
+                        return Err(table.invalid("angular_diameter", "must be at least 0 and under 180 degrees".to_string()));
+                    }
+                    Box::new(DirectionalLight::new(direction(&table)?, light_color, desc.intensity, angular_diameter))
+                }
+                other => return Err(table.unknown_type(&desc.kind, other, "point, spot, directional")),
+            });
+        }
+
+        Ok(Scene { camera, config, material_names, materials, primitives, objs, lights, default_material: Material::default() })
+    }
+
+    /// Whether there are point, spot or directional lights, which only the nee integrator renders
+    pub fn has_punctual_lights(&self) -> bool {
+        !self.lights.is_empty()
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.material_names.iter().position(|n| n == name).map(|i| &self.materials[i])
+    }
+
+    /// Build the world, borrowing materials and lights from the scene
+    pub fn world(&self) -> World<'_> {
+        let mut world = HittableList::default();
+        for primitive in &self.primitives {
+            match primitive {
+                Primitive::Sphere { center, radius, material } => {
+                    world.add(Box::new(Sphere{center: *center, radius: *radius, material: &self.materials[*material]}));
+                }
+                Primitive::Triangle { vertices: [v0, v1, v2], material } => {
+                    world.add(Box::new(Triangle{v0: *v0, v1: *v1, v2: *v2, material: &self.materials[*material]}));
+                }
+                Primitive::Rect { corner, edges: [edge_u, edge_v], material } => {
+                    world.add(Box::new(Rect{corner: *corner, edge_u: *edge_u, edge_v: *edge_v, material: &self.materials[*material]}));
+                }
+                Primitive::Mesh { obj, material } => {
+                    let meshes = match material {
+                        None => self.objs[*obj].meshes(&self.default_material),
+                        Some(material) => self.objs[*obj].meshes_with_material(&self.materials[*material]),
+                    };
+                    for mesh in meshes {
+                        world.add(Box::new(mesh));
+                    }
+                }
+            }
+        }
+        World { surfaces: BvhNode::new(world, SplitMethod::Sah), lights: &self.lights }
+    }
+}
+
+/// What `Scene::world` builds: the scene's surfaces in a BVH, and its lights that aren't surfaces
+pub struct World<'a> {
+    surfaces: BvhNode<'a>,
+    lights: &'a [Box<dyn Light>],
+}
+
+impl Hittable for World<'_> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        self.surfaces.hit(r, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.surfaces.bounding_box()
+    }
+
+    fn lights(&self) -> Vec<&dyn Light> {
+        let mut lights = self.surfaces.lights();
+        lights.extend(self.lights.iter().map(|light| light.as_ref()));
+        lights
+    }
+}
+
+fn vector(v: [f32; 3]) -> Vector3 {
+    Vector3::new(v[0], v[1], v[2])
+}
+
+fn color(c: [f32; 3]) -> Color {
+    Color::new(c[0], c[1], c[2])
+}
+
+fn positive<'v>(text: &str, value: &'v Spanned<u32>, field: &str) -> Result<&'v u32, SceneError> {
+    if *value.get_ref() == 0 {
+        return Err(SceneError::Invalid {
+            line: line_of(text, value.span().start),
+            field: field.to_string(),
+            message: "must be at least 1".to_string(),
+        });
+    }
+    Ok(value.get_ref())
+}
+
+// 1-based line number of a byte offset
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+// The key assigned on the line containing `offset`, if there is one
+fn key_at(text: &str, offset: usize, message: &str) -> Option<String> {
+    let offset = offset.min(text.len());
+    let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[start..].lines().next().unwrap_or("");
+    match line.split_once('=') {
+        Some((key, _)) if !key.trim().is_empty() && !key.trim().starts_with('[') => Some(key.trim().to_string()),
+        // Missing fields are reported against the table header, so the name is only in the message
+        _ => message.strip_prefix("missing field `").and_then(|rest| rest.split('`').next()).map(|f| f.to_string()),
+    }
+}
+
+// A `[materials.x]` or `[[primitives]]` table, for locating errors in it
+struct Table<'t> {
+    text: &'t str,
+    span: Range<usize>,
+}
+
+impl Table<'_> {
+    // Line of `field` inside the table, falling back to the start of the table
+    fn line(&self, field: &str) -> usize {
+        let mut offset = self.span.start;
+        for line in self.text[self.span.clone()].split_inclusive('\n') {
+            let trimmed = line.trim_start();
+            if trimmed.starts_with(field) && trimmed[field.len()..].trim_start().starts_with('=') {
+                return line_of(self.text, offset);
+            }
+            offset += line.len();
+        }
+        line_of(self.text, self.span.start)
+    }
+
+    fn required<T>(&self, field: &str, value: Option<T>) -> Result<T, SceneError> {
+        value.ok_or_else(|| SceneError::Invalid {
+            line: line_of(self.text, self.span.start),
+            field: field.to_string(),
+            message: "missing field".to_string(),
+        })
+    }
+
+    // Reject fields that were given but mean nothing for this type
+    fn only(&self, unused: &[(&str, bool)]) -> Result<(), SceneError> {
+        match unused.iter().find(|(_, given)| *given) {
+            None => Ok(()),
+            Some((field, _)) => Err(SceneError::Invalid {
+                line: self.line(field),
+                field: field.to_string(),
+                message: "not used by this type".to_string(),
+            }),
+        }
+    }
+
+    fn invalid(&self, field: &str, message: String) -> SceneError {
+        SceneError::Invalid { line: self.line(field), field: field.to_string(), message }
+    }
+
+    fn unknown_type(&self, kind: &Spanned<String>, name: &str, expected: &str) -> SceneError {
+        SceneError::Invalid {
+            line: line_of(self.text, kind.span().start),
+            field: "type".to_string(),
+            message: format!("unknown type `{}`, expected one of: {}", name, expected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_file_loads() {
+        let scene = Scene::load("scenes/metal_spheres.toml").unwrap();
+        assert_eq!(scene.config().samples_per_pixel(), 150);
+        assert!(scene.material("glass").is_some());
+
+        let world = scene.world();
+        let r = Ray { origin: Vector3::new(0.0, 0.0, 0.0), direction: Vector3::new(0.0, 0.0, -1.0) };
+        assert!((world.hit(&r, 0.001, f32::INFINITY).unwrap().t - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn scene_errors_report_line_and_field() {
+        let camera = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 90.0\naspect_ratio = 1.0\n";
+
+        let bad_type = format!("{}\n[materials.red]\ntype = \"diffuse\"\nalbedo = \"red\"\n", camera);
+        match Scene::parse(&bad_type) {
+            Err(SceneError::Parse { line, field, .. }) => assert_eq!((line, field.as_deref()), (9, Some("albedo"))),
+            _ => panic!("expected a parse error"),
+        }
+
+        let unknown = format!("{}\n[[primitives]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, -1.0]\nradius = 0.5\nmaterial = \"missing\"\n", camera);
+        match Scene::parse(&unknown) {
+            Err(SceneError::Invalid { line, field, .. }) => assert_eq!((line, field.as_str()), (11, "material")),
+            _ => panic!("expected an invalid scene error"),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// An analytic daylight sky and sun, after Preetham, Shirley and Smits, "A Practical Analytic
+// Model for Daylight" (1999)
+use super::colors::*;
+use super::vectors::*;
+use super::environment::*;
+use super::lights::*;
+use Vector3 as Point3;
+
+use std::f32::consts::PI;
+
+// Preetham's luminances are in kcd/m^2. Scaled by this, a clear sky's zenith comes out a
+// little under half the brightness of the old gradient's.
+const SKY_SCALE: f32 = 0.05;
+// Illuminance of the sun above the atmosphere, in klx, scaled to match
+const SUN_ILLUMINANCE: f32 = 128.0;
+const SUN_ANGULAR_DIAMETER: f32 = 0.53;
+// The sky is baked at this size to pick directions from
+const BAKE_WIDTH: u32 = 128;
+const BAKE_HEIGHT: u32 = 64;
+// Below the horizon, the sky keeps the color it has just above it
+const MIN_COS_THETA: f32 = 1e-3;
+
+// Perez distribution coefficients A to E for luminance and the x and y chromaticities, each
+// linear in turbidity: [slope, offset]
+const PEREZ_Y: [[f32; 2]; 5] = [[0.1787, -1.4630], [-0.3554, 0.4275], [-0.0227, 5.3251], [0.1206, -2.5771], [-0.0670, 0.3703]];
+const PEREZ_X: [[f32; 2]; 5] = [[-0.0193, -0.2592], [-0.0665, 0.0008], [-0.0004, 0.2125], [-0.0641, -0.8989], [-0.0033, 0.0452]];
+const PEREZ_CHROMA_Y: [[f32; 2]; 5] = [[-0.0167, -0.2608], [-0.0950, 0.0092], [-0.0079, 0.2102], [-0.0441, -1.6537], [-0.0109, 0.0529]];
+
+// Zenith chromaticities, [T^2, T, 1] times these times [theta^3, theta^2, theta, 1] of the sun
+const ZENITH_X: [[f32; 4]; 3] = [
+    [0.00166, -0.00375, 0.00209, 0.0],
+    [-0.02903, 0.06377, -0.03202, 0.00394],
+    [0.11693, -0.21196, 0.06052, 0.25886],
+];
+const ZENITH_CHROMA_Y: [[f32; 4]; 3] = [
+    [0.00275, -0.00610, 0.00317, 0.0],
+    [-0.04214, 0.08970, -0.04153, 0.00516],
+    [0.15346, -0.26756, 0.06670, 0.26688],
+];
+
+// Wavelengths in micrometres the sun's color is worked out at, for red, green and blue
+const WAVELENGTHS: [f32; 3] = [0.65, 0.57, 0.475];
+
+/// Clear to hazy daylight for a sun at some elevation and azimuth. The sky lights the scene
+/// along with a matching sun disk, both sampled by the nee integrator.
+pub struct Sky {
+    sun_elevation: f32,
+    sun_azimuth: f32,
+    turbidity: f32,
+    intensity: f32,
+    model: Perez,
+    sun: DirectionalLight,
+    map: EnvironmentMap, // The sky without the sun, to pick directions from
+    sun_probability: f32, // Of sampling the sun rather than the sky
+}
+
+impl Sky {
+    /// A sun `sun_elevation` degrees above the horizon, from 0 to 90, and `sun_azimuth`
+    /// degrees clockwise from -z seen from above, so 90 is toward +x. `turbidity` is the haze,
+    /// from about 2 for a very clear sky to 10 for a murky one. `intensity` scales it all.
+    pub fn new(sun_elevation: f32, sun_azimuth: f32, turbidity: f32, intensity: f32) -> Self {
+        let (elevation, azimuth) = (sun_elevation.to_radians(), sun_azimuth.to_radians());
+        let to_sun = Vector3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos());
+        let theta_sun = PI / 2.0 - elevation;
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |m: &[[f32; 4]; 3]| {
+            let thetas = [theta_sun.powi(3), theta_sun.powi(2), theta_sun, 1.0];
+            let row = |r: &[f32; 4]| r.iter().zip(&thetas).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(&m[0]) + t * row(&m[1]) + row(&m[2])
+        };
+        let coefficients = |c: &[[f32; 2]; 5]| {
+            let mut out = [0.0; 5];
+            for (o, [slope, offset]) in out.iter_mut().zip(c) {
+                *o = slope * t + offset;
+            }
+            out
+        };
+        let perez = [coefficients(&PEREZ_Y), coefficients(&PEREZ_X), coefficients(&PEREZ_CHROMA_Y)];
+        let zenith = [zenith_luminance, chromaticity(&ZENITH_X), chromaticity(&ZENITH_CHROMA_Y)];
+
+        let model = Perez { to_sun, zenith, perez, scale: SKY_SCALE * intensity };
+        let map = EnvironmentMap::from_fn(BAKE_WIDTH, BAKE_HEIGHT, |direction| model.radiance(direction));
+        let sun = DirectionalLight::new(-to_sun, sun_transmittance(theta_sun, turbidity), SUN_ILLUMINANCE * SKY_SCALE * intensity, SUN_ANGULAR_DIAMETER);
+        // By power, but never so lopsided that shade lit only by the sky goes without samples
+        let (sun_power, sky_power) = (sun.power(1.0), map.power(1.0));
+        let sun_probability = if sun_power + sky_power > 0.0 { (sun_power / (sun_power + sky_power)).clamp(0.1, 0.9) } else { 0.5 };
+        Self { sun_elevation, sun_azimuth, turbidity, intensity, model, sun, map, sun_probability }
+    }
+
+    /// In degrees
+    pub fn sun_elevation(&self) -> f32 {
+        self.sun_elevation
+    }
+
+    /// In degrees
+    pub fn sun_azimuth(&self) -> f32 {
+        self.sun_azimuth
+    }
+
+    pub fn turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// The sun disk, as a directional light
+    pub fn sun(&self) -> &DirectionalLight {
+        &self.sun
+    }
+
+    /// Radiance arriving from `direction`, sun and sky together
+    pub fn radiance(&self, direction: Vector3) -> Color {
+        self.model.radiance(direction) + self.sun.disk_radiance(direction)
+    }
+}
+
+// The sky without the sun, as the Perez distribution of luminance and chromaticity scaled to
+// match the zenith
+struct Perez {
+    to_sun: Vector3,
+    zenith: [f32; 3], // Luminance and chromaticity straight up
+    perez: [[f32; 5]; 3],
+    scale: f32,
+}
+
+impl Perez {
+    fn radiance(&self, direction: Vector3) -> Color {
+        let direction = direction.unit_vector();
+        let cos_theta = direction.y.max(MIN_COS_THETA);
+        let gamma = direction.dot(self.to_sun).clamp(-1.0, 1.0).acos();
+        let theta_sun = self.to_sun.y.clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2].map(|k| {
+            let f = |cos_theta: f32, gamma: f32| {
+                let [a, b, c, d, e] = self.perez[k];
+                (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+            };
+            self.zenith[k] * f(cos_theta, gamma) / f(1.0, theta_sun)
+        });
+        self.scale * xyy_to_rgb(luminance, x, y)
+    }
+}
+
+// A mix of sampling the sun disk and the baked sky, weighed by which is brighter
+impl Light for Sky {
+    fn sample(&self, p: Point3, (u1, u2): (f32, f32)) -> Option<LightSample> {
+        let direction = if u1 < self.sun_probability {
+            self.sun.sample(p, (u1 / self.sun_probability, u2))?.direction
+        }
+        else {
+            self.map.sample(p, ((u1 - self.sun_probability) / (1.0 - self.sun_probability), u2))?.direction
+        };
+        let pdf = self.pdf(p, direction);
+        (pdf > 0.0).then(|| LightSample { direction, distance: f32::INFINITY, radiance: self.radiance(direction), pdf })
+    }
+
+    fn pdf(&self, p: Point3, direction: Vector3) -> f32 {
+        self.sun_probability * self.sun.disk_pdf(direction) + (1.0 - self.sun_probability) * self.map.pdf(p, direction)
+    }
+
+    fn power(&self, world_radius: f32) -> f32 {
+        self.sun.power(world_radius) + self.map.power(world_radius)
+    }
+}
+
+// The sun's color through the atmosphere: Rayleigh scattering by air and Angstrom's formula
+// for haze, over the air mass at its zenith angle (Kasten's fit, as Preetham uses)
+fn sun_transmittance(theta_sun: f32, turbidity: f32) -> Color {
+    let air_mass = 1.0 / (theta_sun.cos() + 0.15 * (93.885 - theta_sun.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let [r, g, b] = WAVELENGTHS.map(|lambda| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let haze = beta * lambda.powf(-1.3);
+        (-(rayleigh + haze) * air_mass).exp()
+    });
+    Color::new(r, g, b)
+}
+
+// CIE xyY to linear sRGB
+fn xyy_to_rgb(luminance: f32, x: f32, y: f32) -> Color {
+    if y <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    let (cx, cy, cz) = (x * luminance / y, luminance, (1.0 - x - y) * luminance / y);
+    Color::new(
+        3.2406 * cx - 1.5372 * cy - 0.4986 * cz,
+        -0.9689 * cx + 1.8758 * cy + 0.0415 * cz,
+        0.0557 * cx - 0.2040 * cy + 1.0570 * cz,
+    ).clamp(0.0, f32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Integrator;
+    use crate::scene::Scene;
+    use crate::test_util::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn daylight_skies_light_the_scene_with_their_sun() {
+        // A sun 30 degrees up and a quarter turn round toward +x, reddened by the air it shines
+        // through, in a sky bluer overhead than toward it
+        let sky = Sky::new(30.0, 90.0, 3.0, 1.0);
+        let to_sun = -sky.sun().direction();
+        assert!((to_sun.x - 0.75f32.sqrt()).abs() < 1e-4 && (to_sun.y - 0.5).abs() < 1e-4 && to_sun.z.abs() < 1e-4);
+        let (zenith, sun) = (sky.radiance(Vector3::new(0.0, 1.0, 0.0)), sky.radiance(to_sun));
+        assert!(zenith.b > zenith.r && sun.r > sun.b);
+        assert!(sun.g > 1000.0 * zenith.g);
+        let sky = Arc::new(sky);
+        let environment = Environment::Sky(Arc::clone(&sky));
+
+        let floor_material = floor_material();
+        let world = floor_world(&floor_material, Vec::new());
+        let lights = LightList::new(&world, &environment, LightSelection::Power);
+        assert_eq!(lights.len(), 1);
+
+        // The floor gets the sun's irradiance at its slant, plus the sky's summed over a grid
+        // that leaves the sun disk out, since it's far too small for the grid to find
+        let irradiance = hemisphere_irradiance(256, |direction| (sky.radiance(direction) - sky.sun().disk_radiance(direction)).g);
+        // The disk's radiance over its solid angle, at a slant of cos 60
+        let sun_irradiance = 0.5 * sky.sun().disk_radiance(to_sun).g / sky.sun().disk_pdf(to_sun);
+        let expected = FLOOR_ALBEDO * (irradiance + sun_irradiance) / PI;
+        let lit = mean(&estimates(Integrator::Nee, &down_from(1.0), &world, &lights, &environment, 2, 4000));
+        assert_near("light sampling", lit, expected, 0.05);
+
+        // Scene files describe the sky by its sun, and keep the turbidity to what the model fits
+        let base = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 40.0\n[environment]\ntype = \"sky\"\nsun_elevation = 45.0\n";
+        match Scene::parse(&format!("{}sun_azimuth = 180.0\n", base)).unwrap().config().environment() {
+            Environment::Sky(sky) => assert_eq!((sky.sun_elevation(), sky.sun_azimuth(), sky.turbidity()), (45.0, 180.0, 3.0)),
+            _ => panic!("expected a sky"),
+        }
+        assert_eq!(invalid_field(&format!("{}turbidity = 20.0\n", base)), "turbidity");
+    }
+}
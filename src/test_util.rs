@@ -0,0 +1,128 @@
+// Helpers shared by the tests
+use super::colors::*;
+use super::vectors::*;
+use super::rays::*;
+use super::primitives::*;
+use super::materials::*;
+use super::render::*;
+use super::samplers::*;
+use super::lights::*;
+use super::environment::*;
+use super::scene::*;
+
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A path in the temp directory that no other test, or other run of the tests, uses. The file
+/// is removed when this is dropped.
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    pub fn new(extension: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("rustrays_{}_{}.{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed), extension);
+        TempFile(std::env::temp_dir().join(name))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// How much light the floor in the light tests reflects
+pub const FLOOR_ALBEDO: f32 = 0.5;
+
+pub fn floor_material() -> Material {
+    Material::Diffuse { albedo: Color::new(FLOOR_ALBEDO, FLOOR_ALBEDO, FLOOR_ALBEDO).into() }
+}
+
+/// The floor for the light tests, the top of a huge sphere at the origin, with `objects` added
+pub fn floor_world<'a>(floor: &'a Material, objects: Vec<Box<dyn Hittable + 'a>>) -> HittableList<'a> {
+    let mut world = HittableList::default();
+    world.add(Box::new(Sphere { center: Vector3::new(0.0, -1000.0, 0.0), radius: 1000.0, material: floor }));
+    for object in objects {
+        world.add(object);
+    }
+    world
+}
+
+/// The floor in a scene file, seen straight down from `height` against a black background, for
+/// tests to add lights to
+pub fn floor_scene(height: f32) -> String {
+    format!("color_space = \"linear\"\n[camera]\nlookfrom = [0.0, {}, 0.0]\nlookat = [0.0, 0.0, 0.0]\nvup = [0.0, 0.0, -1.0]\nvfov = 40.0\n\
+        [render]\nbackground = [0.0, 0.0, 0.0]\n[materials.floor]\ntype = \"diffuse\"\nalbedo = [{1}, {1}, {1}]\n\
+        [[primitives]]\ntype = \"sphere\"\ncenter = [0.0, -1000.0, 0.0]\nradius = 1000.0\nmaterial = \"floor\"\n", height, FLOOR_ALBEDO)
+}
+
+/// A ray straight down at the floor from `height`
+pub fn down_from(height: f32) -> Ray {
+    Ray { origin: Vector3::new(0.0, height, 0.0), direction: Vector3::new(0.0, -1.0, 0.0) }
+}
+
+/// The green of `samples` colors `integrator` finds along `ray`, one from each sample of an
+/// independent sampler
+pub fn estimates(integrator: Integrator, ray: &Ray, world: &impl Hittable, lights: &LightList, environment: &Environment, depth: u32, samples: u32) -> Vec<f32> {
+    let mut sampler = SamplerKind::Independent.sampler(7, samples);
+    (0..samples).map(|s| {
+        sampler.start_sample(0, 0, s);
+        integrator.ray_color(ray, world, lights, environment, depth, sampler.as_mut()).0.g
+    }).collect()
+}
+
+/// The same for a scene file, lit by its own lights and environment with next event estimation
+pub fn scene_estimates(scene: &Scene, ray: &Ray, depth: u32, samples: u32) -> Vec<f32> {
+    let world = scene.world();
+    let lights = LightList::new(&world, scene.config().environment(), scene.config().light_selection());
+    estimates(Integrator::Nee, ray, &world, &lights, scene.config().environment(), depth, samples)
+}
+
+/// Irradiance on the floor from the sky above it, summing the `radiance` each direction sends
+/// down over a grid of `rows` by twice as many columns
+pub fn hemisphere_irradiance(rows: u32, radiance: impl Fn(Vector3) -> f32) -> f32 {
+    let columns = 2 * rows;
+    let (d_theta, d_phi) = (FRAC_PI_2 / rows as f32, 2.0 * PI / columns as f32);
+    let mut irradiance = 0.0f64;
+    for row in 0..rows {
+        let theta = (row as f32 + 0.5) * d_theta;
+        for column in 0..columns {
+            let phi = (column as f32 + 0.5) * d_phi;
+            let direction = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            irradiance += (radiance(direction) * theta.cos() * theta.sin() * d_theta * d_phi) as f64;
+        }
+    }
+    irradiance as f32
+}
+
+/// The field a scene file is rejected for
+#[track_caller]
+pub fn invalid_field(text: &str) -> String {
+    match Scene::parse(text) {
+        Err(SceneError::Invalid { field, .. }) => field,
+        Err(err) => panic!("expected an invalid field, got {}", err),
+        Ok(_) => panic!("expected the scene to be rejected"),
+    }
+}
+
+pub fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+/// The standard deviation of `values`
+pub fn spread(values: &[f32]) -> f32 {
+    let m = mean(values);
+    (values.iter().map(|v| (v - m).powi(2)).sum::<f32>() / values.len() as f32).sqrt()
+}
+
+/// Check an estimate, named `what` in the failure message, is within `tolerance` of `expected`
+/// as a fraction of it
+#[track_caller]
+pub fn assert_near(what: &str, value: f32, expected: f32, tolerance: f32) {
+    assert!((value - expected).abs() <= tolerance * expected.abs(), "{} gave {}, expected {}", what, value, expected);
+}
@@ -0,0 +1,427 @@
+// Textures
+use super::vectors::*;
+use super::colors::*;
+use rand::prelude::*;
+use rand_pcg::Pcg32;
+use Vector3 as Point3;
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const POINT_COUNT: usize = 256;
+
+/// Color that varies over a surface, looked up by surface coordinates or hit point. Clones
+/// share image data and noise tables, so one texture can color many materials.
+#[derive(Clone)]
+pub enum Texture {
+    Solid(Color),
+    /// Alternating cubes in space, `scale` of them per unit length
+    Checker {
+        even: Box<Texture>,
+        odd: Box<Texture>,
+        scale: f32,
+    },
+    /// Alternating squares in texture space, `columns` across u and `rows` across v
+    UvChecker {
+        even: Box<Texture>,
+        odd: Box<Texture>,
+        columns: u32,
+        rows: u32,
+    },
+    Image(ImageTexture),
+    Noise {
+        perlin: Arc<Perlin>,
+        color: Color,
+        scale: f32,
+        kind: NoiseKind,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NoiseKind {
+    /// Smooth Perlin noise
+    Plain,
+    /// Several octaves of noise summed, like camouflage netting
+    Turbulence,
+    /// Sine stripes along z, disturbed by turbulence
+    Marble,
+}
+
+impl Texture {
+    pub fn checker(even: Color, odd: Color, scale: f32) -> Self {
+        Texture::Checker { even: Box::new(even.into()), odd: Box::new(odd.into()), scale }
+    }
+
+    pub fn uv_checker(even: Color, odd: Color, columns: u32, rows: u32) -> Self {
+        Texture::UvChecker { even: Box::new(even.into()), odd: Box::new(odd.into()), columns, rows }
+    }
+
+    pub fn noise(color: Color, scale: f32, kind: NoiseKind, seed: u64) -> Self {
+        Texture::Noise { perlin: Arc::new(Perlin::new(seed)), color, scale, kind }
+    }
+
+    pub fn value(&self, u: f32, v: f32, p: Point3) -> Color {
+        match self {
+            Texture::Solid(color) => *color,
+            Texture::Checker { even, odd, scale } => {
+                let sines = (scale * p.x).sin() * (scale * p.y).sin() * (scale * p.z).sin();
+                if sines < 0.0 { odd.value(u, v, p) } else { even.value(u, v, p) }
+            }
+            Texture::UvChecker { even, odd, columns, rows } => {
+                let column = (u * *columns as f32).floor() as i64;
+                let row = (v * *rows as f32).floor() as i64;
+                if (column + row) % 2 == 0 { even.value(u, v, p) } else { odd.value(u, v, p) }
+            }
+            Texture::Image(image) => image.value(u, v),
+            Texture::Noise { perlin, color, scale, kind } => {
+                let s = *scale * p;
+                let amount = match kind {
+                    NoiseKind::Plain => 0.5 * (1.0 + perlin.noise(s)),
+                    NoiseKind::Turbulence => perlin.turb(s, 7),
+                    NoiseKind::Marble => 0.5 * (1.0 + (s.z + 10.0 * perlin.turb(p, 7)).sin()),
+                };
+                amount * *color
+            }
+        }
+    }
+}
+
+impl From<Color> for Texture {
+    fn from(color: Color) -> Self {
+        Texture::Solid(color)
+    }
+}
+
+/// Why an image texture could not be loaded
+#[derive(Debug)]
+pub enum TextureError {
+    Io { path: PathBuf, error: std::io::Error },
+    Decode { path: PathBuf, message: String },
+}
+
+impl std::fmt::Display for TextureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TextureError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            TextureError::Decode { path, message } => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for TextureError {}
+
+/// How an image is sampled between texel centers
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Filter {
+    /// The texel the point falls in, blocky up close
+    Nearest,
+    /// Blend of the four closest texels
+    #[default]
+    Bilinear,
+}
+
+/// What an image shows outside the 0 to 1 uv square
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum Wrap {
+    /// Tile the image
+    #[default]
+    Repeat,
+    /// Stretch the edge texels
+    Clamp,
+    /// Tile the image, flipping every other copy so the seams match
+    Mirror,
+}
+
+/// Linear colors in rows from the top, stretched over the 0 to 1 uv square
+#[derive(Clone)]
+pub struct ImageTexture {
+    width: u32,
+    height: u32,
+    pixels: Arc<[Color]>, // Shared between clones
+    filter: Filter,
+    wrap: Wrap,
+}
+
+impl ImageTexture {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> Self {
+        assert_eq!(pixels.len(), (width * height) as usize, "image needs width * height pixels");
+        Self { width, height, pixels: pixels.into(), filter: Filter::default(), wrap: Wrap::default() }
+    }
+
+    /// Load an 8 or 16-bit grayscale, RGB or indexed PNG, with or without alpha.
+    /// The sRGB values are converted to linear and alpha is ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TextureError> {
+        let path = path.as_ref();
+        let decode_error = |err: png::DecodingError| match err {
+            png::DecodingError::IoError(error) => TextureError::Io { path: path.to_path_buf(), error },
+            err => TextureError::Decode { path: path.to_path_buf(), message: err.to_string() },
+        };
+        let file = File::open(path).map_err(|error| TextureError::Io { path: path.to_path_buf(), error })?;
+        let mut decoder = png::Decoder::new(file);
+        // Palettes and bit depths under 8 come out as plain 8-bit channels
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let (info, mut reader) = decoder.read_info().map_err(decode_error)?;
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).map_err(decode_error)?;
+
+        let channels = match info.color_type {
+            png::ColorType::Grayscale => 1,
+            png::ColorType::GrayscaleAlpha => 2,
+            png::ColorType::RGB => 3,
+            png::ColorType::RGBA => 4,
+            other => return Err(TextureError::Decode { path: path.to_path_buf(), message: format!("unsupported color type {:?}", other) }),
+        };
+        let bytes = match info.bit_depth {
+            png::BitDepth::Eight => 1,
+            png::BitDepth::Sixteen => 2,
+            other => return Err(TextureError::Decode { path: path.to_path_buf(), message: format!("unsupported bit depth {:?}", other) }),
+        };
+        let mut pixels = Vec::with_capacity((info.width * info.height) as usize);
+        for row in data.chunks(info.line_size).take(info.height as usize) {
+            for texel in row.chunks_exact(channels * bytes).take(info.width as usize) {
+                // 16-bit samples are big endian
+                let channel = |c: usize| if bytes == 1 {
+                    texel[c] as f32 / 255.0
+                }
+                else {
+                    u16::from_be_bytes([texel[2 * c], texel[2 * c + 1]]) as f32 / 65535.0
+                };
+                let (r, g, b) = if channels < 3 { (channel(0), channel(0), channel(0)) } else { (channel(0), channel(1), channel(2)) };
+                pixels.push(Color::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b)));
+            }
+        }
+        Ok(Self::new(info.width, info.height, pixels))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+    }
+
+    pub fn set_wrap(&mut self, wrap: Wrap) {
+        self.wrap = wrap;
+    }
+
+    pub fn value(&self, u: f32, v: f32) -> Color {
+        if self.pixels.is_empty() {
+            return Color::new(0.0, 1.0, 1.0); // Cyan stands out as a missing texture
+        }
+        // v runs up the image, rows run down it
+        let x = u * self.width as f32;
+        let y = (1.0 - v) * self.height as f32;
+        match self.filter {
+            Filter::Nearest => self.texel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                // Texel centers sit half way across each texel
+                let (x, y) = (x - 0.5, y - 0.5);
+                // Huge and infinite uvs saturate the index, so the next texel along can't overflow
+                let (i, j) = (x.floor() as i64, y.floor() as i64);
+                let (i1, j1) = (i.saturating_add(1), j.saturating_add(1));
+                let (fx, fy) = (x - x.floor(), y - y.floor());
+                let top = (1.0 - fx) * self.texel(i, j) + fx * self.texel(i1, j);
+                let bottom = (1.0 - fx) * self.texel(i, j1) + fx * self.texel(i1, j1);
+                (1.0 - fy) * top + fy * bottom
+            }
+        }
+    }
+
+    fn texel(&self, i: i64, j: i64) -> Color {
+        let i = wrap_index(i, self.width as i64, self.wrap);
+        let j = wrap_index(j, self.height as i64, self.wrap);
+        self.pixels[(j * self.width as i64 + i) as usize]
+    }
+}
+
+fn wrap_index(i: i64, n: i64, wrap: Wrap) -> i64 {
+    match wrap {
+        Wrap::Repeat => i.rem_euclid(n),
+        Wrap::Clamp => i.clamp(0, n - 1),
+        Wrap::Mirror => {
+            let m = i.rem_euclid(2 * n);
+            if m < n { m } else { 2 * n - 1 - m }
+        }
+    }
+}
+
+/// Ken Perlin's gradient noise, from a fixed seed so a texture looks the same every render
+#[derive(Clone)]
+pub struct Perlin {
+    ranvec: Vec<Vector3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Pcg32::seed_from_u64(seed);
+        let ranvec = (0..POINT_COUNT).map(|_| {
+            Vector3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)).unit_vector()
+        }).collect();
+        let mut perm = || {
+            let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+            p.shuffle(&mut rng);
+            p
+        };
+        Self {
+            perm_x: perm(),
+            perm_y: perm(),
+            perm_z: perm(),
+            ranvec,
+        }
+    }
+
+    /// Noise in [-1, 1]
+    pub fn noise(&self, p: Point3) -> f32 {
+        let u = p.x - p.x.floor();
+        let v = p.y - p.y.floor();
+        let w = p.z - p.z.floor();
+        let i = p.x.floor() as i64;
+        let j = p.y.floor() as i64;
+        let k = p.z.floor() as i64;
+
+        let mut c = [[[Vector3::default(); 2]; 2]; 2];
+        for (di, plane) in c.iter_mut().enumerate() {
+            for (dj, row) in plane.iter_mut().enumerate() {
+                for (dk, corner) in row.iter_mut().enumerate() {
+                    *corner = self.ranvec[
+                        self.perm_x[((i + di as i64) & 255) as usize] ^
+                        self.perm_y[((j + dj as i64) & 255) as usize] ^
+                        self.perm_z[((k + dk as i64) & 255) as usize]
+                    ];
+                }
+            }
+        }
+
+        // Hermite smoothing hides the grid
+        let uu = u*u*(3.0 - 2.0*u);
+        let vv = v*v*(3.0 - 2.0*v);
+        let ww = w*w*(3.0 - 2.0*w);
+        let mut accum = 0.0;
+        for (di, plane) in c.iter().enumerate() {
+            for (dj, row) in plane.iter().enumerate() {
+                for (dk, corner) in row.iter().enumerate() {
+                    let (fi, fj, fk) = (di as f32, dj as f32, dk as f32);
+                    let weight = Vector3::new(u - fi, v - fj, w - fk);
+                    accum += (fi*uu + (1.0 - fi)*(1.0 - uu))
+                           * (fj*vv + (1.0 - fj)*(1.0 - vv))
+                           * (fk*ww + (1.0 - fk)*(1.0 - ww))
+                           * corner.dot(weight);
+                }
+            }
+        }
+        accum
+    }
+
+    /// Sum of `depth` octaves of noise, each twice the frequency and half the weight of the last
+    pub fn turb(&self, p: Point3, depth: u32) -> f32 {
+        let mut accum = 0.0;
+        let mut temp_p = p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            accum += weight * self.noise(temp_p);
+            weight *= 0.5;
+            temp_p *= 2.0;
+        }
+        accum.abs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{primitives, materials, scene, test_util};
+
+    #[test]
+    fn clones_share_image_data() {
+        let image = Texture::Image(ImageTexture::new(2, 1, vec![Color::new(1.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0)]));
+        match (&image, &image.clone()) {
+            (Texture::Image(a), Texture::Image(b)) => assert!(Arc::ptr_eq(&a.pixels, &b.pixels)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn textures_vary_over_surfaces() {
+        let (black, white) = (Color::new(0.0, 0.0, 0.0), Color::new(1.0, 1.0, 1.0));
+        let squares = Texture::uv_checker(black, white, 2, 2);
+        let origin = Vector3::new(0.0, 0.0, 0.0);
+        assert_eq!(squares.value(0.25, 0.25, origin).r, 0.0);
+        assert_eq!(squares.value(0.75, 0.25, origin).r, 1.0);
+        assert_eq!(squares.value(0.75, 0.75, origin).r, 0.0);
+
+        // The top of a sphere is v = 1, and u runs from -x round through +z
+        let (_, v) = primitives::sphere_uv(Vector3::new(0.0, 1.0, 0.0));
+        assert!((v - 1.0).abs() < 1e-5);
+        let (u, _) = primitives::sphere_uv(Vector3::new(-1.0, 0.0, 0.0));
+        assert!(u.abs() < 1e-5 || (u - 1.0).abs() < 1e-5);
+
+        let camera = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 90.0\naspect_ratio = 1.0\n";
+        let textured = format!("{}\n[textures.tiles]\ntype = \"checker\"\neven = [0.0, 0.0, 0.0]\nodd = [1.0, 1.0, 1.0]\n\n[materials.floor]\ntype = \"diffuse\"\ntexture = \"tiles\"\n", camera);
+        let scene = scene::Scene::parse(&textured).unwrap();
+        assert!(matches!(scene.material("floor"), Some(materials::Material::Diffuse { albedo: Texture::Checker { .. } })));
+
+        let both = textured.replace("texture = \"tiles\"", "texture = \"tiles\"\nalbedo = [0.5, 0.5, 0.5]");
+        assert!(matches!(scene::Scene::parse(&both), Err(scene::SceneError::Invalid { .. })));
+        let missing = textured.replace("texture = \"tiles\"", "texture = \"bricks\"");
+        match scene::Scene::parse(&missing) {
+            Err(scene::SceneError::Invalid { line, field, .. }) => assert_eq!((line, field.as_str()), (14, "texture")),
+            _ => panic!("expected an invalid scene error"),
+        }
+    }
+
+    #[test]
+    fn png_textures_load_and_filter() {
+        let write = |width: u32, color: png::ColorType, depth: png::BitDepth, data: &[u8]| {
+            let file = test_util::TempFile::new("png");
+            let mut encoder = png::Encoder::new(std::fs::File::create(file.path()).unwrap(), width, 1);
+            encoder.set_color(color);
+            encoder.set_depth(depth);
+            encoder.write_header().unwrap().write_image_data(data).unwrap();
+            file
+        };
+
+        // Black and sRGB 188 (about 0.5 linear), side by side
+        let rgb = write(2, png::ColorType::RGB, png::BitDepth::Eight, &[0, 0, 0, 188, 188, 188]);
+        let mut image = ImageTexture::load(rgb.path()).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        image.set_filter(Filter::Nearest);
+        assert!((image.value(0.75, 0.5).r - 0.5).abs() < 0.01);
+        assert_eq!(image.value(1.25, 0.5).r, 0.0);
+        image.set_wrap(Wrap::Mirror);
+        assert!((image.value(1.25, 0.5).r - 0.5).abs() < 0.01);
+        image.set_wrap(Wrap::Clamp);
+        image.set_filter(Filter::Bilinear);
+        assert!((image.value(0.5, 0.5).r - 0.25).abs() < 0.01);
+        assert!((image.value(1.5, 0.5).r - 0.5).abs() < 0.01);
+        // Wild uvs still land on some texel
+        for wrap in [Wrap::Repeat, Wrap::Clamp, Wrap::Mirror] {
+            image.set_wrap(wrap);
+            for u in [f32::INFINITY, f32::NEG_INFINITY, f32::NAN, 1e30, -1e30] {
+                image.value(u, u);
+            }
+        }
+
+        // Alpha is dropped and 16-bit gray fills all three channels
+        let rgba = write(1, png::ColorType::RGBA, png::BitDepth::Eight, &[255, 0, 0, 7]);
+        let c = ImageTexture::load(rgba.path()).unwrap().value(0.5, 0.5);
+        assert_eq!((c.r, c.g, c.b), (1.0, 0.0, 0.0));
+        let gray = write(1, png::ColorType::Grayscale, png::BitDepth::Sixteen, &[0xff, 0xff]);
+        let c = ImageTexture::load(gray.path()).unwrap().value(0.5, 0.5);
+        assert_eq!((c.r, c.g, c.b), (1.0, 1.0, 1.0));
+
+        let missing = test_util::TempFile::new("png");
+        assert!(matches!(ImageTexture::load(missing.path()), Err(TextureError::Io { .. })));
+        let garbage = test_util::TempFile::new("png");
+        std::fs::write(garbage.path(), "not a png").unwrap();
+        assert!(matches!(ImageTexture::load(garbage.path()), Err(TextureError::Decode { .. })));
+    }
+}
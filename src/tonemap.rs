@@ -0,0 +1,137 @@
+// Tone mapping, squeezing linear radiance into the 0 to 1 a display can show
+use super::colors::*;
+
+/// Where extended Reinhard maps to white, unless set
+pub const DEFAULT_WHITE_POINT: f32 = 4.0;
+
+// Hable's filmic curve, from the Uncharted 2 talk
+const HABLE_SHOULDER: f32 = 0.15;
+const HABLE_LINEAR: f32 = 0.50;
+const HABLE_LINEAR_ANGLE: f32 = 0.10;
+const HABLE_TOE: f32 = 0.20;
+const HABLE_TOE_NUMERATOR: f32 = 0.02;
+const HABLE_TOE_DENOMINATOR: f32 = 0.30;
+const HABLE_WHITE: f32 = 11.2;
+const HABLE_EXPOSURE_BIAS: f32 = 2.0;
+
+// Stephen Hill's fit of the ACES reference and output transforms, sRGB in and out
+const ACES_INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+const ACES_OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+/// How radiance beyond what the display shows is brought into range
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum ToneMap {
+    /// Left alone, so anything brighter than 1 clips to white
+    #[default]
+    Clamp,
+    /// x / (1 + x) per channel, never quite reaching white
+    Reinhard,
+    /// Reinhard stretched so `white_point` maps to exactly 1
+    ExtendedReinhard { white_point: f32 },
+    /// John Hable's filmic curve, with a toe in the shadows and a soft shoulder
+    Hable,
+    /// The ACES filmic look, as fitted by Stephen Hill
+    Aces,
+}
+
+impl ToneMap {
+    pub const NAMES: [&'static str; 5] = ["clamp", "reinhard", "reinhard-extended", "hable", "aces"];
+
+    /// Map a linear color, already exposed, to linear display values. Colors may still need
+    /// clamping, and the curves expect non-negative input.
+    pub fn apply(&self, color: Color) -> Color {
+        let color = color.clamp(0.0, f32::MAX);
+        let per_channel = |f: &dyn Fn(f32) -> f32| Color::new(f(color.r), f(color.g), f(color.b));
+        match self {
+            ToneMap::Clamp => color,
+            ToneMap::Reinhard => per_channel(&|x| x / (1.0 + x)),
+            ToneMap::ExtendedReinhard { white_point } => {
+                let white_squared = white_point * white_point;
+                per_channel(&|x| x * (1.0 + x / white_squared) / (1.0 + x))
+            }
+            ToneMap::Hable => {
+                let white_scale = 1.0 / hable(HABLE_WHITE);
+                per_channel(&|x| hable(x * HABLE_EXPOSURE_BIAS) * white_scale)
+            }
+            ToneMap::Aces => {
+                let v = multiply(&ACES_INPUT, color);
+                let fitted = Color::new(aces_fit(v.r), aces_fit(v.g), aces_fit(v.b));
+                multiply(&ACES_OUTPUT, fitted).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "reinhard-extended" => Ok(ToneMap::ExtendedReinhard { white_point: DEFAULT_WHITE_POINT }),
+            "hable" => Ok(ToneMap::Hable),
+            "aces" => Ok(ToneMap::Aces),
+            _ => Err(format!("unknown tone map `{}`, expected one of: {}", s, ToneMap::NAMES.join(", "))),
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (HABLE_SHOULDER, HABLE_LINEAR, HABLE_LINEAR_ANGLE, HABLE_TOE, HABLE_TOE_NUMERATOR, HABLE_TOE_DENOMINATOR);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+fn aces_fit(v: f32) -> f32 {
+    (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081)
+}
+
+fn multiply(m: &[[f32; 3]; 3], c: Color) -> Color {
+    let row = |r: &[f32; 3]| r[0] * c.r + r[1] * c.g + r[2] * c.b;
+    Color::new(row(&m[0]), row(&m[1]), row(&m[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{scene, framebuffer};
+
+    #[test]
+    fn tone_maps_compress_highlights() {
+        let grey = |tone_map: ToneMap, x: f32| tone_map.apply(Color::new(x, x, x)).g;
+        let operators = [ToneMap::Clamp, ToneMap::Reinhard, ToneMap::ExtendedReinhard { white_point: 4.0 }, ToneMap::Hable, ToneMap::Aces];
+        for tone_map in operators {
+            let curve: Vec<f32> = (0..200).map(|k| grey(tone_map, k as f32 * 0.05)).collect();
+            assert!(curve.windows(2).all(|w| w[1] >= w[0]), "{:?} should never get darker", tone_map);
+            assert!(curve[0].abs() < 0.01);
+        }
+        assert_eq!(grey(ToneMap::Reinhard, 1.0), 0.5);
+        assert!(grey(ToneMap::Reinhard, 1000.0) < 1.0);
+        assert!((grey(ToneMap::ExtendedReinhard { white_point: 4.0 }, 4.0) - 1.0).abs() < 1e-6);
+        assert!((grey(ToneMap::Hable, 5.6) - 1.0).abs() < 1e-5);
+        assert!(grey(ToneMap::Aces, 1000.0) > 0.99 && grey(ToneMap::Aces, 1000.0) <= 1.0);
+
+        // One stop up doubles the light
+        let settings = framebuffer::OutputSettings { exposure: 1.0, transfer: Transfer::Linear, ..Default::default() };
+        assert!((settings.display_color(Color::new(0.125, 0.125, 0.125)).r - 0.25).abs() < 1e-6);
+
+        let camera = "[camera]\nlookfrom = [0.0, 0.0, 0.0]\nlookat = [0.0, 0.0, -1.0]\nvfov = 90.0\n";
+        let scene = scene::Scene::parse(&format!("{}[render]\ntone_map = \"reinhard-extended\"\nwhite_point = 8.0\nexposure = -1.5\n", camera)).unwrap();
+        assert_eq!(scene.config().output().tone_map, ToneMap::ExtendedReinhard { white_point: 8.0 });
+        assert_eq!(scene.config().output().exposure, -1.5);
+        let scene = scene::Scene::parse(&format!("{}[render]\nwhite_point = 2.0\n", camera)).unwrap();
+        assert_eq!(scene.config().output().tone_map, ToneMap::ExtendedReinhard { white_point: 2.0 });
+        match scene::Scene::parse(&format!("{}[render]\ntone_map = \"aces\"\nwhite_point = 8.0\n", camera)) {
+            Err(scene::SceneError::Invalid { line, field, .. }) => assert_eq!((line, field.as_str()), (7, "white_point")),
+            _ => panic!("expected white_point to need reinhard-extended"),
+        }
+    }
+}
@@ -5,37 +5,69 @@ use super::rays::*;
 use super::primitives::*;
 use super::cameras::*;
 use super::materials::*;
+use super::scene::*;
 use rand::prelude::*;
+use rand::Rng as _; // Our own Rng shadows the trait from the prelude
+use rand_pcg::Pcg32;
 use super::render::*;
+use super::samplers::*;
 use Vector3 as Point3;
 
-const INFINITY: f32 = std::f32::INFINITY;
-const PI: f32 = 3.1415926535897932385;
+const INFINITY: f32 = f32::INFINITY;
+const PI: f32 = std::f32::consts::PI;
+
+/// Random number generator handed down through the render, so a seed fixes every draw
+pub struct Rng {
+    pcg: Pcg32,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { pcg: Pcg32::seed_from_u64(seed) }
+    }
+
+    // Produce a random float [0,1)
+    pub fn rand(&mut self) -> f32 {
+        self.pcg.gen_range(0.0..1.0)
+    }
+
+    // Produce a random float [min,max)
+    pub fn rand_range(&mut self, min: f32, max: f32) -> f32 {
+        self.pcg.gen_range(min..max)
+    }
+}
+
+/// Mix a value into a seed, so nearby values give unrelated seeds (splitmix64 finalizer)
+pub fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = seed ^ value.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
 
 pub fn degrees_to_radians(degrees: f32) -> f32 {
     degrees * PI / 180.0
 }
 
-// Produce a random float [0,1)
-pub fn rand() -> f32 {
-    thread_rng().gen_range(0.0..1.0)
-}
+// Samples are mapped onto shapes rather than rejected, so stratified samples stay stratified
 
-// Produce a random float [min,max)
-pub fn rand_range(min:f32, max:f32) -> f32 {
-    thread_rng().gen_range(min..max)
+/// A direction spread evenly over the unit sphere
+pub fn rand_unit_vector(sampler: &mut dyn Sampler) -> Vector3 {
+    let (u1, u2) = sampler.get_2d();
+    let z = 1.0 - 2.0 * u1;
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
 }
 
-pub fn rand_in_unit_sphere() -> Vector3 {
-    let mut p = Vector3::rand_range(-1.0, 1.0);
-    while p.length_squared() >= 1.0 {
-        p = Vector3::rand_range(-1.0, 1.0);
-    };
-    p
+/// A point spread evenly through the unit ball
+pub fn rand_in_unit_sphere(sampler: &mut dyn Sampler) -> Vector3 {
+    let direction = rand_unit_vector(sampler);
+    sampler.get_1d().cbrt() * direction
 }
 
-pub fn rand_lamb_vector(hit_record: &HitRecord) -> Vector3 {
-    hit_record.normal + rand_in_unit_sphere().unit_vector()
+pub fn rand_lamb_vector(hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Vector3 {
+    hit_record.normal + rand_unit_vector(sampler)
 }
 
 pub fn output_color_gradient() {
@@ -58,9 +90,9 @@ pub fn output_color_gradient() {
             let b = 0.25;
 
             let pixel_color = Color {
-                r: r,
-                g: g,
-                b: b
+                r,
+                g,
+                b
             };
 
             println!("{}", pixel_color);
@@ -104,15 +136,17 @@ pub fn output_blue_white_gradient() {
     println!("{} {}", IMAGE_WIDTH, IMAGE_HEIGHT);
     println!("{}", (DYN_RANGE-1) as u32);
 
+    let mut sampler = IndependentSampler::new(0);
     for j in (0..IMAGE_HEIGHT).rev() {
         //std::io::stderr().write_fmt("\nScanlines remaining: {} ", j);
         eprintln!("\nScanlines remaining: {} ", j);
         for i in 0..IMAGE_WIDTH {
             let u = i as f32 / (IMAGE_WIDTH as f32 - 1.0);
             let v = j as f32 / (IMAGE_HEIGHT as f32 - 1.0);
-            let r = Ray {origin: origin, direction: (lower_left_corner + u*horizontal + v*vertical - origin)};
+            let r = Ray {origin, direction: (lower_left_corner + u*horizontal + v*vertical - origin)};
 
-            let pixel_color = ray_color_bounce(&r, &sphere1, MAX_DEPTH);
+            sampler.start_sample(i, j, 0);
+            let (pixel_color, _) = ray_color_bounce(&r, &sphere1, MAX_DEPTH, &mut sampler);
 
             println!("{}", pixel_color);
         }
@@ -129,19 +163,17 @@ pub fn output_sphere_on_sphere() {
     world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-1.0), material: &material_white, radius: 0.5}));
     world.add(Box::new(Sphere{center: Vector3::new(0.0,-100.5,-1.0), material: &material_white, radius: 100.0}));
     
+    // Scene Config
+    let scene = SceneConfig::builder().width(600).samples_per_pixel(200).build().unwrap();
+
     // Camera
 
-    let cam = Camera::new(Point3::new(0.0,0.0,0.0), Point3::new(0.0,0.0,-1.0), Point3::new(0.0,1.0,0.0), 60.0, 16.0/9.0, 1.0, 1.0);
-
-    // Scene Config
-    let mut scene = SceneConfig::new();
-    scene.set_width(600);
-    scene.samples_per_pixel = 200;
+    let cam = Camera::new(Point3::new(0.0,0.0,0.0), Point3::new(0.0,0.0,-1.0), Point3::new(0.0,1.0,0.0), 60.0, scene.aspect_ratio(), 1.0, 1.0);
 
     // Render Image
     
     //render_image_ppmstdout(&scene, &world, &cam);
-    render_image_png(&scene, &world, &cam, "image.png");
+    render_image_png(&scene, &world, &cam, "image.png").unwrap();
 }
 
 
@@ -149,10 +181,10 @@ pub fn output_sphere_on_sphere() {
 pub fn output_metal_spheres() {
 
     // Materials
-    let material_ground = Material::Diffuse {albedo: Color::new(0.8, 0.8, 0.0)};
-    let material_center = Material::Diffuse {albedo: Color::new(0.1, 0.2, 0.5)};
-    let material_left = Material::Dialectric {albedo: Color::new(1.0, 1.0, 1.0), index_of_refraction: 1.5};
-    let material_right = Material::Metal {albedo: Color::new(0.8, 0.6, 0.2), fuzz: 0.005};
+    let material_ground = Material::Diffuse {albedo: Color::new(0.8, 0.8, 0.0).into()};
+    let material_center = Material::Diffuse {albedo: Color::new(0.1, 0.2, 0.5).into()};
+    let material_left = Material::Dialectric {albedo: Color::new(1.0, 1.0, 1.0).into(), index_of_refraction: 1.5};
+    let material_right = Material::Metal {albedo: Color::new(0.8, 0.6, 0.2).into(), fuzz: 0.005};
 
     // World
     let mut world = HittableList::default();
@@ -171,39 +203,136 @@ pub fn output_metal_spheres() {
     let focus_dist = (lookfrom - lookat).length();
     let aperture = 0.4;
 
-    let cam = Camera::new(lookfrom, lookat, vup, 40.0, 16.0/9.0, aperture, focus_dist);
-
     // Scene Config
-    let mut scene = SceneConfig::new();
-    scene.set_width(800);
-    scene.samples_per_pixel = 150;
-    scene.max_depth = 20;
+    let scene = SceneConfig::builder().width(800).samples_per_pixel(150).max_depth(20).build().unwrap();
+
+    let cam = Camera::new(lookfrom, lookat, vup, 40.0, scene.aspect_ratio(), aperture, focus_dist);
 
     // Render Image
     
     //render_image_ppmstdout(&scene, &world, &cam);
-    render_image_png(&scene, &world, &cam, "image.png");
+    render_image_png(&scene, &world, &cam, "image.png").unwrap();
+}
+
+/// Names accepted by `builtin_scene`
+pub const BUILTIN_SCENES: [&str; 4] = ["metal-spheres", "sphere-on-sphere", "random-spheres", "cornell-spheres"];
+
+/// Scenes that ship with the renderer, for when there's no scene file at hand
+pub fn builtin_scene(name: &str) -> Option<Scene> {
+    match name {
+        // These files are part of the source tree, so they always parse
+        "metal-spheres" => Some(Scene::parse(include_str!("../scenes/metal_spheres.toml")).unwrap()),
+        "sphere-on-sphere" => Some(Scene::parse(include_str!("../scenes/sphere_on_sphere.toml")).unwrap()),
+        // A fixed seed keeps the layout the same from run to run
+        "random-spheres" => Some(random_spheres_scene(&mut Rng::new(0))),
+        "cornell-spheres" => Some(Scene::parse(include_str!("../scenes/cornell_spheres.toml")).unwrap()),
+        _ => None,
+    }
 }
 
-/// Color surface normals
-pub fn ray_color_normals(r: &Ray, world: &impl Hittable) -> Color {
+/// The book cover: a field of small random spheres around three large ones
+pub fn random_spheres_scene(rng: &mut Rng) -> Scene {
+
+    // Camera
+
+    let lookfrom = Point3::new(13.0,2.0,3.0);
+    let lookat = Point3::new(0.0,0.0,0.0);
+    let vup = Point3::new(0.0,1.0,0.0);
+
+    // Scene Config
+    let config = SceneConfig::builder().width(800).samples_per_pixel(100).max_depth(20).build().unwrap();
+
+    let cam = Camera::new(lookfrom, lookat, vup, 20.0, config.aspect_ratio(), 0.1, 10.0);
+
+    let mut scene = Scene::new(cam, config);
+
+    // Materials and World
+    let material_ground = scene.add_material("ground", Material::Diffuse {albedo: Color::new(0.5, 0.5, 0.5).into()});
+    scene.add_sphere(Point3::new(0.0,-1000.0,0.0), 1000.0, material_ground);
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Point3::new(a as f32 + 0.9*rng.rand(), 0.2, b as f32 + 0.9*rng.rand());
+            if (center - Point3::new(4.0, 0.2, 0.0)).length() <= 0.9 {
+                continue;
+            }
+            let choose_mat = rng.rand();
+            let material = if choose_mat < 0.8 {
+                Material::Diffuse {albedo: Color::from_vector(Vector3::rand(rng) * Vector3::rand(rng)).into()}
+            }
+            else if choose_mat < 0.95 {
+                Material::Metal {albedo: Color::from_vector(Vector3::rand_range(rng, 0.5, 1.0)).into(), fuzz: rng.rand_range(0.0, 0.5)}
+            }
+            else {
+                Material::Dialectric {albedo: Color::new(1.0, 1.0, 1.0).into(), index_of_refraction: 1.5}
+            };
+            let material = scene.add_material(&format!("small_{}_{}", a, b), material);
+            scene.add_sphere(center, 0.2, material);
+        }
+    }
+
+    let material_glass = scene.add_material("glass", Material::Dialectric {albedo: Color::new(1.0, 1.0, 1.0).into(), index_of_refraction: 1.5});
+    let material_brown = scene.add_material("brown", Material::Diffuse {albedo: Color::new(0.4, 0.2, 0.1).into()});
+    let material_steel = scene.add_material("steel", Material::Metal {albedo: Color::new(0.7, 0.6, 0.5).into(), fuzz: 0.0});
+    scene.add_sphere(Point3::new(0.0, 1.0, 0.0), 1.0, material_glass);
+    scene.add_sphere(Point3::new(-4.0, 1.0, 0.0), 1.0, material_brown);
+    scene.add_sphere(Point3::new(4.0, 1.0, 0.0), 1.0, material_steel);
+
+    scene
+}
+
+/// Color surface normals, and 1 where there's a surface, else 0
+pub fn ray_color_normals(r: &Ray, world: &impl Hittable) -> (Color, f32) {
     match world.hit(r, 0.0, INFINITY) {
-        None => ray_color_bg(r),
-        Some(hit_record) => 0.5 * (Color::from_vector(hit_record.normal) + Color::new(1.0,1.0,1.0,)),
+        None => (ray_color_bg(r), 0.0),
+        Some(hit_record) => (0.5 * (Color::from_vector(hit_record.normal) + Color::new(1.0,1.0,1.0,)), 1.0),
     }
 }
 
-/// Simple diffuse tracer
-fn ray_color_bounce(r: &Ray, world: &impl Hittable, depth: u32) -> Color {
+/// Simple diffuse tracer, also returning 1 if `r` hit anything, else 0
+pub fn ray_color_bounce(r: &Ray, world: &impl Hittable, depth: u32, sampler: &mut dyn Sampler) -> (Color, f32) {
     if depth == 0 {
-        return Color::new(0.0,0.0,0.0);
+        return (Color::new(0.0,0.0,0.0), 0.0);
     }
 
     match world.hit(r, 0.001, INFINITY) {
-        None => ray_color_bg(r),
+        None => (ray_color_bg(r), 0.0),
         Some(hit_record) => {
-            let target = rand_lamb_vector(&hit_record);
-            0.5 * ray_color_bounce(&Ray{origin:hit_record.p, direction:target}, world, depth-1)
+            let target = rand_lamb_vector(&hit_record, sampler);
+            (0.5 * ray_color_bounce(&Ray{origin:hit_record.p, direction:target}, world, depth-1, sampler).0, 1.0)
         },
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rng_streams_are_reproducible() {
+        let draws = |mut rng: Rng| (0..8).map(|_| rng.rand()).collect::<Vec<f32>>();
+        assert_eq!(draws(Rng::new(3)), draws(Rng::new(3)));
+        // Each pixel's stream depends on the pixel, not on which thread draws it
+        let pixel_draws = |i, j| {
+            let mut sampler = SamplerKind::Independent.sampler(3, 1);
+            sampler.start_sample(i, j, 0);
+            (0..8).map(|_| sampler.get_1d()).collect::<Vec<f32>>()
+        };
+        assert_eq!(pixel_draws(10, 20), pixel_draws(10, 20));
+        assert_ne!(pixel_draws(10, 20), pixel_draws(20, 10));
+
+        // The same scatter, drawn twice from the same stream
+        let material = Material::Metal { albedo: Color::new(0.8, 0.8, 0.8).into(), fuzz: 0.5 };
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere{center: Vector3::new(0.0,0.0,-1.0), material: &material, radius: 0.5}));
+        let cam = Camera::default();
+        let bounce = |seed| {
+            let mut sampler = IndependentSampler::new(seed);
+            sampler.start_sample(0, 0, 0);
+            let r = cam.get_ray(0.5, 0.5, &mut sampler);
+            let hit = world.hit(&r, 0.001, f32::INFINITY).unwrap();
+            material.scatter(&r, &hit, &mut sampler).map(|(_, scattered)| scattered.direction.x.to_bits())
+        };
+        assert_eq!(bounce(5), bounce(5));
+    }
+}
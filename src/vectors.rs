@@ -131,6 +131,19 @@ impl DivAssign<f32> for Vector3 {
     }
 }
 
+impl Index<usize> for Vector3 {
+    type Output = f32;
+
+    fn index(&self, axis: usize) -> &f32 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vector3 axis out of range: {}", axis),
+        }
+    }
+}
+
 impl Neg for Vector3 {
     type Output = Self;
 
@@ -196,14 +209,22 @@ impl Vector3 {
         }
     }
 
-    pub fn rand() -> Self {
-        Self::new(rand(), rand(), rand())
+    pub fn rand(rng: &mut Rng) -> Self {
+        Self::new(rng.rand(), rng.rand(), rng.rand())
     }
 
-    pub fn rand_range(min: f32, max: f32) -> Self {
-        Self::new(rand_range(min,max),rand_range(min,max),rand_range(min,max))
+    pub fn rand_range(rng: &mut Rng, min: f32, max: f32) -> Self {
+        Self::new(rng.rand_range(min,max),rng.rand_range(min,max),rng.rand_range(min,max))
     }
     
+    pub fn min(self, other: Self) -> Self {
+        Self::new(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z))
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        Self::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
     pub fn near_zero(self) -> bool {
         let s = 1e-8;
         (self.x.abs() < s) && (self.y.abs() < s) && (self.z.abs() < s)
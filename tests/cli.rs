@@ -0,0 +1,51 @@
+// Command line parsing, run against the built binary so exit codes are checked too
+use std::process::{Command, Output};
+
+fn rustrays(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rustrays")).args(args).output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn help_prints_usage() {
+    for flag in ["-h", "--help"] {
+        let output = rustrays(&[flag]);
+        assert_eq!(output.status.code(), Some(0));
+        let usage = String::from_utf8_lossy(&output.stdout);
+        assert!(usage.starts_with("RustRays") && usage.contains("--samples <count>"));
+    }
+}
+
+#[test]
+fn bad_arguments_exit_with_usage_errors() {
+    let cases: [(&[&str], &str); 7] = [
+        (&["--frobnicate"], "unknown option `--frobnicate`"),
+        (&["-n", "lots"], "-n expects a whole number, got `lots`"),
+        (&["--width", "0"], "--width must be at least 1"),
+        (&["--exposure", "inf"], "--exposure expects a number of stops, got `inf`"),
+        (&["--samples"], "--samples needs a value"),
+        (&["a.toml", "b.toml"], "unexpected argument `b.toml`"),
+        (&["-o", "out.png", "--exr-pixel", "float"], "--exr-pixel and --exr-compression only apply to EXR output"),
+    ];
+    for (args, message) in cases.iter() {
+        let output = rustrays(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = stderr(&output);
+        assert!(stderr.starts_with("error: ") && stderr.contains(message), "{:?} gave {}", args, stderr);
+        assert!(stderr.contains("Run with --help for usage."));
+    }
+}
+
+#[test]
+fn failed_renders_exit_with_one() {
+    let output = rustrays(&["--scene", "no-such-scene"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("unknown built-in scene `no-such-scene`"));
+
+    let output = rustrays(&["no-such-scene.toml"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("error: no-such-scene.toml: "));
+}